@@ -0,0 +1,162 @@
+use crate::{
+    bd_addr::{BdAddr, BdAddrType},
+    ble_sniffer::{BlePacket, ADV_TYPE_CONNECT_REQ, ADV_TYPE_SCAN_REQ},
+    crypto::ble_ah,
+};
+
+// Resolved addresses kept before the cache is dropped, RPAs rotate every ~15 minutes
+#[allow(unused)]
+pub const IRK_STORE_CACHE_SIZE: usize = 1024;
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct NamedIrk {
+    pub name: String,
+    // Most significant byte first like SnifferKey
+    pub irk: [u8; 16],
+}
+
+// Resolves resolvable private addresses against a set of named IRKs.
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartB 1.3.2.3
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct IrkStore {
+    irks: Vec<NamedIrk>,
+    // Every RPA seen so far with the index of the IRK that resolved it
+    cache: Vec<([u8; 6], Option<usize>)>,
+}
+
+#[allow(unused)]
+impl IrkStore {
+    pub fn new() -> IrkStore {
+        IrkStore {
+            irks: Vec::new(),
+            cache: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, irk: [u8; 16]) {
+        self.irks.push(NamedIrk {
+            name: name.to_string(),
+            irk,
+        });
+        self.cache.clear();
+    }
+
+    pub fn irks(&self) -> &Vec<NamedIrk> {
+        &self.irks
+    }
+
+    // One "<name>=<hex>" per line, empty lines and lines starting with '#' are skipped
+    pub fn load(&mut self, path: &str) -> Result<usize, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => return Err(format!("Failed to read {}, {}", path, error)),
+        };
+        let mut count = 0;
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_named_irk(line) {
+                Some(named_irk) => {
+                    self.add(&named_irk.name, named_irk.irk);
+                    count += 1;
+                }
+                None => return Err(format!("{}:{} invalid IRK entry", path, line_index + 1)),
+            }
+        }
+        Ok(count)
+    }
+
+    // Name of the IRK generating this address
+    pub fn resolve(&mut self, address: &BdAddr) -> Option<String> {
+        if address.address_type != BdAddrType::ResolvablePrivate || self.irks.is_empty() {
+            return None;
+        }
+        let mac = &address.bytes;
+        if let Some((_, index)) = self.cache.iter().find(|(cached, _)| cached == mac) {
+            return index.map(|index| self.irks[index].name.clone());
+        }
+        let prand = [mac[2], mac[1], mac[0]];
+        let hash = [mac[5], mac[4], mac[3]];
+        let index = self.irks.iter().position(|named_irk| {
+            let mut irk = named_irk.irk;
+            irk.reverse();
+            ble_ah(&irk, &prand) == hash
+        });
+        if self.cache.len() >= IRK_STORE_CACHE_SIZE {
+            self.cache.clear();
+        }
+        self.cache.push((*mac, index));
+        index.map(|index| self.irks[index].name.clone())
+    }
+
+    // Sets tx_identity/rx_identity of the packet for every random address resolved
+    pub fn annotate(&mut self, packet: &mut BlePacket) {
+        let (tx_mac, rx_mac) = if let Some(msg) = &packet.ll_layer_data.non_conn_ind {
+            (Some(msg.advertising_mac), None)
+        } else if let (ADV_TYPE_SCAN_REQ, Some(msg)) = (
+            packet.ll_layer_data.pdu_type,
+            &packet.ll_layer_data.scan_req,
+        ) {
+            (Some(msg.scanning_mac), Some(msg.advertising_mac))
+        } else if let (ADV_TYPE_CONNECT_REQ, Some(msg)) = (
+            packet.ll_layer_data.pdu_type,
+            &packet.ll_layer_data.connect_ind,
+        ) {
+            (Some(msg.initiator_mac), Some(msg.advertising_mac))
+        } else {
+            (None, None)
+        };
+        if let Some(address) = tx_mac {
+            packet.ll_layer_data.tx_identity = self.resolve(&address);
+        }
+        if let Some(address) = rx_mac {
+            packet.ll_layer_data.rx_identity = self.resolve(&address);
+        }
+    }
+}
+
+// "<name>=<hex>", the hex string alone is accepted too and named after itself
+#[allow(unused)]
+pub fn parse_named_irk(text: &str) -> Option<NamedIrk> {
+    let (name, hex) = match text.split_once('=') {
+        Some((name, hex)) => (name.trim(), hex.trim()),
+        None => (text.trim(), text.trim()),
+    };
+    let irk: [u8; 16] = crate::ble_sniffer::parse_hex_bytes(hex)?.try_into().ok()?;
+    if name.is_empty() {
+        return None;
+    }
+    Some(NamedIrk {
+        name: name.to_string(),
+        irk,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IRK and RPA of the ah sample data, Core v5.4 vol.3 PartH D.7
+    const SAMPLE_IRK: &str = "sample=ec0234a357c8ad05341010a60a397d9b";
+    const SAMPLE_RPA: [u8; 6] = [0x70, 0x81, 0x94, 0x0d, 0xfb, 0xaa];
+
+    #[test]
+    fn resolves_sample_rpa() {
+        let named_irk = parse_named_irk(SAMPLE_IRK).unwrap();
+        let mut irk_store = IrkStore::new();
+        irk_store.add(&named_irk.name, named_irk.irk);
+        let address = BdAddr::new(SAMPLE_RPA, true);
+        assert_eq!(address.address_type, BdAddrType::ResolvablePrivate);
+        assert_eq!(irk_store.resolve(&address), Some("sample".to_string()));
+        // Answered from the cache the second time
+        assert_eq!(irk_store.resolve(&address), Some("sample".to_string()));
+
+        let mut other = SAMPLE_RPA;
+        other[5] ^= 1;
+        assert_eq!(irk_store.resolve(&BdAddr::new(other, true)), None);
+    }
+}
@@ -0,0 +1,975 @@
+use std::fmt;
+
+use crate::{
+    ble_sniffer::{read_u16_le, BlePacket},
+    l2cap::{BleL2capFrame, L2CAP_CID_ATT},
+};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.3 PartF Chapter3.4
+#[allow(unused)]
+pub const ATT_ERROR_RSP: u8 = 0x01;
+#[allow(unused)]
+pub const ATT_EXCHANGE_MTU_REQ: u8 = 0x02;
+#[allow(unused)]
+pub const ATT_EXCHANGE_MTU_RSP: u8 = 0x03;
+#[allow(unused)]
+pub const ATT_FIND_INFORMATION_REQ: u8 = 0x04;
+#[allow(unused)]
+pub const ATT_FIND_INFORMATION_RSP: u8 = 0x05;
+#[allow(unused)]
+pub const ATT_FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+#[allow(unused)]
+pub const ATT_FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+#[allow(unused)]
+pub const ATT_READ_BY_TYPE_REQ: u8 = 0x08;
+#[allow(unused)]
+pub const ATT_READ_BY_TYPE_RSP: u8 = 0x09;
+#[allow(unused)]
+pub const ATT_READ_REQ: u8 = 0x0A;
+#[allow(unused)]
+pub const ATT_READ_RSP: u8 = 0x0B;
+#[allow(unused)]
+pub const ATT_READ_BLOB_REQ: u8 = 0x0C;
+#[allow(unused)]
+pub const ATT_READ_BLOB_RSP: u8 = 0x0D;
+#[allow(unused)]
+pub const ATT_READ_MULTIPLE_REQ: u8 = 0x0E;
+#[allow(unused)]
+pub const ATT_READ_MULTIPLE_RSP: u8 = 0x0F;
+#[allow(unused)]
+pub const ATT_READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+#[allow(unused)]
+pub const ATT_READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+#[allow(unused)]
+pub const ATT_WRITE_REQ: u8 = 0x12;
+#[allow(unused)]
+pub const ATT_WRITE_RSP: u8 = 0x13;
+#[allow(unused)]
+pub const ATT_PREPARE_WRITE_REQ: u8 = 0x16;
+#[allow(unused)]
+pub const ATT_PREPARE_WRITE_RSP: u8 = 0x17;
+#[allow(unused)]
+pub const ATT_EXECUTE_WRITE_REQ: u8 = 0x18;
+#[allow(unused)]
+pub const ATT_EXECUTE_WRITE_RSP: u8 = 0x19;
+#[allow(unused)]
+pub const ATT_HANDLE_VALUE_NTF: u8 = 0x1B;
+#[allow(unused)]
+pub const ATT_HANDLE_VALUE_IND: u8 = 0x1D;
+#[allow(unused)]
+pub const ATT_HANDLE_VALUE_CFM: u8 = 0x1E;
+#[allow(unused)]
+pub const ATT_READ_MULTIPLE_VARIABLE_REQ: u8 = 0x20;
+#[allow(unused)]
+pub const ATT_READ_MULTIPLE_VARIABLE_RSP: u8 = 0x21;
+#[allow(unused)]
+pub const ATT_MULTIPLE_HANDLE_VALUE_NTF: u8 = 0x23;
+#[allow(unused)]
+pub const ATT_WRITE_CMD: u8 = 0x52;
+#[allow(unused)]
+pub const ATT_SIGNED_WRITE_CMD: u8 = 0xD2;
+
+#[allow(unused)]
+pub const ATT_FIND_INFORMATION_FORMAT_UUID16: u8 = 0x01;
+#[allow(unused)]
+pub const ATT_FIND_INFORMATION_FORMAT_UUID128: u8 = 0x02;
+// Execute Write Request flags
+#[allow(unused)]
+pub const ATT_EXECUTE_WRITE_CANCEL: u8 = 0x00;
+#[allow(unused)]
+pub const ATT_EXECUTE_WRITE_IMMEDIATELY: u8 = 0x01;
+#[allow(unused)]
+pub const ATT_SIGNATURE_LENGTH: usize = 12;
+// A transaction not completed within 30 s has timed out (vol.3 PartF Chapter3.3.3)
+#[allow(unused)]
+pub const ATT_TRANSACTION_TIMEOUT_US: u64 = 30_000_000;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BleUuid {
+    Uuid16(u16),
+    // Stored in the little endian order used on air
+    Uuid128([u8; 16]),
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttHandleValue {
+    pub handle: u16,
+    pub value: Vec<u8>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttHandleUuid {
+    pub handle: u16,
+    pub uuid: BleUuid,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttHandleRange {
+    pub handle: u16,
+    pub end_group_handle: u16,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttGroupValue {
+    pub handle: u16,
+    pub end_group_handle: u16,
+    pub value: Vec<u8>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttPdu {
+    ErrorRsp {
+        request_opcode: u8,
+        handle: u16,
+        error_code: u8,
+    },
+    ExchangeMtuReq {
+        client_rx_mtu: u16,
+    },
+    ExchangeMtuRsp {
+        server_rx_mtu: u16,
+    },
+    FindInformationReq {
+        start_handle: u16,
+        end_handle: u16,
+    },
+    FindInformationRsp {
+        entries: Vec<AttHandleUuid>,
+    },
+    FindByTypeValueReq {
+        start_handle: u16,
+        end_handle: u16,
+        attribute_type: u16,
+        value: Vec<u8>,
+    },
+    FindByTypeValueRsp {
+        entries: Vec<AttHandleRange>,
+    },
+    ReadByTypeReq {
+        start_handle: u16,
+        end_handle: u16,
+        attribute_type: BleUuid,
+    },
+    ReadByTypeRsp {
+        entries: Vec<AttHandleValue>,
+    },
+    ReadReq {
+        handle: u16,
+    },
+    ReadRsp {
+        value: Vec<u8>,
+    },
+    ReadBlobReq {
+        handle: u16,
+        offset: u16,
+    },
+    ReadBlobRsp {
+        value: Vec<u8>,
+    },
+    ReadMultipleReq {
+        handles: Vec<u16>,
+    },
+    ReadMultipleRsp {
+        values: Vec<u8>,
+    },
+    ReadByGroupTypeReq {
+        start_handle: u16,
+        end_handle: u16,
+        group_type: BleUuid,
+    },
+    ReadByGroupTypeRsp {
+        entries: Vec<AttGroupValue>,
+    },
+    WriteReq {
+        handle: u16,
+        value: Vec<u8>,
+    },
+    WriteRsp,
+    WriteCmd {
+        handle: u16,
+        value: Vec<u8>,
+    },
+    SignedWriteCmd {
+        handle: u16,
+        value: Vec<u8>,
+        signature: [u8; ATT_SIGNATURE_LENGTH],
+    },
+    PrepareWriteReq {
+        handle: u16,
+        offset: u16,
+        value: Vec<u8>,
+    },
+    PrepareWriteRsp {
+        handle: u16,
+        offset: u16,
+        value: Vec<u8>,
+    },
+    ExecuteWriteReq {
+        flags: u8,
+    },
+    ExecuteWriteRsp,
+    HandleValueNtf {
+        handle: u16,
+        value: Vec<u8>,
+    },
+    HandleValueInd {
+        handle: u16,
+        value: Vec<u8>,
+    },
+    HandleValueCfm,
+    ReadMultipleVariableReq {
+        handles: Vec<u16>,
+    },
+    ReadMultipleVariableRsp {
+        values: Vec<Vec<u8>>,
+    },
+    MultipleHandleValueNtf {
+        entries: Vec<AttHandleValue>,
+    },
+    Unknown {
+        opcode: u8,
+        parameters: Vec<u8>,
+    },
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttRecord {
+    pub access_address: u32,
+    pub direction_to_slave: bool,
+    pub timestamp_us: u64,
+    pub pdu: AttPdu,
+    // Only set when pdu is the response (or confirmation) of a previous request
+    pub request: Option<AttPdu>,
+    pub latency_us: Option<u64>,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct AttTransactionTracker {
+    clock_us: u64,
+    previous_airtime_us: u32,
+    pending: Vec<AttRecord>,
+    expired: Vec<AttRecord>,
+}
+
+impl BleUuid {
+    pub fn from(bytes: &[u8]) -> Option<BleUuid> {
+        if bytes.len() == 2 {
+            Some(BleUuid::Uuid16(read_u16_le(bytes, 0)))
+        } else if bytes.len() == 16 {
+            let mut uuid = [0; 16];
+            uuid.copy_from_slice(bytes);
+            Some(BleUuid::Uuid128(uuid))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for BleUuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BleUuid::Uuid16(uuid) => write!(f, "0x{:04X}", uuid),
+            BleUuid::Uuid128(uuid) => {
+                for (index, b) in uuid.iter().rev().enumerate() {
+                    if index == 4 || index == 6 || index == 8 || index == 10 {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[allow(unused)]
+impl AttPdu {
+    pub fn from(bytes: &[u8]) -> Option<AttPdu> {
+        let (opcode, params) = bytes.split_first()?;
+        let opcode = *opcode;
+        let pdu = match opcode {
+            ATT_ERROR_RSP => {
+                if params.len() != 4 {
+                    return None;
+                }
+                AttPdu::ErrorRsp {
+                    request_opcode: params[0],
+                    handle: read_u16_le(params, 1),
+                    error_code: params[3],
+                }
+            }
+            ATT_EXCHANGE_MTU_REQ | ATT_EXCHANGE_MTU_RSP => {
+                if params.len() != 2 {
+                    return None;
+                }
+                let mtu = read_u16_le(params, 0);
+                if opcode == ATT_EXCHANGE_MTU_REQ {
+                    AttPdu::ExchangeMtuReq { client_rx_mtu: mtu }
+                } else {
+                    AttPdu::ExchangeMtuRsp { server_rx_mtu: mtu }
+                }
+            }
+            ATT_FIND_INFORMATION_REQ => {
+                if params.len() != 4 {
+                    return None;
+                }
+                AttPdu::FindInformationReq {
+                    start_handle: read_u16_le(params, 0),
+                    end_handle: read_u16_le(params, 2),
+                }
+            }
+            ATT_FIND_INFORMATION_RSP => {
+                let (format, data) = params.split_first()?;
+                let uuid_len = match *format {
+                    ATT_FIND_INFORMATION_FORMAT_UUID16 => 2,
+                    ATT_FIND_INFORMATION_FORMAT_UUID128 => 16,
+                    _ => return None,
+                };
+                let mut entries = Vec::new();
+                for chunk in data.chunks(2 + uuid_len) {
+                    if chunk.len() != 2 + uuid_len {
+                        return None;
+                    }
+                    entries.push(AttHandleUuid {
+                        handle: read_u16_le(chunk, 0),
+                        uuid: BleUuid::from(&chunk[2..])?,
+                    });
+                }
+                AttPdu::FindInformationRsp { entries }
+            }
+            ATT_FIND_BY_TYPE_VALUE_REQ => {
+                if params.len() < 6 {
+                    return None;
+                }
+                AttPdu::FindByTypeValueReq {
+                    start_handle: read_u16_le(params, 0),
+                    end_handle: read_u16_le(params, 2),
+                    attribute_type: read_u16_le(params, 4),
+                    value: params[6..].to_vec(),
+                }
+            }
+            ATT_FIND_BY_TYPE_VALUE_RSP => {
+                if params.len() % 4 != 0 {
+                    return None;
+                }
+                let entries = params
+                    .chunks(4)
+                    .map(|chunk| AttHandleRange {
+                        handle: read_u16_le(chunk, 0),
+                        end_group_handle: read_u16_le(chunk, 2),
+                    })
+                    .collect();
+                AttPdu::FindByTypeValueRsp { entries }
+            }
+            ATT_READ_BY_TYPE_REQ | ATT_READ_BY_GROUP_TYPE_REQ => {
+                if params.len() < 4 {
+                    return None;
+                }
+                let start_handle = read_u16_le(params, 0);
+                let end_handle = read_u16_le(params, 2);
+                let uuid = BleUuid::from(&params[4..])?;
+                if opcode == ATT_READ_BY_TYPE_REQ {
+                    AttPdu::ReadByTypeReq {
+                        start_handle,
+                        end_handle,
+                        attribute_type: uuid,
+                    }
+                } else {
+                    AttPdu::ReadByGroupTypeReq {
+                        start_handle,
+                        end_handle,
+                        group_type: uuid,
+                    }
+                }
+            }
+            ATT_READ_BY_TYPE_RSP => {
+                let (length, data) = params.split_first()?;
+                let length = *length as usize;
+                if length < 2 || data.len() % length != 0 {
+                    return None;
+                }
+                let entries = data
+                    .chunks(length)
+                    .map(|chunk| AttHandleValue {
+                        handle: read_u16_le(chunk, 0),
+                        value: chunk[2..].to_vec(),
+                    })
+                    .collect();
+                AttPdu::ReadByTypeRsp { entries }
+            }
+            ATT_READ_BY_GROUP_TYPE_RSP => {
+                let (length, data) = params.split_first()?;
+                let length = *length as usize;
+                if length < 4 || data.len() % length != 0 {
+                    return None;
+                }
+                let entries = data
+                    .chunks(length)
+                    .map(|chunk| AttGroupValue {
+                        handle: read_u16_le(chunk, 0),
+                        end_group_handle: read_u16_le(chunk, 2),
+                        value: chunk[4..].to_vec(),
+                    })
+                    .collect();
+                AttPdu::ReadByGroupTypeRsp { entries }
+            }
+            ATT_READ_REQ => {
+                if params.len() != 2 {
+                    return None;
+                }
+                AttPdu::ReadReq {
+                    handle: read_u16_le(params, 0),
+                }
+            }
+            ATT_READ_RSP => AttPdu::ReadRsp {
+                value: params.to_vec(),
+            },
+            ATT_READ_BLOB_REQ => {
+                if params.len() != 4 {
+                    return None;
+                }
+                AttPdu::ReadBlobReq {
+                    handle: read_u16_le(params, 0),
+                    offset: read_u16_le(params, 2),
+                }
+            }
+            ATT_READ_BLOB_RSP => AttPdu::ReadBlobRsp {
+                value: params.to_vec(),
+            },
+            ATT_READ_MULTIPLE_REQ | ATT_READ_MULTIPLE_VARIABLE_REQ => {
+                if params.len() < 4 || params.len() % 2 != 0 {
+                    return None;
+                }
+                let handles = params
+                    .chunks(2)
+                    .map(|chunk| read_u16_le(chunk, 0))
+                    .collect();
+                if opcode == ATT_READ_MULTIPLE_REQ {
+                    AttPdu::ReadMultipleReq { handles }
+                } else {
+                    AttPdu::ReadMultipleVariableReq { handles }
+                }
+            }
+            ATT_READ_MULTIPLE_RSP => AttPdu::ReadMultipleRsp {
+                values: params.to_vec(),
+            },
+            ATT_READ_MULTIPLE_VARIABLE_RSP => {
+                let mut values = Vec::new();
+                let mut index = 0;
+                while index < params.len() {
+                    if index + 2 > params.len() {
+                        return None;
+                    }
+                    let value_len = read_u16_le(params, index) as usize;
+                    index += 2;
+                    // The last value may be truncated to the ATT_MTU
+                    let value_end = usize::min(index + value_len, params.len());
+                    values.push(params[index..value_end].to_vec());
+                    index = value_end;
+                }
+                AttPdu::ReadMultipleVariableRsp { values }
+            }
+            ATT_WRITE_REQ | ATT_WRITE_CMD | ATT_HANDLE_VALUE_NTF | ATT_HANDLE_VALUE_IND => {
+                if params.len() < 2 {
+                    return None;
+                }
+                let handle = read_u16_le(params, 0);
+                let value = params[2..].to_vec();
+                match opcode {
+                    ATT_WRITE_REQ => AttPdu::WriteReq { handle, value },
+                    ATT_WRITE_CMD => AttPdu::WriteCmd { handle, value },
+                    ATT_HANDLE_VALUE_NTF => AttPdu::HandleValueNtf { handle, value },
+                    _ => AttPdu::HandleValueInd { handle, value },
+                }
+            }
+            ATT_SIGNED_WRITE_CMD => {
+                if params.len() < 2 + ATT_SIGNATURE_LENGTH {
+                    return None;
+                }
+                let signature_index = params.len() - ATT_SIGNATURE_LENGTH;
+                let mut signature = [0; ATT_SIGNATURE_LENGTH];
+                signature.copy_from_slice(&params[signature_index..]);
+                AttPdu::SignedWriteCmd {
+                    handle: read_u16_le(params, 0),
+                    value: params[2..signature_index].to_vec(),
+                    signature,
+                }
+            }
+            ATT_PREPARE_WRITE_REQ | ATT_PREPARE_WRITE_RSP => {
+                if params.len() < 4 {
+                    return None;
+                }
+                let handle = read_u16_le(params, 0);
+                let offset = read_u16_le(params, 2);
+                let value = params[4..].to_vec();
+                if opcode == ATT_PREPARE_WRITE_REQ {
+                    AttPdu::PrepareWriteReq {
+                        handle,
+                        offset,
+                        value,
+                    }
+                } else {
+                    AttPdu::PrepareWriteRsp {
+                        handle,
+                        offset,
+                        value,
+                    }
+                }
+            }
+            ATT_EXECUTE_WRITE_REQ => {
+                if params.len() != 1 {
+                    return None;
+                }
+                AttPdu::ExecuteWriteReq { flags: params[0] }
+            }
+            ATT_WRITE_RSP | ATT_EXECUTE_WRITE_RSP | ATT_HANDLE_VALUE_CFM => {
+                if !params.is_empty() {
+                    return None;
+                }
+                match opcode {
+                    ATT_WRITE_RSP => AttPdu::WriteRsp,
+                    ATT_EXECUTE_WRITE_RSP => AttPdu::ExecuteWriteRsp,
+                    _ => AttPdu::HandleValueCfm,
+                }
+            }
+            ATT_MULTIPLE_HANDLE_VALUE_NTF => {
+                let mut entries = Vec::new();
+                let mut index = 0;
+                while index < params.len() {
+                    if index + 4 > params.len() {
+                        return None;
+                    }
+                    let handle = read_u16_le(params, index);
+                    let value_len = read_u16_le(params, index + 2) as usize;
+                    index += 4;
+                    if index + value_len > params.len() {
+                        return None;
+                    }
+                    entries.push(AttHandleValue {
+                        handle,
+                        value: params[index..index + value_len].to_vec(),
+                    });
+                    index += value_len;
+                }
+                AttPdu::MultipleHandleValueNtf { entries }
+            }
+            _ => AttPdu::Unknown {
+                opcode,
+                parameters: params.to_vec(),
+            },
+        };
+        Some(pdu)
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match self {
+            AttPdu::ErrorRsp { .. } => ATT_ERROR_RSP,
+            AttPdu::ExchangeMtuReq { .. } => ATT_EXCHANGE_MTU_REQ,
+            AttPdu::ExchangeMtuRsp { .. } => ATT_EXCHANGE_MTU_RSP,
+            AttPdu::FindInformationReq { .. } => ATT_FIND_INFORMATION_REQ,
+            AttPdu::FindInformationRsp { .. } => ATT_FIND_INFORMATION_RSP,
+            AttPdu::FindByTypeValueReq { .. } => ATT_FIND_BY_TYPE_VALUE_REQ,
+            AttPdu::FindByTypeValueRsp { .. } => ATT_FIND_BY_TYPE_VALUE_RSP,
+            AttPdu::ReadByTypeReq { .. } => ATT_READ_BY_TYPE_REQ,
+            AttPdu::ReadByTypeRsp { .. } => ATT_READ_BY_TYPE_RSP,
+            AttPdu::ReadReq { .. } => ATT_READ_REQ,
+            AttPdu::ReadRsp { .. } => ATT_READ_RSP,
+            AttPdu::ReadBlobReq { .. } => ATT_READ_BLOB_REQ,
+            AttPdu::ReadBlobRsp { .. } => ATT_READ_BLOB_RSP,
+            AttPdu::ReadMultipleReq { .. } => ATT_READ_MULTIPLE_REQ,
+            AttPdu::ReadMultipleRsp { .. } => ATT_READ_MULTIPLE_RSP,
+            AttPdu::ReadByGroupTypeReq { .. } => ATT_READ_BY_GROUP_TYPE_REQ,
+            AttPdu::ReadByGroupTypeRsp { .. } => ATT_READ_BY_GROUP_TYPE_RSP,
+            AttPdu::WriteReq { .. } => ATT_WRITE_REQ,
+            AttPdu::WriteRsp => ATT_WRITE_RSP,
+            AttPdu::WriteCmd { .. } => ATT_WRITE_CMD,
+            AttPdu::SignedWriteCmd { .. } => ATT_SIGNED_WRITE_CMD,
+            AttPdu::PrepareWriteReq { .. } => ATT_PREPARE_WRITE_REQ,
+            AttPdu::PrepareWriteRsp { .. } => ATT_PREPARE_WRITE_RSP,
+            AttPdu::ExecuteWriteReq { .. } => ATT_EXECUTE_WRITE_REQ,
+            AttPdu::ExecuteWriteRsp => ATT_EXECUTE_WRITE_RSP,
+            AttPdu::HandleValueNtf { .. } => ATT_HANDLE_VALUE_NTF,
+            AttPdu::HandleValueInd { .. } => ATT_HANDLE_VALUE_IND,
+            AttPdu::HandleValueCfm => ATT_HANDLE_VALUE_CFM,
+            AttPdu::ReadMultipleVariableReq { .. } => ATT_READ_MULTIPLE_VARIABLE_REQ,
+            AttPdu::ReadMultipleVariableRsp { .. } => ATT_READ_MULTIPLE_VARIABLE_RSP,
+            AttPdu::MultipleHandleValueNtf { .. } => ATT_MULTIPLE_HANDLE_VALUE_NTF,
+            AttPdu::Unknown { opcode, .. } => *opcode,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        att_opcode_name(self.opcode())
+    }
+
+    // Requests and indications are answered by exactly one PDU from the peer
+    pub fn expects_response(&self) -> bool {
+        matches!(
+            self.opcode(),
+            ATT_EXCHANGE_MTU_REQ
+                | ATT_FIND_INFORMATION_REQ
+                | ATT_FIND_BY_TYPE_VALUE_REQ
+                | ATT_READ_BY_TYPE_REQ
+                | ATT_READ_REQ
+                | ATT_READ_BLOB_REQ
+                | ATT_READ_MULTIPLE_REQ
+                | ATT_READ_BY_GROUP_TYPE_REQ
+                | ATT_WRITE_REQ
+                | ATT_PREPARE_WRITE_REQ
+                | ATT_EXECUTE_WRITE_REQ
+                | ATT_HANDLE_VALUE_IND
+                | ATT_READ_MULTIPLE_VARIABLE_REQ
+        )
+    }
+
+    pub fn is_response_to(&self, request: &AttPdu) -> bool {
+        match self {
+            AttPdu::ErrorRsp { request_opcode, .. } => *request_opcode == request.opcode(),
+            AttPdu::HandleValueCfm => request.opcode() == ATT_HANDLE_VALUE_IND,
+            _ => request.expects_response() && self.opcode() == request.opcode() + 1,
+        }
+    }
+}
+
+#[allow(unused)]
+impl AttRecord {
+    // One line description, e.g. "connection 0x50654A9B peripheral
+    // ReadRsp { value: [18, 52] }, answers ATT_READ_REQ after 1750 us"
+    pub fn summary(&self) -> String {
+        let sender = if self.direction_to_slave {
+            "central"
+        } else {
+            "peripheral"
+        };
+        let mut summary = format!(
+            "connection 0x{:08X} {} {:?}",
+            self.access_address, sender, self.pdu
+        );
+        if let (Some(request), Some(latency_us)) = (&self.request, self.latency_us) {
+            summary = format!(
+                "{}, answers {} after {} us",
+                summary,
+                request.name(),
+                latency_us
+            );
+        }
+        summary
+    }
+}
+
+#[allow(unused)]
+impl AttTransactionTracker {
+    pub fn new() -> AttTransactionTracker {
+        AttTransactionTracker {
+            clock_us: 0,
+            previous_airtime_us: 0,
+            pending: Vec::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    // Feed every packet in capture order so the clock built from
+    // delta_time_us stays in sync, with the L2CAP frame it completes if any
+    // (see BleL2capReassembler), returns the decoded ATT PDU if any.
+    // delta_time_us runs from the end of the previous packet, hence its airtime.
+    pub fn process(
+        &mut self,
+        packet: &BlePacket,
+        frame: Option<&BleL2capFrame>,
+    ) -> Option<AttRecord> {
+        self.clock_us +=
+            self.previous_airtime_us as u64 + packet.packet_header.delta_time_us as u64;
+        self.previous_airtime_us = packet.airtime_us().unwrap_or(0);
+        let mut index = 0;
+        while index < self.pending.len() {
+            if self.clock_us - self.pending[index].timestamp_us >= ATT_TRANSACTION_TIMEOUT_US {
+                self.expired.push(self.pending.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        let frame = frame?;
+        if frame.channel_id != L2CAP_CID_ATT {
+            return None;
+        }
+        let mut record = AttRecord {
+            access_address: frame.access_address,
+            direction_to_slave: frame.direction_to_slave,
+            timestamp_us: self.clock_us,
+            pdu: AttPdu::from(&frame.payload)?,
+            request: None,
+            latency_us: None,
+        };
+        let request_index = self.pending.iter().position(|pending| {
+            pending.access_address == record.access_address
+                && pending.direction_to_slave != record.direction_to_slave
+                && record.pdu.is_response_to(&pending.pdu)
+        });
+        if let Some(index) = request_index {
+            let request = self.pending.remove(index);
+            record.latency_us = Some(record.timestamp_us - request.timestamp_us);
+            record.request = Some(request.pdu);
+        } else if record.pdu.expects_response() {
+            // Only one request (and one indication) may be outstanding per direction
+            let opcode = record.pdu.opcode();
+            self.pending.retain(|pending| {
+                pending.access_address != record.access_address
+                    || pending.direction_to_slave != record.direction_to_slave
+                    || (pending.pdu.opcode() == ATT_HANDLE_VALUE_IND)
+                        != (opcode == ATT_HANDLE_VALUE_IND)
+            });
+            self.pending.push(record.clone());
+        }
+        Some(record)
+    }
+
+    // Requests left unanswered past ATT_TRANSACTION_TIMEOUT_US, a late response
+    // isn't paired with them anymore
+    pub fn expired(&mut self) -> Vec<AttRecord> {
+        std::mem::take(&mut self.expired)
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.expired.clear();
+    }
+}
+
+#[allow(unused)]
+pub fn att_opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        ATT_ERROR_RSP => "ATT_ERROR_RSP",
+        ATT_EXCHANGE_MTU_REQ => "ATT_EXCHANGE_MTU_REQ",
+        ATT_EXCHANGE_MTU_RSP => "ATT_EXCHANGE_MTU_RSP",
+        ATT_FIND_INFORMATION_REQ => "ATT_FIND_INFORMATION_REQ",
+        ATT_FIND_INFORMATION_RSP => "ATT_FIND_INFORMATION_RSP",
+        ATT_FIND_BY_TYPE_VALUE_REQ => "ATT_FIND_BY_TYPE_VALUE_REQ",
+        ATT_FIND_BY_TYPE_VALUE_RSP => "ATT_FIND_BY_TYPE_VALUE_RSP",
+        ATT_READ_BY_TYPE_REQ => "ATT_READ_BY_TYPE_REQ",
+        ATT_READ_BY_TYPE_RSP => "ATT_READ_BY_TYPE_RSP",
+        ATT_READ_REQ => "ATT_READ_REQ",
+        ATT_READ_RSP => "ATT_READ_RSP",
+        ATT_READ_BLOB_REQ => "ATT_READ_BLOB_REQ",
+        ATT_READ_BLOB_RSP => "ATT_READ_BLOB_RSP",
+        ATT_READ_MULTIPLE_REQ => "ATT_READ_MULTIPLE_REQ",
+        ATT_READ_MULTIPLE_RSP => "ATT_READ_MULTIPLE_RSP",
+        ATT_READ_BY_GROUP_TYPE_REQ => "ATT_READ_BY_GROUP_TYPE_REQ",
+        ATT_READ_BY_GROUP_TYPE_RSP => "ATT_READ_BY_GROUP_TYPE_RSP",
+        ATT_WRITE_REQ => "ATT_WRITE_REQ",
+        ATT_WRITE_RSP => "ATT_WRITE_RSP",
+        ATT_WRITE_CMD => "ATT_WRITE_CMD",
+        ATT_SIGNED_WRITE_CMD => "ATT_SIGNED_WRITE_CMD",
+        ATT_PREPARE_WRITE_REQ => "ATT_PREPARE_WRITE_REQ",
+        ATT_PREPARE_WRITE_RSP => "ATT_PREPARE_WRITE_RSP",
+        ATT_EXECUTE_WRITE_REQ => "ATT_EXECUTE_WRITE_REQ",
+        ATT_EXECUTE_WRITE_RSP => "ATT_EXECUTE_WRITE_RSP",
+        ATT_HANDLE_VALUE_NTF => "ATT_HANDLE_VALUE_NTF",
+        ATT_HANDLE_VALUE_IND => "ATT_HANDLE_VALUE_IND",
+        ATT_HANDLE_VALUE_CFM => "ATT_HANDLE_VALUE_CFM",
+        ATT_READ_MULTIPLE_VARIABLE_REQ => "ATT_READ_MULTIPLE_VARIABLE_REQ",
+        ATT_READ_MULTIPLE_VARIABLE_RSP => "ATT_READ_MULTIPLE_VARIABLE_RSP",
+        ATT_MULTIPLE_HANDLE_VALUE_NTF => "ATT_MULTIPLE_HANDLE_VALUE_NTF",
+        _ => "ATT_UNKNOWN",
+    }
+}
+
+// Reference: Core v5.4 vol.3 PartF Table 3.4
+#[allow(unused)]
+pub fn att_error_name(error_code: u8) -> &'static str {
+    match error_code {
+        0x01 => "Invalid Handle",
+        0x02 => "Read Not Permitted",
+        0x03 => "Write Not Permitted",
+        0x04 => "Invalid PDU",
+        0x05 => "Insufficient Authentication",
+        0x06 => "Request Not Supported",
+        0x07 => "Invalid Offset",
+        0x08 => "Insufficient Authorization",
+        0x09 => "Prepare Queue Full",
+        0x0A => "Attribute Not Found",
+        0x0B => "Attribute Not Long",
+        0x0C => "Encryption Key Size Too Short",
+        0x0D => "Invalid Attribute Value Length",
+        0x0E => "Unlikely Error",
+        0x0F => "Insufficient Encryption",
+        0x10 => "Unsupported Group Type",
+        0x11 => "Insufficient Resources",
+        0x12 => "Database Out Of Sync",
+        0x13 => "Value Not Allowed",
+        0x80..=0x9F => "Application Error",
+        0xE0..=0xFF => "Common Profile and Service Error",
+        _ => "Reserved",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble_sniffer::{
+        BleLLDataPdu, BlePacketHeaderData, EVENT_PACKET_DATA_PDU, LLID_CONTROL, LLID_DATA_START,
+    };
+    use crate::l2cap::BleL2capReassembler;
+
+    const ACCESS_ADDRESS: u32 = 0x50654A9B;
+
+    fn data_packet(
+        direction_to_slave: bool,
+        delta_time_us: u32,
+        llid: u8,
+        payload: &[u8],
+    ) -> BlePacket {
+        let mut packet = BlePacket::new();
+        packet.packet_id = EVENT_PACKET_DATA_PDU;
+        packet.packet_header.delta_time_us = delta_time_us;
+        packet.packet_header.data_header = Some(BlePacketHeaderData {
+            direction_to_slave,
+            encrypted: false,
+            mic_ok: false,
+        });
+        packet.ll_layer_data.access_address = ACCESS_ADDRESS;
+        packet.ll_layer_data.data_pdu = Some(BleLLDataPdu {
+            llid,
+            nesn: false,
+            sn: false,
+            more_data: false,
+            cte_info_present: false,
+            payload: payload.to_vec(),
+        });
+        packet
+    }
+
+    // Every test packet carries a whole L2CAP frame
+    fn track(tracker: &mut AttTransactionTracker, packet: &BlePacket) -> Option<AttRecord> {
+        let frame = BleL2capReassembler::new().process(packet);
+        tracker.process(packet, frame.as_ref())
+    }
+
+    fn att_packet(direction_to_slave: bool, delta_time_us: u32, att: &[u8]) -> BlePacket {
+        let mut payload = (att.len() as u16).to_le_bytes().to_vec();
+        payload.extend_from_slice(&L2CAP_CID_ATT.to_le_bytes());
+        payload.extend_from_slice(att);
+        data_packet(direction_to_slave, delta_time_us, LLID_DATA_START, &payload)
+    }
+
+    #[test]
+    fn decodes_opcodes() {
+        match AttPdu::from(&[0x01, 0x0A, 0x03, 0x00, 0x0A]) {
+            Some(AttPdu::ErrorRsp {
+                request_opcode: ATT_READ_REQ,
+                handle: 0x0003,
+                error_code: 0x0A,
+            }) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        match AttPdu::from(&[0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28]) {
+            Some(AttPdu::ReadByGroupTypeReq {
+                start_handle: 0x0001,
+                end_handle: 0xFFFF,
+                group_type: BleUuid::Uuid16(0x2800),
+            }) => {}
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        let pdu = AttPdu::from(&[0x11, 0x06, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18]).unwrap();
+        assert_eq!(pdu.name(), "ATT_READ_BY_GROUP_TYPE_RSP");
+        match &pdu {
+            AttPdu::ReadByGroupTypeRsp { entries } => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].handle, 0x0001);
+                assert_eq!(entries[0].end_group_handle, 0x0005);
+                assert_eq!(entries[0].value, [0x00, 0x18]);
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        match AttPdu::from(&[0x05, 0x01, 0x03, 0x00, 0x02, 0x29]) {
+            Some(AttPdu::FindInformationRsp { entries }) => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].handle, 0x0003);
+                assert_eq!(entries[0].uuid, BleUuid::Uuid16(0x2902));
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        match AttPdu::from(&[0x52, 0x03, 0x00, 0x01, 0x00]) {
+            Some(AttPdu::WriteCmd {
+                handle: 0x0003,
+                value,
+            }) => assert_eq!(value, [0x01, 0x00]),
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        match AttPdu::from(&[0x7F, 0x01]) {
+            Some(pdu @ AttPdu::Unknown { opcode: 0x7F, .. }) => {
+                assert_eq!(pdu.name(), "ATT_UNKNOWN")
+            }
+            pdu => panic!("unexpected {:?}", pdu),
+        }
+        // Truncated parameters and unknown Find Information format
+        assert!(AttPdu::from(&[]).is_none());
+        assert!(AttPdu::from(&[0x01, 0x0A, 0x03, 0x00]).is_none());
+        assert!(AttPdu::from(&[0x0A, 0x03]).is_none());
+        assert!(AttPdu::from(&[0x05, 0x03, 0x03, 0x00, 0x02, 0x29]).is_none());
+        for opcode in [ATT_EXCHANGE_MTU_REQ, ATT_READ_REQ, ATT_HANDLE_VALUE_CFM] {
+            let mut bytes = vec![opcode];
+            if opcode != ATT_HANDLE_VALUE_CFM {
+                bytes.extend_from_slice(&[0x17, 0x00]);
+            }
+            assert_eq!(AttPdu::from(&bytes).unwrap().opcode(), opcode);
+        }
+    }
+
+    #[test]
+    fn pairs_error_rsp_with_its_request() {
+        let mut tracker = AttTransactionTracker::new();
+        let request = track(&mut tracker, &att_packet(true, 0, &[0x0A, 0x03, 0x00])).unwrap();
+        assert!(request.request.is_none());
+        // A response sent in the same direction isn't an answer
+        let response = track(&mut tracker, &att_packet(true, 500, &[0x0B, 0x01])).unwrap();
+        assert!(response.request.is_none());
+        let error = track(
+            &mut tracker,
+            &att_packet(false, 1250, &[0x01, 0x0A, 0x03, 0x00, 0x02]),
+        )
+        .unwrap();
+        assert!(matches!(
+            error.request,
+            Some(AttPdu::ReadReq { handle: 0x0003 })
+        ));
+        assert_eq!(error.latency_us, Some(1750));
+        assert_eq!(
+            error.summary(),
+            "connection 0x50654A9B peripheral ErrorRsp { request_opcode: 10, handle: 3, \
+             error_code: 2 }, answers ATT_READ_REQ after 1750 us"
+        );
+        assert_eq!(att_error_name(0x02), "Read Not Permitted");
+        // The Read Req is answered already
+        let response = track(&mut tracker, &att_packet(false, 100, &[0x0B, 0x01])).unwrap();
+        assert!(response.request.is_none());
+        assert!(tracker.expired().is_empty());
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let mut tracker = AttTransactionTracker::new();
+        track(&mut tracker, &att_packet(true, 0, &[0x0A, 0x03, 0x00]));
+        // Empty LL packets keep the clock running without ATT traffic
+        for _ in 0..2 {
+            let packet = data_packet(false, 14_999_999, LLID_CONTROL, &[]);
+            assert!(track(&mut tracker, &packet).is_none());
+        }
+        assert!(tracker.expired().is_empty());
+        track(&mut tracker, &data_packet(true, 2, LLID_CONTROL, &[]));
+        let expired = tracker.expired();
+        assert_eq!(expired.len(), 1);
+        assert!(matches!(expired[0].pdu, AttPdu::ReadReq { handle: 0x0003 }));
+        assert!(tracker.expired().is_empty());
+        let late = track(&mut tracker, &att_packet(false, 100, &[0x0B, 0x01])).unwrap();
+        assert!(late.request.is_none());
+        assert_eq!(late.timestamp_us, ATT_TRANSACTION_TIMEOUT_US + 100);
+    }
+}
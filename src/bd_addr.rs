@@ -0,0 +1,206 @@
+use std::{fmt, str::FromStr};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartB 1.3
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BdAddrType {
+    Public,
+    // Two most significant bits 0b11
+    RandomStatic,
+    // 0b01, see address_resolution.rs
+    ResolvablePrivate,
+    // 0b00
+    NonResolvablePrivate,
+    // 0b10 is reserved for future use
+    RandomReserved,
+}
+
+#[allow(unused)]
+impl BdAddrType {
+    // random is the TxAdd/RxAdd bit of the PDU header, msb the address' first byte
+    pub fn from(random: bool, msb: u8) -> BdAddrType {
+        if !random {
+            return BdAddrType::Public;
+        }
+        match msb >> 6 {
+            0b11 => BdAddrType::RandomStatic,
+            0b01 => BdAddrType::ResolvablePrivate,
+            0b00 => BdAddrType::NonResolvablePrivate,
+            _ => BdAddrType::RandomReserved,
+        }
+    }
+
+    pub fn is_random(&self) -> bool {
+        *self != BdAddrType::Public
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BdAddrType::Public => "public",
+            BdAddrType::RandomStatic => "random static",
+            BdAddrType::ResolvablePrivate => "resolvable private",
+            BdAddrType::NonResolvablePrivate => "non-resolvable private",
+            BdAddrType::RandomReserved => "random reserved",
+        }
+    }
+}
+
+impl fmt::Display for BdAddrType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BdAddr {
+    // Most significant byte first, the order addresses are written in
+    pub bytes: [u8; 6],
+    pub address_type: BdAddrType,
+}
+
+#[allow(unused)]
+impl BdAddr {
+    pub fn new(bytes: [u8; 6], random: bool) -> BdAddr {
+        BdAddr {
+            bytes,
+            address_type: BdAddrType::from(random, bytes[0]),
+        }
+    }
+
+    // Addresses are sent least significant byte first, callers check the length
+    pub fn from_le(bytes: &[u8], random: bool) -> BdAddr {
+        let mut address = [0; 6];
+        for index in 0..6 {
+            address[5 - index] = bytes[index];
+        }
+        BdAddr::new(address, random)
+    }
+
+    pub fn to_le(self) -> [u8; 6] {
+        let mut bytes = self.bytes;
+        bytes.reverse();
+        bytes
+    }
+
+    pub fn is_random(&self) -> bool {
+        self.address_type.is_random()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.bytes == [0; 6]
+    }
+}
+
+// "AA:BB:CC:DD:EE:FF", the alternate form "{:#}" appends "/random" to random
+// addresses so it can be parsed back by from_str
+impl fmt::Display for BdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            self.bytes[0],
+            self.bytes[1],
+            self.bytes[2],
+            self.bytes[3],
+            self.bytes[4],
+            self.bytes[5]
+        )?;
+        if f.alternate() && self.is_random() {
+            write!(f, "/random")?;
+        }
+        Ok(())
+    }
+}
+
+// Accepts ':' or '-' separators and an optional "/public" or "/random" suffix,
+// addresses without suffix are public
+impl FromStr for BdAddr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<BdAddr, String> {
+        let (address, random) = match text.trim().split_once('/') {
+            Some((address, "public")) => (address, false),
+            Some((address, "random")) => (address, true),
+            Some((_, suffix)) => return Err(format!("Unknown address type {}", suffix)),
+            None => (text.trim(), false),
+        };
+        let parts: Vec<&str> = address.split([':', '-']).collect();
+        if parts.len() != 6 {
+            return Err(format!("Invalid address {}", text));
+        }
+        let mut bytes = [0; 6];
+        for (index, part) in parts.iter().enumerate() {
+            // from_str_radix alone would take a sign, e.g. "+F"
+            if part.len() != 2 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid address {}", text));
+            }
+            bytes[index] = match u8::from_str_radix(part, 16) {
+                Ok(b) => b,
+                Err(_) => return Err(format!("Invalid address {}", text)),
+            };
+        }
+        Ok(BdAddr::new(bytes, random))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats_addresses() {
+        let address: BdAddr = "c0:11:22:33:44:55/random".parse().unwrap();
+        assert_eq!(address.bytes, [0xC0, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(address.address_type, BdAddrType::RandomStatic);
+        assert_eq!(address.to_string(), "C0:11:22:33:44:55");
+        assert_eq!(format!("{:#}", address), "C0:11:22:33:44:55/random");
+        assert_eq!(format!("{:#}", address).parse::<BdAddr>(), Ok(address));
+
+        let address: BdAddr = " 00-11-22-33-44-55/public ".parse().unwrap();
+        assert_eq!(address.address_type, BdAddrType::Public);
+        assert_eq!(format!("{:#}", address), "00:11:22:33:44:55");
+        assert_eq!("00:11:22:33:44:55".parse::<BdAddr>(), Ok(address));
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for text in [
+            "",
+            "00:11:22:33:44",
+            "00:11:22:33:44:55:66",
+            "00:11:22:33:44:5",
+            "00:11:22:33:44:555",
+            "00:11:22:33:44:GG",
+            "00:11:22:33:44:+F",
+            "00:11:22:33:44:55/private",
+        ] {
+            assert!(text.parse::<BdAddr>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn converts_from_and_to_air_order() {
+        let address = BdAddr::from_le(&[0x55, 0x44, 0x33, 0x22, 0x11, 0x40, 0xff], true);
+        assert_eq!(address.bytes, [0x40, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        assert_eq!(address.to_le(), [0x55, 0x44, 0x33, 0x22, 0x11, 0x40]);
+        assert!(address.is_random());
+        assert!(!address.is_zero());
+    }
+
+    #[test]
+    fn classifies_random_addresses_by_their_two_top_bits() {
+        let kind = |msb| BdAddr::new([msb, 0, 0, 0, 0, 1], true).address_type;
+        assert_eq!(kind(0xC0), BdAddrType::RandomStatic);
+        assert_eq!(kind(0xFF), BdAddrType::RandomStatic);
+        assert_eq!(kind(0x40), BdAddrType::ResolvablePrivate);
+        assert_eq!(kind(0x7F), BdAddrType::ResolvablePrivate);
+        assert_eq!(kind(0x3F), BdAddrType::NonResolvablePrivate);
+        assert_eq!(kind(0x80), BdAddrType::RandomReserved);
+        assert_eq!(
+            BdAddr::new([0xC0, 0, 0, 0, 0, 1], false).address_type,
+            BdAddrType::Public
+        );
+        assert!(!BdAddrType::Public.is_random());
+    }
+}
@@ -1,13 +1,42 @@
 use std::{
+    io::{self, ErrorKind},
     sync::mpsc::{Receiver, Sender},
     thread,
-    time::Duration,
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    address_resolution::IrkStore,
+    att::{AttRecord, AttTransactionTracker},
+    bd_addr::BdAddr,
+    crc::{ble_crc24, BleCrcCheck, BleCrcVerifier, BLE_ADV_CRC_INIT},
+    error::{ParseError, SnifferError},
+    firmware::{
+        find_baud_rate, negotiate_baud_rate, request_firmware_version, PacketCounterTracker,
+        PacketTimeline, SnifferClock, RECONNECT_DELAY, TIMESTAMP_DRIFT_WARNING_PPM,
+        TIMESTAMP_SYNC_INTERVAL, WATCHDOG_INTERVAL, WATCHDOG_TIMEOUT,
+    },
+    l2cap::{
+        BleL2capFrame, BleL2capReassembler, L2capChannelEvent, L2capChannelTracker,
+        L2capSignalingCommand,
+    },
+    legacy_pairing::LegacyPairingWorker,
+    link_encryption::LinkDecryptor,
+    logger::{log, LogLevel},
+    phy::pdu_airtime_us,
+    protocol::{SnifferCommand, SnifferCommander, SnifferEvent},
+    slip::{slip_encode, SlipDecoder},
+    smp::{smp_failed_reason_name, smp_pairing_method, SmpPairingFeatures, SmpPdu},
+    transport::{SnifferSource, SnifferTransport},
 };
 
 #[allow(unused)]
 pub const SNIFFER_VERSION: &str = "V1.1";
 #[allow(unused)]
 pub const SNIFFER_BAUDRATE: u32 = 460800;
+// Access address of every advertising physical channel packet
+#[allow(unused)]
+pub const ADV_ACCESS_ADDRESS: u32 = 0x8E89BED6;
 // UART protocol packet codes start (see sniffer_uart_protocol.pdf)
 #[allow(unused)]
 pub const SLIP_START: u8 = 0xAB;
@@ -30,6 +59,12 @@ pub const PROTOVER_V2: u8 = 2;
 pub const PROTOVER_V1: u8 = 1;
 #[allow(unused)]
 pub const HEADER_LENGTH: u8 = 6;
+// Length of the packet header of EVENT_PACKET_ADV_PDU/EVENT_PACKET_DATA_PDU, flags to timestamp
+#[allow(unused)]
+pub const PACKET_HEADER_LENGTH: u8 = 10;
+// The firmware copies the radio's S1 field, one zero byte between the PDU length and payload
+#[allow(unused)]
+pub const UART_PDU_PADDING_LENGTH: usize = 1;
 #[allow(unused)]
 pub const REQ_FOLLOW: u8 = 0x00;
 #[allow(unused)]
@@ -110,6 +145,10 @@ pub const ADV_TYPE_CONNECT_REQ: u8 = 0x5;
 #[allow(unused)]
 pub const ADV_TYPE_ADV_EXT_IND: u8 = 0x7;
 
+// Advertising channel indices, the firmware hops on them in this order by default
+#[allow(unused)]
+pub const ADV_CHANNELS: [u8; 3] = [37, 38, 39];
+
 #[allow(unused)]
 pub const PHY_1M: u8 = 0;
 #[allow(unused)]
@@ -122,48 +161,158 @@ pub const PHY_CODED_CI_S8: u8 = 0;
 #[allow(unused)]
 pub const PHY_CODED_CI_S2: u8 = 1;
 
+// Debug private key of LE Secure Connections (Core v5.4 vol.3 PartH 2.3.5.6.1)
+#[allow(unused)]
+pub const SC_DEBUG_PRIVATE_KEY: [u8; 32] = [
+    0x3f, 0x49, 0xf6, 0xd4, 0xa3, 0xc5, 0x5f, 0x38, 0x74, 0xc9, 0xb3, 0xe3, 0xd2, 0x10, 0x3f, 0x50,
+    0x4a, 0xff, 0x60, 0x7b, 0xeb, 0x40, 0xb7, 0x99, 0x58, 0x99, 0xb8, 0xa6, 0xcd, 0x3c, 0x1a, 0xbd,
+];
+
+#[allow(unused)]
+pub const LLID_DATA_CONTINUATION: u8 = 0x1;
+#[allow(unused)]
+pub const LLID_DATA_START: u8 = 0x2;
+#[allow(unused)]
+pub const LLID_CONTROL: u8 = 0x3;
+
+// Keys pushed to the firmware so it can follow and decrypt encrypted links.
+// Keys are written most significant byte first, as shown by Wireshark and
+// as sent to the firmware.
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum SnifferKey {
+    Passkey(u32),
+    OobTemporaryKey([u8; 16]),
+    LegacyLongTermKey([u8; 16]),
+    ScLongTermKey([u8; 16]),
+    ScPrivateKey([u8; 32]),
+    IdentityResolvingKey([u8; 16]),
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct SnifferConfig {
+    pub keys: Vec<SnifferKey>,
+    // Used to resolve random addresses on the host, whatever the firmware does
+    pub irk_store: IrkStore,
+    // Negotiated with SWITCH_BAUD_RATE_REQ when it isn't SNIFFER_BAUDRATE
+    pub baud_rate: u32,
+    // Advertising channels scanned and their order, see SnifferCommand::SetAdvChannelHopSequence
+    pub adv_channel_hop_sequence: Vec<u8>,
+    pub mode: SnifferMode,
+    pub scan_options: SnifferScanOptions,
+    // Recover the passkey of sniffed LE legacy pairings to decrypt their links,
+    // see LegacyPairingCracker
+    pub crack_legacy_pairing: bool,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SnifferScanOptions {
+    // Also report the SCAN_RSP of the advertisers
+    pub find_scan_rsp: bool,
+    // Follow the AUX_ADV_IND of extended advertising
+    pub find_aux: bool,
+    pub scan_coded: bool,
+}
+
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnifferFollowTarget {
+    pub address: BdAddr,
+    // Don't follow the device into connections
+    pub only_advertisements: bool,
+    pub only_legacy: bool,
+    pub coded: bool,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnifferMode {
+    // GO_IDLE, the firmware stops reporting packets
+    Idle,
+    // REQ_SCAN_CONT
+    Scan,
+    // REQ_FOLLOW
+    Follow(SnifferFollowTarget),
+}
+
+// Sent to the analyze_serial_packets thread, applied while the port stays open
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum SnifferControl {
+    Stop,
+    GoIdle,
+    Scan,
+    Follow(SnifferFollowTarget),
+    SetScanOptions(SnifferScanOptions),
+    SetKeys(Vec<SnifferKey>),
+    SetAdvHopSequence(Vec<u8>),
+}
+
+#[allow(unused)]
+#[derive(Debug, PartialEq)]
 pub struct BlePacket {
-    pub valid: bool,
     pub protocol_version: u8,
     pub packet_counter: u16,
+    // UART packets lost between the previous packet and this one, see PacketCounterTracker
+    pub dropped_before: u16,
     pub packet_id: u8,
     pub packet_header: BlePacketHeader,
     pub ll_layer_data: BleLinkLayer,
 }
 
+// Reference: sniffer_uart_protocol.pdf
+// The 6 bytes starting every UART packet, in all protocol versions
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
+pub struct UartPacketHeader {
+    pub protocol_version: u8,
+    pub payload_length: u16,
+    pub packet_counter: u16,
+    pub packet_id: u8,
+}
+
+#[allow(unused)]
+#[derive(Debug, PartialEq)]
 pub struct BlePacketHeader {
     pub protocol_version: u8,
     pub crc_ok: bool,
     pub adv_header: Option<BlePacketHeaderAdv>,
     pub data_header: Option<BlePacketHeaderData>,
     pub phy: u8,
+    // PHY_CODED_CI_S8 or PHY_CODED_CI_S2, only for coded PHY packets
+    pub coding_indicator: Option<u8>,
     pub channel_index: u8,
     pub rssi: i16,
     pub event_counter: u16,
+    // From the end of the previous packet to the start of this one. Protocol version 3
+    // sends timestamp_us instead, see PacketTimeline
     pub delta_time_us: u32,
+    // Firmware clock at the start of the packet, see SnifferClock
+    pub timestamp_us: Option<u32>,
+    // Host time of timestamp_us, set by analyze_transport_packets once RESP_TIMESTAMP
+    // synced the clocks
+    pub host_time: Option<SystemTime>,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BlePacketHeaderAdv {
-    aux_type: u8,
-    address_resolved: bool,
+    pub aux_type: u8,
+    pub address_resolved: bool,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BlePacketHeaderData {
-    direction_to_slave: bool,
-    encrypted: bool,
-    mic_ok: bool,
+    pub direction_to_slave: bool,
+    pub encrypted: bool,
+    pub mic_ok: bool,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLinkLayer {
     pub access_address: u32,
     pub pdu_type: u8,
@@ -172,12 +321,26 @@ pub struct BleLinkLayer {
     pub rx_address_public: bool,
     pub non_conn_ind: Option<BleLLNonConnIndMsg>,
     pub scan_req: Option<BleLLScanReqMsg>,
+    pub connect_ind: Option<BleLLConnectIndMsg>,
+    pub data_pdu: Option<BleLLDataPdu>,
+    // Names of the IRKs resolving the TxAdd/RxAdd addresses, see IrkStore::annotate
+    pub tx_identity: Option<String>,
+    pub rx_identity: Option<String>,
+    // The PDU as sent on air (header, length and payload) and the CRC following it,
+    // see crc.rs
+    pub pdu: Vec<u8>,
+    pub crc: Option<[u8; 3]>,
+    // Set by analyze_transport_packets with BleCrcVerifier, whatever crc_ok says
+    pub crc_check: Option<BleCrcCheck>,
+    // ATT PDU of the L2CAP frame the packet completes, paired with its request by
+    // AttTransactionTracker
+    pub att: Option<AttRecord>,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLNonConnIndMsg {
-    pub advertising_mac: [u8; 6],
+    pub advertising_mac: BdAddr,
     pub advertising_types: Vec<u8>,
     pub flags: Option<BleLLDataFlags>,
     pub complete_local_name: Option<BleLLCompleteLocalName>,
@@ -186,14 +349,42 @@ pub struct BleLLNonConnIndMsg {
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLScanReqMsg {
-    pub scanning_mac: [u8; 6],
-    pub advertising_mac: [u8; 6],
+    pub scanning_mac: BdAddr,
+    pub advertising_mac: BdAddr,
+}
+
+#[allow(unused)]
+#[derive(Debug, PartialEq)]
+pub struct BleLLConnectIndMsg {
+    pub initiator_mac: BdAddr,
+    pub advertising_mac: BdAddr,
+    pub access_address: u32,
+    pub crc_init: u32,
+    pub win_size: u8,
+    pub win_offset: u16,
+    pub interval: u16,
+    pub latency: u16,
+    pub timeout: u16,
+    pub channel_map: [u8; 5],
+    pub hop_increment: u8,
+    pub sleep_clock_accuracy: u8,
+}
+
+#[allow(unused)]
+#[derive(Debug, PartialEq)]
+pub struct BleLLDataPdu {
+    pub llid: u8,
+    pub nesn: bool,
+    pub sn: bool,
+    pub more_data: bool,
+    pub cte_info_present: bool,
+    pub payload: Vec<u8>,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLDataFlags {
     pub simultaneous_host: bool,
     pub simultaneous_controller: bool,
@@ -203,219 +394,286 @@ pub struct BleLLDataFlags {
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLCompleteLocalName {
     pub device_name: String,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLTxPowerLevel {
     pub tx_power_level: u8,
 }
 
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct BleLLManufacturerSpecificData {
     pub company_id: u16,
     pub data: Vec<u8>,
 }
 
+#[allow(unused)]
 impl BlePacket {
     pub fn new() -> BlePacket {
         BlePacket {
-            valid: false,
             protocol_version: 0,
             packet_counter: 0,
+            dropped_before: 0,
             packet_id: 0,
             packet_header: BlePacketHeader::new(),
             ll_layer_data: BleLinkLayer::new(),
         }
     }
 
-    pub fn from(bytes: &Vec<u8>) -> BlePacket {
-        let mut ll_payload_len: u8 = 0;
-        let mut ll_payload_index: u8 = 0;
-        let mut ll_payload_read_status: u8 = 0;
-        let mut ll_payload_info_len: u8 = 0;
-        let mut ll_payload_info_index: u8 = 0;
-        let mut ll_payload_info_type: u8 = 0;
+    pub fn from(bytes: &[u8]) -> Result<BlePacket, ParseError> {
         let mut result = BlePacket::new();
-        let mut cache_bytes: Vec<u8> = Vec::new();
-        let mut byte_index = 0;
-        let mut non_conn_ind_msg = BleLLNonConnIndMsg::new();
-        let mut scan_req_msg = BleLLScanReqMsg::new();
-        for b in bytes {
-            // Reference:
-            // Bytes index < 16: Sniffer API Guide.pdf & sniffer_uart_protocol.txt
-            // Bytes index >= 16: Core v5.4 vol.6. PartB Chapter2
-            if byte_index == 0 && *b != HEADER_LENGTH {
-                return result;
-            } else if byte_index == 2 {
-                result.protocol_version = *b;
-            } else if byte_index == 3 {
-                result.packet_counter |= *b as u16;
-            } else if byte_index == 4 {
-                result.packet_counter |= (*b as u16) << 8;
-            } else if byte_index == 5 {
-                result.packet_id = *b;
-            } else if byte_index == 7 {
-                result.packet_header.crc_ok = (*b & 1) == 1;
-                result.packet_header.phy = (*b & 0b1110000) >> 4;
-                if result.packet_id == EVENT_PACKET_DATA_PDU {
-                    let data_header = BlePacketHeaderData {
-                        direction_to_slave: ((*b & 0b10) >> 1) == 1,
-                        encrypted: ((*b & 0b100) >> 2) == 1,
-                        mic_ok: ((*b & 0b1000) >> 3) == 1,
-                    };
-                    result.packet_header.data_header = Some(data_header);
-                } else if result.packet_id == EVENT_PACKET_ADV_PDU {
-                    let adv_header = BlePacketHeaderAdv {
-                        aux_type: (*b & 0b110) >> 1,
-                        address_resolved: ((*b & 0b1000) >> 3) == 1,
-                    };
-                    result.packet_header.adv_header = Some(adv_header);
-                }
-            } else if byte_index == 8 {
-                result.packet_header.channel_index = *b;
-            } else if byte_index == 9 {
-                result.packet_header.rssi = 0 - *b as i16;
-            } else if byte_index == 10 {
-                result.packet_header.event_counter |= *b as u16;
-            } else if byte_index == 11 {
-                result.packet_header.event_counter |= (*b as u16) << 8;
-            } else if byte_index == 12 {
-                result.packet_header.delta_time_us |= *b as u32;
-            } else if byte_index == 13 {
-                result.packet_header.delta_time_us |= (*b as u32) << 8;
-            } else if byte_index == 14 {
-                result.packet_header.delta_time_us |= (*b as u32) << 16;
-            } else if byte_index == 15 {
-                result.packet_header.delta_time_us |= (*b as u32) << 24;
-            } else if byte_index == 16 {
-                result.ll_layer_data.access_address |= *b as u32;
-            } else if byte_index == 17 {
-                result.ll_layer_data.access_address |= (*b as u32) << 8;
-            } else if byte_index == 18 {
-                result.ll_layer_data.access_address |= (*b as u32) << 16;
-            } else if byte_index == 19 {
-                result.ll_layer_data.access_address |= (*b as u32) << 24;
-            } else if byte_index == 20 {
-                result.ll_layer_data.pdu_type = *b & 0b1111;
-                result.ll_layer_data.channel_select = (*b & 0x20) >> 5;
-                result.ll_layer_data.tx_address_public = ((*b & 0x40) >> 6) == 0;
-                result.ll_layer_data.rx_address_public = ((*b & 0x80) >> 7) == 0;
-            } else if byte_index == 21 {
-                // Differences between wireshark and raw uart bytes: WTF!
-                // An extra zero byte is right here in the raw uart bytes
-                if ll_payload_len == 0 {
-                    ll_payload_len = *b;
-                    byte_index -= 1;
-                } else {
-                    if result.ll_layer_data.pdu_type == ADV_TYPE_SCAN_REQ && ll_payload_len != 12 {
-                        return result;
-                    }
-                }
-            } else if byte_index >= 22 && byte_index <= 27 {
-                if result.ll_layer_data.pdu_type == ADV_TYPE_ADV_NONCONN_IND {
-                    non_conn_ind_msg.advertising_mac[27 - byte_index] = *b;
-                } else if result.ll_layer_data.pdu_type == ADV_TYPE_SCAN_REQ {
-                    scan_req_msg.scanning_mac[27 - byte_index] = *b;
-                }
-                ll_payload_index += 1;
+        let uart_header = UartPacketHeader::from(bytes)?;
+        result.protocol_version = uart_header.protocol_version;
+        result.packet_counter = uart_header.packet_counter;
+        result.packet_id = uart_header.packet_id;
+        if result.packet_id != EVENT_PACKET_ADV_PDU && result.packet_id != EVENT_PACKET_DATA_PDU {
+            return Err(ParseError::NotAPacket(result.packet_id));
+        }
+        // Anything after the payload length of the UART header isn't part of the packet
+        let payload_length = uart_header.payload_length as usize;
+        check_length(bytes, HEADER_LENGTH as usize, payload_length)?;
+        let bytes = &bytes[..HEADER_LENGTH as usize + payload_length];
+        result.packet_header = BlePacketHeader::from(bytes, &uart_header)?;
+
+        // Reference: Core v5.4 vol.6 PartB Chapter2
+        // The packet as received by the radio follows the packet header
+        let mut index = HEADER_LENGTH as usize + bytes[HEADER_LENGTH as usize] as usize;
+        check_length(bytes, index, 4)?;
+        result.ll_layer_data.access_address = read_u32_le(bytes, index);
+        index += 4;
+        // Coded PHY packets have the coding indicator between the access address
+        // and the PDU header (Core v5.4 vol.6 PartB 2.2)
+        if result.packet_header.phy == PHY_CODED {
+            check_length(bytes, index, 1)?;
+            result.packet_header.coding_indicator = Some(bytes[index] & 0b11);
+            index += 1;
+        }
+        // Header and length are followed by the padding byte of the radio's S1 field
+        check_length(bytes, index, 2 + UART_PDU_PADDING_LENGTH)?;
+        let payload_index = index + 2 + UART_PDU_PADDING_LENGTH;
+        check_length(bytes, payload_index, bytes[index + 1] as usize)?;
+        let pdu_end = payload_index + bytes[index + 1] as usize;
+        result.ll_layer_data.pdu = vec![bytes[index], bytes[index + 1]];
+        result
+            .ll_layer_data
+            .pdu
+            .extend_from_slice(&bytes[payload_index..pdu_end]);
+        if bytes.len() >= pdu_end + 3 {
+            result.ll_layer_data.crc = bytes[pdu_end..pdu_end + 3].try_into().ok();
+        }
+
+        if result.packet_id == EVENT_PACKET_DATA_PDU {
+            result.ll_layer_data.data_pdu = BleLLDataPdu::from(&result.ll_layer_data.pdu);
+        } else {
+            result.parse_adv_pdu()?;
+        }
+        Ok(result)
+    }
+
+    // Time the packet took on air, from the preamble to the end of the CRC
+    pub fn airtime_us(&self) -> Option<u32> {
+        let payload_len = *self.ll_layer_data.pdu.get(1)?;
+        pdu_airtime_us(
+            self.packet_header.phy,
+            self.packet_header.coding_indicator,
+            payload_len as usize,
+        )
+    }
+
+    // The PDU as sent on air, rebuilt from the decoded messages so fields changed
+    // since BlePacket::from (e.g. anonymized addresses) are taken into account.
+    // PDUs without a decoded message are given back as captured.
+    pub fn to_pdu(&self) -> Vec<u8> {
+        let ll_layer_data = &self.ll_layer_data;
+        if let Some(data_pdu) = &ll_layer_data.data_pdu {
+            return data_pdu.to_pdu();
+        }
+        let (payload, tx_random, rx_random) =
+            if let Some(non_conn_ind) = &ll_layer_data.non_conn_ind {
+                let advertising_data = ll_layer_data.pdu.get(8..).unwrap_or_default();
+                (
+                    non_conn_ind.to_payload(advertising_data),
+                    non_conn_ind.advertising_mac.is_random(),
+                    !ll_layer_data.rx_address_public,
+                )
+            } else if let Some(scan_req) = &ll_layer_data.scan_req {
+                (
+                    scan_req.to_payload(),
+                    scan_req.scanning_mac.is_random(),
+                    scan_req.advertising_mac.is_random(),
+                )
+            } else if let Some(connect_ind) = &ll_layer_data.connect_ind {
+                (
+                    connect_ind.to_payload(),
+                    connect_ind.initiator_mac.is_random(),
+                    connect_ind.advertising_mac.is_random(),
+                )
             } else {
-                if ll_payload_index < ll_payload_len {
-                    if result.ll_layer_data.pdu_type == ADV_TYPE_ADV_NONCONN_IND {
-                        if ll_payload_read_status == 0 {
-                            ll_payload_info_len = *b;
-                            ll_payload_info_index = 0;
-                            ll_payload_info_type = 0;
-                            ll_payload_read_status = 1;
-                            cache_bytes.clear();
-                        } else if ll_payload_read_status == 1 {
-                            ll_payload_info_type = *b;
-                            non_conn_ind_msg.advertising_types.push(*b);
-                            ll_payload_read_status = 2;
-                            ll_payload_info_index += 1;
-                        } else if ll_payload_read_status == 2 {
-                            ll_payload_info_index += 1;
-                            if ll_payload_info_type == 0x01 {
-                                let flags = BleLLDataFlags {
-                                    simultaneous_host: ((*b >> 4) & 1) == 1,
-                                    simultaneous_controller: ((*b >> 3) & 1) == 1,
-                                    br_edr_support: ((*b >> 2) & 1) == 1,
-                                    le_general_discoverale: ((*b >> 1) & 1) == 1,
-                                    le_limited_discoverable: (*b & 1) == 1,
-                                };
-                                non_conn_ind_msg.flags = Some(flags);
-                            } else if ll_payload_info_type == 0x09 {
-                                cache_bytes.push(*b);
-                                if ll_payload_info_index == ll_payload_info_len {
-                                    match String::from_utf8(cache_bytes.clone()) {
-                                        Ok(name) => {
-                                            let complete_local_name =
-                                                BleLLCompleteLocalName { device_name: name };
-                                            non_conn_ind_msg.complete_local_name =
-                                                Some(complete_local_name);
-                                        }
-                                        Err(_) => {}
-                                    }
-                                }
-                            } else if ll_payload_info_type == 0x0a {
-                                non_conn_ind_msg.tx_power_level =
-                                    Some(BleLLTxPowerLevel { tx_power_level: *b });
-                            } else if ll_payload_info_type == 0xff {
-                                cache_bytes.push(*b);
-                                if ll_payload_info_index == ll_payload_info_len {
-                                    let mut cache_bytes_index = 0;
-                                    let mut company_id: u16 = 0;
-                                    let mut extra_data: Vec<u8> = Vec::new();
-                                    for b1 in &cache_bytes {
-                                        if cache_bytes_index == 0 {
-                                            company_id |= *b1 as u16;
-                                        } else if cache_bytes_index == 1 {
-                                            company_id |= (*b1 as u16) << 8;
-                                        } else {
-                                            extra_data.push(*b1);
-                                        }
-                                        cache_bytes_index += 1;
-                                    }
-                                    let manufacturer_data = BleLLManufacturerSpecificData {
-                                        company_id,
-                                        data: extra_data,
-                                    };
-                                    non_conn_ind_msg.manufacturer_data = Some(manufacturer_data);
-                                }
-                            }
-                            if ll_payload_info_index == ll_payload_info_len {
-                                ll_payload_read_status = 0;
-                            }
-                        }
-                    } else if result.ll_layer_data.pdu_type == ADV_TYPE_SCAN_REQ {
-                        scan_req_msg.advertising_mac[33 - byte_index] = *b;
-                    }
-                    ll_payload_index += 1;
+                return ll_layer_data.pdu.clone();
+            };
+        let mut pdu = vec![
+            ll_layer_data.pdu_type
+                | (ll_layer_data.channel_select << 5)
+                | ((tx_random as u8) << 6)
+                | ((rx_random as u8) << 7),
+            payload.len() as u8,
+        ];
+        pdu.extend_from_slice(&payload);
+        pdu
+    }
+
+    // Recomputes the CRC of to_pdu(), e.g. with the CRCInit of CONNECT_IND once a
+    // data PDU was rewritten
+    pub fn update_crc(&mut self, crc_init: u32) {
+        self.ll_layer_data.pdu = self.to_pdu();
+        self.ll_layer_data.crc = Some(ble_crc24(crc_init, &self.ll_layer_data.pdu));
+    }
+
+    // The UART packet BlePacket::from decodes back into this packet, without the SLIP
+    // framing. The captured CRC is kept while the PDU is unchanged, rewritten
+    // advertising PDUs get theirs recomputed and rewritten data PDUs have none,
+    // see update_crc.
+    // Packets made with BlePacket::new get the protocol version 2 header.
+    pub fn to_uart_packet(&self) -> Vec<u8> {
+        let protocol_version = match self.protocol_version {
+            0 => PROTOVER_V2,
+            protocol_version => protocol_version,
+        };
+        let packet_header = &self.packet_header;
+        let mut flags = (packet_header.crc_ok as u8) | ((packet_header.phy & 0b111) << 4);
+        if let Some(data_header) = &packet_header.data_header {
+            flags |= ((data_header.direction_to_slave as u8) << 1)
+                | ((data_header.encrypted as u8) << 2)
+                | ((data_header.mic_ok as u8) << 3);
+        } else if let Some(adv_header) = &packet_header.adv_header {
+            flags |=
+                ((adv_header.aux_type & 0b11) << 1) | ((adv_header.address_resolved as u8) << 3);
+        }
+        let time = if protocol_version >= PROTOVER_V3 {
+            packet_header.timestamp_us.unwrap_or(0)
+        } else {
+            packet_header.delta_time_us
+        };
+        let mut payload = vec![
+            PACKET_HEADER_LENGTH,
+            flags,
+            packet_header.channel_index,
+            packet_header.rssi.unsigned_abs() as u8,
+        ];
+        payload.extend_from_slice(&packet_header.event_counter.to_le_bytes());
+        payload.extend_from_slice(&time.to_le_bytes());
+        payload.extend_from_slice(&self.ll_layer_data.access_address.to_le_bytes());
+        if packet_header.phy == PHY_CODED {
+            payload.push(packet_header.coding_indicator.unwrap_or(PHY_CODED_CI_S8));
+        }
+        let pdu = self.to_pdu();
+        payload.extend_from_slice(&pdu[..pdu.len().min(2)]);
+        payload.extend_from_slice(&[0; UART_PDU_PADDING_LENGTH]);
+        payload.extend_from_slice(pdu.get(2..).unwrap_or_default());
+        // The CRCInit of data PDUs isn't known here, rewritten ones are sent without
+        // a CRC until update_crc is called
+        let crc = if pdu == self.ll_layer_data.pdu {
+            self.ll_layer_data.crc
+        } else if self.packet_id == EVENT_PACKET_DATA_PDU {
+            None
+        } else {
+            Some(ble_crc24(BLE_ADV_CRC_INIT, &pdu))
+        };
+        if let Some(crc) = crc {
+            payload.extend_from_slice(&crc);
+        }
+        make_event_packet(
+            protocol_version,
+            self.packet_id,
+            &payload,
+            self.packet_counter,
+        )
+    }
+
+    // The SLIP encoded UART packet, as the firmware sends it
+    pub fn encode(&self) -> Vec<u8> {
+        slip_encode(&self.to_uart_packet())
+    }
+
+    // Reference: Core v5.4 vol.6 PartB 2.3
+    // Parses ll_layer_data.pdu: byte 0 is the advertising PDU header, byte 1 the
+    // payload length and the payload starts at byte 2. BlePacket::from made sure
+    // the payload holds the length given.
+    fn parse_adv_pdu(&mut self) -> Result<(), ParseError> {
+        let ll_layer_data = &mut self.ll_layer_data;
+        let pdu = &ll_layer_data.pdu;
+        check_length(pdu, 0, 2)?;
+        let header = pdu[0];
+        let pdu_type = header & 0b1111;
+        let tx_random = (header >> 6) & 1 == 1;
+        let rx_random = (header >> 7) & 1 == 1;
+        ll_layer_data.pdu_type = pdu_type;
+        ll_layer_data.channel_select = (header >> 5) & 1;
+        ll_layer_data.tx_address_public = !tx_random;
+        ll_layer_data.rx_address_public = !rx_random;
+        let length = pdu[1];
+        let payload = &pdu[2..];
+        let expected_length = match pdu_type {
+            ADV_TYPE_ADV_NONCONN_IND => (6..=37).contains(&length),
+            ADV_TYPE_SCAN_REQ => length == 12,
+            ADV_TYPE_CONNECT_REQ => length == 34,
+            _ => return Err(ParseError::UnsupportedPduType(pdu_type)),
+        };
+        if !expected_length || payload.len() != length as usize {
+            return Err(ParseError::BadPayloadLength { pdu_type, length });
+        }
+        match pdu_type {
+            ADV_TYPE_ADV_NONCONN_IND => {
+                let non_conn_ind = BleLLNonConnIndMsg::from(payload, tx_random)?;
+                if non_conn_ind.advertising_mac.is_zero() {
+                    return Err(ParseError::MissingAddress);
                 }
+                ll_layer_data.non_conn_ind = Some(non_conn_ind);
             }
-            byte_index += 1;
-        }
-        result.valid = true;
-        if result.ll_layer_data.pdu_type == ADV_TYPE_ADV_NONCONN_IND {
-            if mac_bytes_all_zero(&non_conn_ind_msg.advertising_mac) {
-                result.valid = false;
+            ADV_TYPE_SCAN_REQ => {
+                let scan_req = BleLLScanReqMsg {
+                    scanning_mac: BdAddr::from_le(&payload[..6], tx_random),
+                    advertising_mac: BdAddr::from_le(&payload[6..12], rx_random),
+                };
+                if scan_req.advertising_mac.is_zero() && scan_req.scanning_mac.is_zero() {
+                    return Err(ParseError::MissingAddress);
+                }
+                ll_layer_data.scan_req = Some(scan_req);
             }
-            result.ll_layer_data.non_conn_ind = Some(non_conn_ind_msg);
-        } else if result.ll_layer_data.pdu_type == ADV_TYPE_SCAN_REQ {
-            if mac_bytes_all_zero(&scan_req_msg.advertising_mac)
-                && mac_bytes_all_zero(&scan_req_msg.scanning_mac)
-            {
-                result.valid = false;
+            _ => {
+                ll_layer_data.connect_ind = BleLLConnectIndMsg::from(pdu);
             }
-            result.ll_layer_data.scan_req = Some(scan_req_msg);
-        } else {
-            result.valid = false;
         }
-        result
+        Ok(())
+    }
+}
+
+impl SnifferConfig {
+    pub fn new() -> SnifferConfig {
+        SnifferConfig {
+            keys: Vec::new(),
+            irk_store: IrkStore::new(),
+            baud_rate: SNIFFER_BAUDRATE,
+            adv_channel_hop_sequence: ADV_CHANNELS.to_vec(),
+            mode: SnifferMode::Scan,
+            scan_options: SnifferScanOptions::new(),
+            crack_legacy_pairing: false,
+        }
+    }
+}
+
+impl SnifferScanOptions {
+    pub fn new() -> SnifferScanOptions {
+        SnifferScanOptions {
+            find_scan_rsp: false,
+            find_aux: false,
+            scan_coded: false,
+        }
     }
 }
 
@@ -427,12 +685,117 @@ impl BlePacketHeader {
             data_header: Option::None,
             adv_header: Option::None,
             phy: 0,
+            coding_indicator: None,
             channel_index: 0,
             rssi: 0,
             event_counter: 0,
             delta_time_us: 0,
+            timestamp_us: None,
+            host_time: None,
         }
     }
+
+    // Reference: Sniffer API Guide.pdf & sniffer_uart_protocol.txt
+    // Starts after the UART header with its own length, then flags, channel, RSSI,
+    // event counter and the time field whose meaning depends on the protocol version
+    pub fn from(
+        bytes: &[u8],
+        uart_header: &UartPacketHeader,
+    ) -> Result<BlePacketHeader, ParseError> {
+        let index = HEADER_LENGTH as usize;
+        if bytes.len() < index + PACKET_HEADER_LENGTH as usize {
+            return Err(ParseError::Truncated {
+                offset: index,
+                needed: PACKET_HEADER_LENGTH as usize,
+            });
+        }
+        if bytes[index] < PACKET_HEADER_LENGTH {
+            return Err(ParseError::BadHeaderLength(bytes[index]));
+        }
+        let mut result = BlePacketHeader::new();
+        result.protocol_version = uart_header.protocol_version;
+        let flags = bytes[index + 1];
+        result.crc_ok = (flags & 1) == 1;
+        result.phy = (flags & 0b1110000) >> 4;
+        if uart_header.packet_id == EVENT_PACKET_DATA_PDU {
+            result.data_header = Some(BlePacketHeaderData {
+                direction_to_slave: ((flags & 0b10) >> 1) == 1,
+                encrypted: ((flags & 0b100) >> 2) == 1,
+                mic_ok: ((flags & 0b1000) >> 3) == 1,
+            });
+        } else if uart_header.packet_id == EVENT_PACKET_ADV_PDU {
+            result.adv_header = Some(BlePacketHeaderAdv {
+                aux_type: (flags & 0b110) >> 1,
+                address_resolved: ((flags & 0b1000) >> 3) == 1,
+            });
+        }
+        result.channel_index = bytes[index + 2];
+        result.rssi = 0 - bytes[index + 3] as i16;
+        result.event_counter = read_u16_le(bytes, index + 4);
+        let time = read_u32_le(bytes, index + 6);
+        if uart_header.protocol_version >= PROTOVER_V3 {
+            result.timestamp_us = Some(time);
+        } else {
+            result.delta_time_us = time;
+        }
+        Ok(result)
+    }
+}
+
+#[allow(unused)]
+impl UartPacketHeader {
+    pub fn from(bytes: &[u8]) -> Result<UartPacketHeader, ParseError> {
+        if bytes.len() < HEADER_LENGTH as usize {
+            return Err(ParseError::Truncated {
+                offset: 0,
+                needed: HEADER_LENGTH as usize,
+            });
+        }
+        match bytes[2] {
+            PROTOVER_V1 => UartPacketHeader::from_v1(bytes),
+            PROTOVER_V2 | PROTOVER_V3 => UartPacketHeader::from_v2(bytes),
+            protocol_version => Err(ParseError::UnsupportedProtocolVersion(protocol_version)),
+        }
+    }
+
+    // Newer protocol versions keep the layout of version 2, so the firmware version
+    // they answer with can still be read and reported
+    pub fn from_any_version(bytes: &[u8]) -> Result<UartPacketHeader, ParseError> {
+        if bytes.len() < HEADER_LENGTH as usize {
+            return Err(ParseError::Truncated {
+                offset: 0,
+                needed: HEADER_LENGTH as usize,
+            });
+        }
+        match bytes[2] {
+            PROTOVER_V1 => UartPacketHeader::from_v1(bytes),
+            _ => UartPacketHeader::from_v2(bytes),
+        }
+    }
+
+    // Header length and an 8 bits payload length
+    fn from_v1(bytes: &[u8]) -> Result<UartPacketHeader, ParseError> {
+        if bytes[0] != HEADER_LENGTH {
+            return Err(ParseError::BadHeaderLength(bytes[0]));
+        }
+        Ok(UartPacketHeader {
+            protocol_version: bytes[2],
+            payload_length: bytes[1] as u16,
+            packet_counter: read_u16_le(bytes, 3),
+            packet_id: bytes[5],
+        })
+    }
+
+    // The header length is dropped for a 16 bits payload length, extended
+    // advertising PDUs don't fit in 255 bytes with the packet header
+    fn from_v2(bytes: &[u8]) -> Result<UartPacketHeader, ParseError> {
+        Ok(UartPacketHeader {
+            protocol_version: bytes[2],
+            payload_length: read_u16_le(bytes, 0),
+            packet_counter: read_u16_le(bytes, 3),
+            packet_id: bytes[5],
+        })
+    }
 }
 
 impl BleLinkLayer {
@@ -445,14 +808,23 @@ impl BleLinkLayer {
             rx_address_public: false,
             non_conn_ind: None,
             scan_req: None,
+            connect_ind: None,
+            data_pdu: None,
+            tx_identity: None,
+            rx_identity: None,
+            pdu: Vec::new(),
+            crc: None,
+            crc_check: None,
+            att: None,
         }
     }
 }
 
+#[allow(unused)]
 impl BleLLNonConnIndMsg {
     pub fn new() -> BleLLNonConnIndMsg {
         BleLLNonConnIndMsg {
-            advertising_mac: [0; 6],
+            advertising_mac: BdAddr::new([0; 6], false),
             advertising_types: Vec::new(),
             flags: None,
             complete_local_name: None,
@@ -460,175 +832,763 @@ impl BleLLNonConnIndMsg {
             manufacturer_data: None,
         }
     }
+
+    // AdvA and the advertising data. The AD structures of advertising_data (as
+    // captured) are kept in order with the decoded ones replaced by the fields of
+    // this message, or dropped when the field was cleared. Fields missing there are
+    // appended.
+    pub fn to_payload(&self, advertising_data: &[u8]) -> Vec<u8> {
+        let mut payload = self.advertising_mac.to_le().to_vec();
+        let mut written: Vec<u8> = Vec::new();
+        let mut index = 0;
+        while index < advertising_data.len() {
+            let length = advertising_data[index] as usize;
+            let structure = match advertising_data.get(index + 1..index + 1 + length) {
+                Some(structure) if length > 0 => structure,
+                _ => break,
+            };
+            let ad_type = structure[0];
+            match self.ad_data(ad_type) {
+                Some(data) => {
+                    if !written.contains(&ad_type) {
+                        push_ad_structure(&mut payload, ad_type, &data);
+                        written.push(ad_type);
+                    }
+                }
+                None if is_decoded_ad_structure(structure) => {}
+                None => payload.extend_from_slice(&advertising_data[index..index + 1 + length]),
+            }
+            index += 1 + length;
+        }
+        for ad_type in [0x01, 0x09, 0x0a, 0xff] {
+            if written.contains(&ad_type) {
+                continue;
+            }
+            if let Some(data) = self.ad_data(ad_type) {
+                push_ad_structure(&mut payload, ad_type, &data);
+            }
+        }
+        payload
+    }
+
+    // Data of the AD types decoded into this message
+    fn ad_data(&self, ad_type: u8) -> Option<Vec<u8>> {
+        match ad_type {
+            0x01 => self.flags.as_ref().map(|flags| {
+                vec![
+                    ((flags.simultaneous_host as u8) << 4)
+                        | ((flags.simultaneous_controller as u8) << 3)
+                        | ((flags.br_edr_support as u8) << 2)
+                        | ((flags.le_general_discoverale as u8) << 1)
+                        | (flags.le_limited_discoverable as u8),
+                ]
+            }),
+            0x09 => self
+                .complete_local_name
+                .as_ref()
+                .map(|name| name.device_name.as_bytes().to_vec()),
+            0x0a => self
+                .tx_power_level
+                .as_ref()
+                .map(|tx_power_level| vec![tx_power_level.tx_power_level]),
+            0xff => self.manufacturer_data.as_ref().map(|manufacturer_data| {
+                let mut data = manufacturer_data.company_id.to_le_bytes().to_vec();
+                data.extend_from_slice(&manufacturer_data.data);
+                data
+            }),
+            _ => None,
+        }
+    }
+
+    // Reference: Core v5.4 vol.6 PartB 2.3.1.3 & vol.3 PartC 11
+    // payload is AdvA followed by the advertising data, a list of AD structures:
+    // length, AD type and length - 1 bytes of data
+    pub fn from(payload: &[u8], tx_random: bool) -> Result<BleLLNonConnIndMsg, ParseError> {
+        // Offsets in errors count from the PDU header
+        const PAYLOAD_OFFSET: usize = 2;
+        check_length(payload, 0, 6).map_err(|_| ParseError::BadPayloadLength {
+            pdu_type: ADV_TYPE_ADV_NONCONN_IND,
+            length: payload.len() as u8,
+        })?;
+        let mut result = BleLLNonConnIndMsg::new();
+        result.advertising_mac = BdAddr::from_le(&payload[..6], tx_random);
+        let mut index = 6;
+        while index < payload.len() {
+            let length = payload[index] as usize;
+            // A zero length ends the advertising data early
+            if length == 0 {
+                break;
+            }
+            let structure = match payload.get(index + 1..index + 1 + length) {
+                Some(structure) => structure,
+                None => {
+                    return Err(ParseError::MalformedAdStructure {
+                        offset: PAYLOAD_OFFSET + index,
+                    })
+                }
+            };
+            let ad_type = structure[0];
+            let data = &structure[1..];
+            result.advertising_types.push(ad_type);
+            match ad_type {
+                0x01 => {
+                    if let Some(b) = data.first() {
+                        result.flags = Some(BleLLDataFlags {
+                            simultaneous_host: ((*b >> 4) & 1) == 1,
+                            simultaneous_controller: ((*b >> 3) & 1) == 1,
+                            br_edr_support: ((*b >> 2) & 1) == 1,
+                            le_general_discoverale: ((*b >> 1) & 1) == 1,
+                            le_limited_discoverable: (*b & 1) == 1,
+                        });
+                    }
+                }
+                0x09 => {
+                    if let Ok(name) = String::from_utf8(data.to_vec()) {
+                        result.complete_local_name =
+                            Some(BleLLCompleteLocalName { device_name: name });
+                    }
+                }
+                0x0a => {
+                    if let Some(b) = data.first() {
+                        result.tx_power_level = Some(BleLLTxPowerLevel { tx_power_level: *b });
+                    }
+                }
+                0xff if data.len() >= 2 => {
+                    result.manufacturer_data = Some(BleLLManufacturerSpecificData {
+                        company_id: read_u16_le(data, 0),
+                        data: data[2..].to_vec(),
+                    });
+                }
+                _ => {}
+            }
+            index += 1 + length;
+        }
+        Ok(result)
+    }
+}
+
+#[allow(unused)]
+impl BleLLConnectIndMsg {
+    // InitA, AdvA and LLData, the inverse of from without the PDU header
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = self.initiator_mac.to_le().to_vec();
+        payload.extend_from_slice(&self.advertising_mac.to_le());
+        payload.extend_from_slice(&self.access_address.to_le_bytes());
+        payload.extend_from_slice(&self.crc_init.to_le_bytes()[..3]);
+        payload.push(self.win_size);
+        payload.extend_from_slice(&self.win_offset.to_le_bytes());
+        payload.extend_from_slice(&self.interval.to_le_bytes());
+        payload.extend_from_slice(&self.latency.to_le_bytes());
+        payload.extend_from_slice(&self.timeout.to_le_bytes());
+        payload.extend_from_slice(&self.channel_map);
+        payload.push((self.hop_increment & 0b11111) | (self.sleep_clock_accuracy << 5));
+        payload
+    }
+
+    // Reference: Core v5.4 vol.6 PartB 2.3.3.1
+    // bytes is the PDU as sent on air, header and length followed by the payload
+    pub fn from(bytes: &[u8]) -> Option<BleLLConnectIndMsg> {
+        if bytes.len() < 2 + 34 || bytes[1] != 34 {
+            return None;
+        }
+        let payload = &bytes[2..2 + 34];
+        // TxAdd and RxAdd of the advertising PDU header
+        let initiator_mac = BdAddr::from_le(&payload[..6], (bytes[0] >> 6) & 1 == 1);
+        let advertising_mac = BdAddr::from_le(&payload[6..12], (bytes[0] >> 7) & 1 == 1);
+        let mut channel_map = [0; 5];
+        channel_map.copy_from_slice(&payload[28..33]);
+        Some(BleLLConnectIndMsg {
+            initiator_mac,
+            advertising_mac,
+            access_address: (read_u16_le(payload, 12) as u32)
+                | ((read_u16_le(payload, 14) as u32) << 16),
+            crc_init: (payload[16] as u32)
+                | ((payload[17] as u32) << 8)
+                | ((payload[18] as u32) << 16),
+            win_size: payload[19],
+            win_offset: read_u16_le(payload, 20),
+            interval: read_u16_le(payload, 22),
+            latency: read_u16_le(payload, 24),
+            timeout: read_u16_le(payload, 26),
+            channel_map,
+            hop_increment: payload[33] & 0b11111,
+            sleep_clock_accuracy: payload[33] >> 5,
+        })
+    }
 }
 
+#[allow(unused)]
+impl BleLLDataPdu {
+    pub fn to_pdu(&self) -> Vec<u8> {
+        let mut pdu = vec![
+            (self.llid & 0b11)
+                | ((self.nesn as u8) << 2)
+                | ((self.sn as u8) << 3)
+                | ((self.more_data as u8) << 4)
+                | ((self.cte_info_present as u8) << 5),
+            self.payload.len() as u8,
+        ];
+        pdu.extend_from_slice(&self.payload);
+        pdu
+    }
+
+    // Reference: Core v5.4 vol.6 PartB 2.4
+    // bytes is the PDU as sent on air, header and length followed by the payload
+    pub fn from(bytes: &[u8]) -> Option<BleLLDataPdu> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let header = bytes[0];
+        let payload_len = bytes[1] as usize;
+        if bytes.len() < 2 + payload_len {
+            return None;
+        }
+        Some(BleLLDataPdu {
+            llid: header & 0b11,
+            nesn: ((header >> 2) & 1) == 1,
+            sn: ((header >> 3) & 1) == 1,
+            more_data: ((header >> 4) & 1) == 1,
+            cte_info_present: ((header >> 5) & 1) == 1,
+            payload: bytes[2..2 + payload_len].to_vec(),
+        })
+    }
+}
+
+#[allow(unused)]
 impl BleLLScanReqMsg {
+    // ScanA and AdvA
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = self.scanning_mac.to_le().to_vec();
+        payload.extend_from_slice(&self.advertising_mac.to_le());
+        payload
+    }
+
     pub fn new() -> BleLLScanReqMsg {
         BleLLScanReqMsg {
-            scanning_mac: [0; 6],
-            advertising_mac: [0; 6],
+            scanning_mac: BdAddr::new([0; 6], false),
+            advertising_mac: BdAddr::new([0; 6], false),
         }
     }
 }
 
-pub fn analyze_serial_packets(serial_name: &str, tx: Sender<BlePacket>, rx: &Receiver<String>) {
+// serial_name may also be "tcp://host:port", "file://path" or "emulator://",
+// see SnifferSource
+pub fn analyze_serial_packets(
+    serial_name: &str,
+    config: &SnifferConfig,
+    tx: Sender<BlePacket>,
+    rx: &Receiver<SnifferControl>,
+) -> Result<(), SnifferError> {
+    let source = serial_name
+        .parse::<SnifferSource>()
+        .map_err(|_| SnifferError::InvalidSource(serial_name.to_string()))?;
+    analyze_transport_packets(serial_name, || source.open(), config, tx, rx)
+}
+
+// Runs the sniffer on the transports returned by open, which is called again to
+// reconnect. Captures replayed from a transport that isn't live end the thread
+// once exhausted. Errors the sniffer recovers from are logged, see logger.rs,
+// the thread only fails on a firmware it can't talk to.
+pub fn analyze_transport_packets(
+    serial_name: &str,
+    mut open: impl FnMut() -> io::Result<Box<dyn SnifferTransport>>,
+    config: &SnifferConfig,
+    tx: Sender<BlePacket>,
+    rx: &Receiver<SnifferControl>,
+) -> Result<(), SnifferError> {
+    // Far more than a read timeout at the fastest negotiated rate
     const BUFFER_SIZE: usize = (SNIFFER_BAUDRATE / 10) as usize;
     let mut recv_buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-    let mut packet_start = false;
-    let mut previous_byte_is_esc = false;
-    let mut packet_bytes: Vec<u8> = Vec::new();
     let mut stop_request = false;
+    let mut reconnecting = false;
+    // Controls received change this copy, so they survive reconnections
+    let mut config = config.clone();
+    let mut irk_store = config.irk_store.clone();
+    // Connections outlive reconnections to the sniffer
+    let mut crc_verifier = BleCrcVerifier::new();
+    // Keys set later on are added by receive_controls, tried on the links not
+    // decrypted yet
+    let mut link_decryptor = LinkDecryptor::new();
+    for key in &config.keys {
+        link_decryptor.add_sniffer_key(key);
+    }
+    // Dropping it ends its thread
+    let pairing_worker = config.crack_legacy_pairing.then(LegacyPairingWorker::spawn);
+    let mut l2cap_analyzer = L2capAnalyzer::new();
+    let mut packet_counters = PacketCounterTracker::new();
     loop {
-        thread::sleep(Duration::from_secs(1));
-        if thread_should_stop(rx) || stop_request {
+        if receive_controls(rx, &mut config, &mut link_decryptor).0 || stop_request {
             break;
         }
-        match serialport::new(serial_name, SNIFFER_BAUDRATE).open() {
-            Ok(mut serial) => {
-                let mut send_packet_counter: u16 = 0;
-                let mut send_bytes = make_send_scan_bytes(false, false, false, send_packet_counter);
-                send_packet_counter += 1;
-                match serial.write_all(send_bytes.as_slice()) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        println!(
-                            "Failed to send bytes to serial {}, {}",
-                            serial_name,
-                            error.to_string()
+        if reconnecting {
+            thread::sleep(RECONNECT_DELAY);
+        }
+        reconnecting = true;
+        match open() {
+            Ok(mut transport) => {
+                let transport = transport.as_mut();
+                let live = transport.is_live();
+                let mut commander = SnifferCommander::new();
+                let mut slip_decoder = SlipDecoder::new();
+                if live && find_baud_rate(transport, &mut commander).is_none() {
+                    log(
+                        LogLevel::Warning,
+                        &format!("Sniffer {} doesn't answer to ping", serial_name),
+                    );
+                    let _ = transport.set_baud_rate(SNIFFER_BAUDRATE);
+                }
+                let version = match live {
+                    true => request_firmware_version(transport, &mut commander),
+                    false => None,
+                };
+                match version {
+                    Some(version) => {
+                        log(
+                            LogLevel::Info,
+                            &format!("Sniffer firmware version: {}", version.text),
                         );
+                        if !version.is_supported() {
+                            let error = SnifferError::UnsupportedFirmware(version);
+                            log(
+                                LogLevel::Error,
+                                &format!("Sniffer {}, {}", serial_name, error),
+                            );
+                            return Err(error);
+                        }
                     }
-                }
-                send_bytes = make_send_tk_bytes(0, send_packet_counter);
-                match serial.write_all(send_bytes.as_slice()) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        println!(
-                            "Failed to send bytes to serial {}, {}",
-                            serial_name,
-                            error.to_string()
+                    None if live => {
+                        log(
+                            LogLevel::Warning,
+                            "Sniffer firmware doesn't report its version, assuming protocol version 1",
                         );
                     }
+                    None => {}
+                }
+                if live && config.baud_rate != SNIFFER_BAUDRATE {
+                    let baud_rate =
+                        negotiate_baud_rate(transport, &mut commander, config.baud_rate);
+                    log(
+                        LogLevel::Info,
+                        &format!("Sniffer {} runs at {} baud", serial_name, baud_rate),
+                    );
                 }
-                thread::sleep(Duration::from_secs(1));
+                let mut commands = vec![SnifferCommand::SetAdvChannelHopSequence(
+                    config.adv_channel_hop_sequence.clone(),
+                )];
+                commands.append(&mut SnifferCommand::from_keys(&config.keys));
+                commands.push(SnifferCommand::from_mode(&config));
+                for command in commands {
+                    write_to_transport(transport, serial_name, &commander.encode(command));
+                }
+                packet_counters.clear();
+                let mut dropped_before: u16 = 0;
+                let mut clock = SnifferClock::new();
+                let mut timeline = PacketTimeline::new();
+                let mut last_timestamp_request: Option<Instant> = None;
+                let mut last_ping = Instant::now();
+                // Any packet from the firmware proves it is still alive
+                let mut last_alive = Instant::now();
                 loop {
-                    thread::sleep(Duration::from_millis(100));
-                    if thread_should_stop(rx) {
+                    let (stop, commands) = receive_controls(rx, &mut config, &mut link_decryptor);
+                    for command in commands {
+                        write_to_transport(transport, serial_name, &commander.encode(command));
+                    }
+                    if stop {
                         stop_request = true;
                         break;
                     }
-                    match serial.read(&mut recv_buffer) {
+                    if live && last_alive.elapsed() >= WATCHDOG_TIMEOUT {
+                        log(
+                            LogLevel::Warning,
+                            &format!(
+                                "Sniffer {}, {}, reconnecting",
+                                serial_name,
+                                SnifferError::Unresponsive
+                            ),
+                        );
+                        break;
+                    }
+                    if live && last_ping.elapsed() >= WATCHDOG_INTERVAL {
+                        let send_bytes = commander.encode(SnifferCommand::Ping);
+                        write_to_transport(transport, serial_name, &send_bytes);
+                        last_ping = Instant::now();
+                    }
+                    let sync_due = match last_timestamp_request {
+                        Some(instant) => instant.elapsed() >= TIMESTAMP_SYNC_INTERVAL,
+                        None => true,
+                    };
+                    if live && sync_due {
+                        let send_bytes = commander.encode(SnifferCommand::RequestTimestamp);
+                        write_to_transport(transport, serial_name, &send_bytes);
+                        last_timestamp_request = Some(Instant::now());
+                    }
+                    for command in commander.expired() {
+                        log(
+                            LogLevel::Warning,
+                            &format!(
+                                "Sniffer {}, {}",
+                                serial_name,
+                                SnifferError::NoResponse(command)
+                            ),
+                        );
+                    }
+                    match transport.read(&mut recv_buffer) {
                         Ok(available_len) => {
-                            let mut read_index: usize = 0;
-                            for b in recv_buffer {
-                                if read_index == available_len {
-                                    break;
-                                }
-                                read_index += 1;
-                                if !packet_start {
-                                    packet_start = b == SLIP_START;
-                                    continue;
+                            for frame in slip_decoder.push(&recv_buffer[..available_len]) {
+                                let packet_bytes = match frame {
+                                    Ok(packet_bytes) => packet_bytes,
+                                    Err(error) => {
+                                        let error = SnifferError::from(error);
+                                        log(
+                                            LogLevel::Warning,
+                                            &format!("Sniffer {}, {}", serial_name, error),
+                                        );
+                                        continue;
+                                    }
+                                };
+                                last_alive = Instant::now();
+                                // print_hex_bytes(&packet_bytes);
+                                if let Ok(uart_header) = UartPacketHeader::from(&packet_bytes) {
+                                    let gap = packet_counters.process(uart_header.packet_counter);
+                                    dropped_before = dropped_before.saturating_add(gap);
                                 }
-                                if b == SLIP_END {
-                                    packet_start = false;
-                                    // print_hex_bytes(&packet_bytes);
-                                    let ble_packet = BlePacket::from(&packet_bytes);
-                                    if ble_packet.valid {
-                                        let _ = tx.send(ble_packet);
+                                let event = match SnifferEvent::decode(&packet_bytes) {
+                                    Ok(event) => event,
+                                    Err(error) => {
+                                        log(
+                                            LogLevel::Debug,
+                                            &format!("Sniffer {}, {}", serial_name, error),
+                                        );
+                                        continue;
+                                    }
+                                };
+                                commander.correlate(&event);
+                                match event {
+                                    SnifferEvent::Timestamp(timestamp_us) => {
+                                        clock.sync(timestamp_us, SystemTime::now());
+                                        if let Some(drift) = clock.drift_ppm() {
+                                            if drift.abs() > TIMESTAMP_DRIFT_WARNING_PPM {
+                                                log(
+                                                    LogLevel::Warning,
+                                                    &format!(
+                                                        "Sniffer clock drifts {:.0} ppm from the host",
+                                                        drift
+                                                    ),
+                                                );
+                                            }
+                                        }
                                     }
-                                    packet_bytes.clear();
-                                } else if b == SLIP_ESC {
-                                    previous_byte_is_esc = true;
-                                } else if previous_byte_is_esc {
-                                    if b == SLIP_ESC_START {
-                                        packet_bytes.push(SLIP_START);
-                                    } else if b == SLIP_ESC_END {
-                                        packet_bytes.push(SLIP_END);
-                                    } else if b == SLIP_ESC_ESC {
-                                        packet_bytes.push(SLIP_ESC);
-                                    } else {
-                                        packet_bytes.push(b);
+                                    SnifferEvent::Packet(mut ble_packet) => {
+                                        ble_packet.dropped_before = dropped_before;
+                                        dropped_before = 0;
+                                        timeline.process(&mut ble_packet);
+                                        ble_packet.packet_header.host_time = ble_packet
+                                            .packet_header
+                                            .timestamp_us
+                                            .and_then(|timestamp_us| clock.host_time(timestamp_us));
+                                        irk_store.annotate(&mut ble_packet);
+                                        ble_packet.ll_layer_data.crc_check =
+                                            Some(crc_verifier.process(&ble_packet));
+                                        if let Some(worker) = &pairing_worker {
+                                            add_cracked_keys(
+                                                serial_name,
+                                                worker,
+                                                &mut link_decryptor,
+                                            );
+                                        }
+                                        // Payloads are replaced with the plaintext, the
+                                        // captured PDU and its CRC are left as is
+                                        link_decryptor.process(&mut ble_packet);
+                                        let frame =
+                                            l2cap_analyzer.process(serial_name, &mut ble_packet);
+                                        if let Some(worker) = &pairing_worker {
+                                            worker.process_packet(&ble_packet);
+                                            if let Some(frame) = &frame {
+                                                worker.process_frame(frame);
+                                            }
+                                        }
+                                        let _ = tx.send(*ble_packet);
                                     }
-                                    previous_byte_is_esc = false;
-                                } else {
-                                    packet_bytes.push(b);
+                                    _ => {}
                                 }
                             }
                         }
-                        Err(error) => {
-                            println!("Serial error occurs: {}", error.to_string());
+                        Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                            log(
+                                LogLevel::Info,
+                                &format!("Sniffer {} has nothing more to read", serial_name),
+                            );
+                            stop_request = true;
+                            break;
                         }
-                    }
-                    match serial.clear(serialport::ClearBuffer::Input) {
-                        Ok(_) => {}
                         Err(error) => {
-                            println!("Cannot clear serial inputs, {}", error.to_string());
+                            let error = SnifferError::from(error);
+                            log(
+                                LogLevel::Error,
+                                &format!("Sniffer {}, {}, reconnecting", serial_name, error),
+                            );
+                            break;
                         }
                     }
                 }
             }
             Err(error) => {
-                println!(
-                    "Cannot open serial {}\nReason: {}",
-                    serial_name,
-                    error.to_string()
+                let error = SnifferError::from(error);
+                log(
+                    LogLevel::Error,
+                    &format!("Cannot open sniffer {}, {}", serial_name, error),
                 );
             }
         }
     }
+    if packet_counters.dropped() > 0 {
+        log(
+            LogLevel::Warning,
+            &format!(
+                "Sniffer {} dropped {} packets",
+                serial_name,
+                packet_counters.dropped()
+            ),
+        );
+    }
+    Ok(())
 }
 
-fn thread_should_stop(rx: &Receiver<String>) -> bool {
-    let mut result = false;
-    loop {
-        match rx.try_recv() {
-            Ok(msg) => {
-                if msg.eq("thread-stop") {
-                    result = true;
-                }
+// Follows the L2CAP traffic of the connections (decrypted first): the signaling
+// channel and the credits of the LE credit based channels it opens, the ATT
+// transactions and the pairings on the SMP channel
+struct L2capAnalyzer {
+    reassembler: BleL2capReassembler,
+    channels: L2capChannelTracker,
+    att: AttTransactionTracker,
+    // Pairing Request of each connection, until its Pairing Response
+    pairing_requests: Vec<(u32, SmpPairingFeatures)>,
+}
+
+impl L2capAnalyzer {
+    fn new() -> L2capAnalyzer {
+        L2capAnalyzer {
+            reassembler: BleL2capReassembler::new(),
+            channels: L2capChannelTracker::new(),
+            att: AttTransactionTracker::new(),
+            pairing_requests: Vec::new(),
+        }
+    }
+
+    // Sets the ATT record of the packet, returns the L2CAP frame it completes, if any
+    fn process(&mut self, serial_name: &str, packet: &mut BlePacket) -> Option<BleL2capFrame> {
+        let frame = self.reassembler.process(packet);
+        packet.ll_layer_data.att = self.att.process(packet, frame.as_ref());
+        for request in self.att.expired() {
+            log(
+                LogLevel::Warning,
+                &format!(
+                    "Sniffer {}, connection 0x{:08X} {} got no response",
+                    serial_name,
+                    request.access_address,
+                    request.pdu.name()
+                ),
+            );
+        }
+        let frame = frame?;
+        let violations_before = self.credit_violations(&frame);
+        match self.channels.process(&frame) {
+            Some(L2capChannelEvent::Signaling(packet)) => {
+                let level = match packet.command {
+                    L2capSignalingCommand::CommandRejectRsp { .. } => LogLevel::Warning,
+                    _ => LogLevel::Debug,
+                };
+                log(
+                    level,
+                    &format!(
+                        "Sniffer {}, connection 0x{:08X} L2CAP {:?}",
+                        serial_name, frame.access_address, packet.command
+                    ),
+                );
             }
-            Err(_) => {
-                break;
+            Some(L2capChannelEvent::Sdu(sdu)) => {
+                log(
+                    LogLevel::Debug,
+                    &format!(
+                        "Sniffer {}, connection 0x{:08X} channel 0x{:04X} (SPSM 0x{:04X}) SDU of {} bytes",
+                        serial_name,
+                        sdu.access_address,
+                        sdu.cid,
+                        sdu.spsm,
+                        sdu.payload.len()
+                    ),
+                );
+            }
+            Some(L2capChannelEvent::Smp(pdu)) => {
+                let (level, message) = self.smp_message(serial_name, &frame, &pdu);
+                log(level, &message);
             }
+            None => {}
+        }
+        let violations = self.credit_violations(&frame);
+        if violations > violations_before {
+            log(
+                LogLevel::Warning,
+                &format!(
+                    "Sniffer {}, connection 0x{:08X} channel 0x{:04X} got a K-frame without credits",
+                    serial_name, frame.access_address, frame.channel_id
+                ),
+            );
         }
+        Some(frame)
     }
-    result
-}
 
-#[allow(unused)]
-pub fn get_packet_bytes(bytes: Vec<u8>) -> (Vec<Vec<u8>>, usize) {
-    let mut packet_list: Vec<Vec<u8>> = Vec::new();
-    let mut packet_bytes: Vec<u8> = Vec::new();
-    let mut packet_start = false;
-    let mut previous_byte_is_esc = false;
-    let mut read_index: usize = 0;
-    for b in bytes {
-        read_index += 1;
-        if !packet_start {
-            packet_start = b == SLIP_START;
-            continue;
-        }
-        if b == SLIP_END {
-            packet_start = false;
-            packet_list.push(packet_bytes);
-            packet_bytes = Vec::new();
-        } else if b == SLIP_ESC {
-            previous_byte_is_esc = true;
-        } else if previous_byte_is_esc {
-            if b == SLIP_ESC_START {
-                packet_bytes.push(SLIP_START);
-            } else if b == SLIP_ESC_END {
-                packet_bytes.push(SLIP_END);
-            } else if b == SLIP_ESC_ESC {
-                packet_bytes.push(SLIP_ESC);
-            } else {
-                packet_bytes.push(b);
+    // The pairing method is told once both Pairing Request and Response are seen
+    fn smp_message(
+        &mut self,
+        serial_name: &str,
+        frame: &BleL2capFrame,
+        pdu: &SmpPdu,
+    ) -> (LogLevel, String) {
+        let access_address = frame.access_address;
+        let (level, details) = match pdu {
+            SmpPdu::PairingRequest(request) => {
+                self.pairing_requests
+                    .retain(|(aa, _)| *aa != access_address);
+                self.pairing_requests.push((access_address, *request));
+                (LogLevel::Info, request.summary())
+            }
+            SmpPdu::PairingResponse(response) => {
+                let index = self
+                    .pairing_requests
+                    .iter()
+                    .position(|(aa, _)| *aa == access_address);
+                let mut details = response.summary();
+                if let Some(index) = index {
+                    let (_, request) = self.pairing_requests.remove(index);
+                    let secure_connections =
+                        request.auth_req.secure_connections && response.auth_req.secure_connections;
+                    details = format!(
+                        "{}, {} {} pairing",
+                        details,
+                        if secure_connections {
+                            "LE Secure Connections"
+                        } else {
+                            "legacy"
+                        },
+                        smp_pairing_method(&request, response).name()
+                    );
+                }
+                (LogLevel::Info, details)
             }
-            previous_byte_is_esc = false;
+            SmpPdu::PairingFailed { reason } => (
+                LogLevel::Warning,
+                smp_failed_reason_name(*reason).to_string(),
+            ),
+            SmpPdu::SecurityRequest { auth_req } => (
+                LogLevel::Info,
+                format!("AuthReq {}", auth_req.names().join(" ")),
+            ),
+            _ => (LogLevel::Debug, String::new()),
+        };
+        let sender = if frame.direction_to_slave {
+            "central"
         } else {
-            packet_bytes.push(b);
+            "peripheral"
+        };
+        let mut message = format!(
+            "Sniffer {}, connection 0x{:08X} {} from the {}",
+            serial_name,
+            access_address,
+            pdu.name(),
+            sender
+        );
+        if !details.is_empty() {
+            message = format!("{}: {}", message, details);
         }
+        (level, message)
+    }
+
+    // K-frames the peer sent on the channel of frame without credits
+    fn credit_violations(&self, frame: &BleL2capFrame) -> u32 {
+        self.channels
+            .channels()
+            .iter()
+            .find(|channel| {
+                channel.access_address == frame.access_address
+                    && channel.direction_to_slave == frame.direction_to_slave
+                    && channel.cid == frame.channel_id
+            })
+            .map_or(0, |channel| channel.credit_violations)
+    }
+}
+
+// The STK found is tried on the encryption started right after the pairing (even
+// when found after LL_ENC_RSP), the LTK distributed then on the next connections
+fn add_cracked_keys(
+    serial_name: &str,
+    worker: &LegacyPairingWorker,
+    link_decryptor: &mut LinkDecryptor,
+) {
+    for keys in worker.keys() {
+        link_decryptor.add_legacy_pairing_keys(&keys);
+        if keys.long_term_key.is_none() {
+            log(
+                LogLevel::Info,
+                &format!(
+                    "Sniffer {}, connection 0x{:08X} paired with passkey {:06}",
+                    serial_name, keys.access_address, keys.passkey
+                ),
+            );
+        }
+    }
+}
+
+// Applies the controls received to config (and the keys to link_decryptor), returns
+// whether the thread should stop and the commands to send to the firmware
+fn receive_controls(
+    rx: &Receiver<SnifferControl>,
+    config: &mut SnifferConfig,
+    link_decryptor: &mut LinkDecryptor,
+) -> (bool, Vec<SnifferCommand>) {
+    let mut stop = false;
+    let mut commands = Vec::new();
+    while let Ok(control) = rx.try_recv() {
+        match control {
+            SnifferControl::Stop => stop = true,
+            SnifferControl::GoIdle => config.mode = SnifferMode::Idle,
+            SnifferControl::Scan => config.mode = SnifferMode::Scan,
+            SnifferControl::Follow(target) => config.mode = SnifferMode::Follow(target),
+            SnifferControl::SetScanOptions(scan_options) => {
+                config.scan_options = scan_options;
+                // Only sent again while scanning
+                if config.mode != SnifferMode::Scan {
+                    continue;
+                }
+            }
+            SnifferControl::SetKeys(keys) => {
+                commands.append(&mut SnifferCommand::from_keys(&keys));
+                for key in &keys {
+                    link_decryptor.add_sniffer_key(key);
+                }
+                config.keys = keys;
+                continue;
+            }
+            SnifferControl::SetAdvHopSequence(channels) => {
+                commands.push(SnifferCommand::SetAdvChannelHopSequence(channels.clone()));
+                config.adv_channel_hop_sequence = channels;
+                continue;
+            }
+        }
+        if !stop {
+            commands.push(SnifferCommand::from_mode(config));
+        }
+    }
+    (stop, commands)
+}
+
+fn write_to_transport(transport: &mut dyn SnifferTransport, serial_name: &str, send_bytes: &[u8]) {
+    if let Err(error) = transport.write(send_bytes) {
+        log(
+            LogLevel::Error,
+            &format!("Failed to send bytes to sniffer {}, {}", serial_name, error),
+        );
     }
-    (packet_list, read_index)
 }
 
 #[allow(unused)]
@@ -639,62 +1599,728 @@ fn print_hex_bytes(bytes: &Vec<u8>) {
     println!("\n");
 }
 
-fn add_bytes_to_slip(packet_bytes: &mut Vec<u8>, content_byte: u8) {
-    if content_byte == SLIP_START || content_byte == SLIP_END || content_byte == SLIP_ESC {
-        packet_bytes.push(SLIP_ESC);
-        packet_bytes.push(content_byte + 1);
-    } else {
-        packet_bytes.push(content_byte);
-    }
+// Host commands always use the protocol version 1 header
+pub fn make_send_bytes(id: u8, payload: &[u8], packet_counter: u16) -> Vec<u8> {
+    let mut packet_bytes: Vec<u8> = vec![HEADER_LENGTH, payload.len() as u8, PROTOVER_V1];
+    packet_bytes.extend_from_slice(&packet_counter.to_le_bytes());
+    packet_bytes.push(id);
+    packet_bytes.extend_from_slice(payload);
+    slip_encode(&packet_bytes)
 }
 
-fn make_send_bytes(id: u8, payload: &[u8], packet_counter: u16) -> Vec<u8> {
-    let mut packet_bytes: Vec<u8> = Vec::new();
-    packet_bytes.push(SLIP_START);
-    add_bytes_to_slip(&mut packet_bytes, HEADER_LENGTH);
-    add_bytes_to_slip(&mut packet_bytes, payload.len() as u8);
-    add_bytes_to_slip(&mut packet_bytes, PROTOVER_V1);
-    add_bytes_to_slip(&mut packet_bytes, (packet_counter & 0xff) as u8);
-    add_bytes_to_slip(&mut packet_bytes, (packet_counter >> 8) as u8);
-    add_bytes_to_slip(&mut packet_bytes, id);
-    for payload_byte in payload {
-        add_bytes_to_slip(&mut packet_bytes, *payload_byte);
-    }
-    packet_bytes.push(SLIP_END);
-    packet_bytes
+// What the firmware sends, with the header of protocol_version (see UartPacketHeader)
+#[allow(unused)]
+pub fn make_event_bytes(
+    protocol_version: u8,
+    id: u8,
+    payload: &[u8],
+    packet_counter: u16,
+) -> Vec<u8> {
+    slip_encode(&make_event_packet(
+        protocol_version,
+        id,
+        payload,
+        packet_counter,
+    ))
 }
 
-fn make_send_scan_bytes(
-    find_scan_rsp: bool,
-    find_aux: bool,
-    scan_coded: bool,
+// Same without the SLIP framing, as BlePacket::from takes it
+#[allow(unused)]
+pub fn make_event_packet(
+    protocol_version: u8,
+    id: u8,
+    payload: &[u8],
     packet_counter: u16,
 ) -> Vec<u8> {
-    let mut flags: u8 = 0;
-    if find_scan_rsp {
-        flags = 1;
+    let mut packet_bytes: Vec<u8> = if protocol_version == PROTOVER_V1 {
+        vec![HEADER_LENGTH, payload.len() as u8]
+    } else {
+        (payload.len() as u16).to_le_bytes().to_vec()
+    };
+    packet_bytes.push(protocol_version);
+    packet_bytes.extend_from_slice(&packet_counter.to_le_bytes());
+    packet_bytes.push(id);
+    packet_bytes.extend_from_slice(payload);
+    packet_bytes
+}
+
+// Checks a hop sequence such as "37,38,39" or "39"
+#[allow(unused)]
+pub fn parse_adv_hop_seq(text: &str) -> Option<Vec<u8>> {
+    let mut channels = Vec::new();
+    for part in text.split(',') {
+        let channel = part.trim().parse::<u8>().ok()?;
+        if !ADV_CHANNELS.contains(&channel) || channels.contains(&channel) {
+            return None;
+        }
+        channels.push(channel);
     }
-    if find_aux {
-        flags |= 1 << 1;
+    Some(channels)
+}
+
+// Accepts "0011AABB", "0x0011aabb" and "00:11:AA:BB" styles
+#[allow(unused)]
+pub fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .unwrap_or(text);
+    let digits: Vec<char> = text
+        .chars()
+        .filter(|c| *c != ':' && *c != '-' && *c != ' ')
+        .collect();
+    if digits.is_empty() || digits.len() & 1 == 1 {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    for pair in digits.chunks(2) {
+        let high = pair[0].to_digit(16)?;
+        let low = pair[1].to_digit(16)?;
+        bytes.push((high * 16 + low) as u8);
     }
-    if scan_coded {
-        flags |= 1 << 2;
+    Some(bytes)
+}
+
+// Callers must check that bytes[index + 1] exists
+#[allow(unused)]
+pub fn read_u16_le(bytes: &[u8], index: usize) -> u16 {
+    (bytes[index] as u16) | ((bytes[index + 1] as u16) << 8)
+}
+
+// Callers must check that bytes[index + 3] exists
+#[allow(unused)]
+pub fn read_u32_le(bytes: &[u8], index: usize) -> u32 {
+    (read_u16_le(bytes, index) as u32) | ((read_u16_le(bytes, index + 2) as u32) << 16)
+}
+
+// Whether BleLLNonConnIndMsg::from decodes the AD structure (AD type and data)
+// into one of its fields
+fn is_decoded_ad_structure(structure: &[u8]) -> bool {
+    let data = &structure[1..];
+    match structure[0] {
+        0x01 | 0x0a => !data.is_empty(),
+        0x09 => std::str::from_utf8(data).is_ok(),
+        0xff => data.len() >= 2,
+        _ => false,
     }
-    let payload = [flags];
-    make_send_bytes(REQ_SCAN_CONT, &payload, packet_counter)
 }
 
-fn make_send_tk_bytes(tk: u8, packet_counter: u16) -> Vec<u8> {
-    let payload = [tk; 16];
-    make_send_bytes(SET_TEMPORARY_KEY, &payload, packet_counter)
+// Appends an AD structure: length, AD type and data
+fn push_ad_structure(payload: &mut Vec<u8>, ad_type: u8, data: &[u8]) {
+    payload.push(data.len() as u8 + 1);
+    payload.push(ad_type);
+    payload.extend_from_slice(data);
 }
 
-fn mac_bytes_all_zero(mac: &[u8; 6]) -> bool {
-    let mut zero_count = 0;
-    for mac_byte in mac {
-        if *mac_byte == 0 {
-            zero_count += 1;
+// Checks that the needed bytes of a field starting at offset are there
+fn check_length(bytes: &[u8], offset: usize, needed: usize) -> Result<(), ParseError> {
+    if bytes.len() < offset + needed {
+        return Err(ParseError::Truncated { offset, needed });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        att::{AttPdu, ATT_READ_REQ},
+        crypto::{aes_ccm_encrypt, ble_e, ble_s1, expand_key},
+        emulator::EmulatedPacket,
+        l2cap::{
+            L2CAP_CID_ATT, L2CAP_CID_LE_SIGNALING, L2CAP_CID_SMP,
+            L2CAP_LE_CREDIT_BASED_CONNECTION_REQ, L2CAP_LE_CREDIT_BASED_CONNECTION_RSP,
+        },
+        link_encryption::{make_nonce, LL_START_ENC_RSP},
+        logger::{clear_log_hook, set_log_hook},
+        transport::MockTransport,
+    };
+
+    // A NONCONN_IND of 11:22:33:44:55:66 with the flags and a complete local name
+    fn nonconn_ind_pdu(name: &[u8]) -> Vec<u8> {
+        let mut pdu = vec![ADV_TYPE_ADV_NONCONN_IND, 0];
+        pdu.extend_from_slice(&[0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        push_ad_structure(&mut pdu, 0x01, &[0x06]);
+        push_ad_structure(&mut pdu, 0x09, name);
+        pdu[1] = (pdu.len() - 2) as u8;
+        pdu
+    }
+
+    fn adv_uart_packet(protocol_version: u8, pdu: &[u8]) -> Vec<u8> {
+        let packet = EmulatedPacket::adv(37, pdu);
+        make_event_packet(protocol_version, packet.packet_id, &packet.payload, 0)
+    }
+
+    // An empty LL data PDU (LLID 1) of the connection 0x50654A9B
+    fn data_uart_packet(protocol_version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut pdu = vec![LLID_DATA_CONTINUATION | 0b0101, payload.len() as u8];
+        pdu.extend_from_slice(payload);
+        let packet = EmulatedPacket::data(12, 0x50654A9B, 0x123456, &pdu);
+        make_event_packet(protocol_version, packet.packet_id, &packet.payload, 7)
+    }
+
+    // Moves a 1M PHY packet to the coded PHY with the coding indicator given
+    fn to_coded_phy(bytes: &[u8], coding_indicator: u8) -> Vec<u8> {
+        let header = UartPacketHeader::from(bytes).unwrap();
+        let header_length = HEADER_LENGTH as usize;
+        let mut payload = bytes[header_length..].to_vec();
+        payload[1] |= PHY_CODED << 4;
+        payload.insert(PACKET_HEADER_LENGTH as usize + 4, coding_indicator);
+        make_event_packet(
+            header.protocol_version,
+            header.packet_id,
+            &payload,
+            header.packet_counter,
+        )
+    }
+
+    fn hex_bytes<const N: usize>(hex: &str) -> [u8; N] {
+        parse_hex_bytes(hex).unwrap().try_into().unwrap()
+    }
+
+    // Runs analyze_transport_packets over a capture replayed by a MockTransport
+    fn replay(input: &[u8]) -> Vec<BlePacket> {
+        replay_with(&SnifferConfig::new(), input)
+    }
+
+    fn replay_with(config: &SnifferConfig, input: &[u8]) -> Vec<BlePacket> {
+        let (tx, packets) = mpsc::channel();
+        let (_control_tx, rx) = mpsc::channel();
+        let transport = MockTransport::replay(input);
+        let result = analyze_transport_packets(
+            "mock",
+            || Ok(Box::new(transport.clone()) as Box<dyn SnifferTransport>),
+            config,
+            tx,
+            &rx,
+        );
+        assert!(result.is_ok());
+        packets.try_iter().collect()
+    }
+
+    #[test]
+    fn log_hook_captures_parse_diagnostics() {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let captured = messages.clone();
+        set_log_hook(move |level, message| {
+            captured.lock().unwrap().push((level, message.to_string()));
+        });
+        let mut pdu = nonconn_ind_pdu(b"");
+        pdu.truncate(7);
+        pdu[1] = 5;
+        let packets = replay(&slip_encode(&adv_uart_packet(PROTOVER_V2, &pdu)));
+        clear_log_hook();
+        assert!(packets.is_empty());
+        let expected = ParseError::BadPayloadLength {
+            pdu_type: ADV_TYPE_ADV_NONCONN_IND,
+            length: 5,
+        };
+        let messages = messages.lock().unwrap();
+        assert!(messages.contains(&(LogLevel::Debug, format!("Sniffer mock, {}", expected))));
+    }
+
+    #[test]
+    fn to_uart_packet_round_trips() {
+        for protocol_version in [PROTOVER_V1, PROTOVER_V2, PROTOVER_V3] {
+            let adv = adv_uart_packet(protocol_version, &nonconn_ind_pdu(b"Sensor"));
+            let data = data_uart_packet(protocol_version, &[0x07, 0x00, 0x04, 0x00, 0x0a]);
+            let coded = to_coded_phy(&adv, PHY_CODED_CI_S2);
+            for bytes in [adv, data, coded] {
+                let packet = BlePacket::from(&bytes).unwrap();
+                assert_eq!(packet.protocol_version, protocol_version);
+                assert_eq!(packet.to_uart_packet(), bytes);
+                assert_eq!(BlePacket::from(&packet.to_uart_packet()).unwrap(), packet);
+            }
+        }
+    }
+
+    #[test]
+    fn rewritten_data_pdu_has_no_crc_until_updated() {
+        let mut packet = BlePacket::from(&data_uart_packet(PROTOVER_V2, &[0x01, 0x02])).unwrap();
+        assert!(packet.ll_layer_data.crc.is_some());
+        packet.ll_layer_data.data_pdu.as_mut().unwrap().payload = vec![0x03, 0x04];
+        let rewritten = BlePacket::from(&packet.to_uart_packet()).unwrap();
+        assert_eq!(rewritten.ll_layer_data.pdu[2..], [0x03, 0x04]);
+        assert_eq!(rewritten.ll_layer_data.crc, None);
+
+        packet.update_crc(0x123456);
+        let updated = BlePacket::from(&packet.to_uart_packet()).unwrap();
+        let crc = ble_crc24(0x123456, &updated.ll_layer_data.pdu);
+        assert_eq!(updated.ll_layer_data.crc, Some(crc));
+    }
+
+    // An advertising PDU of pdu_type whose payload is length bytes of 0x11
+    fn adv_pdu(pdu_type: u8, length: u8) -> Vec<u8> {
+        let mut pdu = vec![pdu_type, length];
+        pdu.resize(2 + length as usize, 0x11);
+        pdu
+    }
+
+    #[test]
+    fn nonconn_ind_length_boundaries() {
+        for (length, accepted) in [(5, false), (6, true), (37, true), (38, false)] {
+            let mut pdu = adv_pdu(ADV_TYPE_ADV_NONCONN_IND, length);
+            if length > 6 {
+                // The advertising data is a single manufacturer specific AD structure
+                pdu[8] = length - 7;
+                pdu[9] = 0xff;
+            }
+            let result = BlePacket::from(&adv_uart_packet(PROTOVER_V2, &pdu));
+            if accepted {
+                let packet = result.unwrap();
+                let non_conn_ind = packet.ll_layer_data.non_conn_ind.unwrap();
+                assert_eq!(non_conn_ind.advertising_mac, BdAddr::new([0x11; 6], false));
+            } else {
+                let error = ParseError::BadPayloadLength {
+                    pdu_type: ADV_TYPE_ADV_NONCONN_IND,
+                    length,
+                };
+                assert_eq!(result.unwrap_err(), error);
+            }
+        }
+    }
+
+    #[test]
+    fn short_scan_req_and_connect_req_are_rejected() {
+        for (pdu_type, expected_length) in [(ADV_TYPE_SCAN_REQ, 12), (ADV_TYPE_CONNECT_REQ, 34)] {
+            for length in 0..expected_length {
+                let pdu = adv_pdu(pdu_type, length);
+                let result = BlePacket::from(&adv_uart_packet(PROTOVER_V2, &pdu));
+                let error = ParseError::BadPayloadLength { pdu_type, length };
+                assert_eq!(result.unwrap_err(), error);
+            }
+            // The length is right but the UART packet ends before the payload does
+            let mut pdu = adv_pdu(pdu_type, expected_length);
+            pdu.truncate(8);
+            let result = BlePacket::from(&adv_uart_packet(PROTOVER_V2, &pdu));
+            assert!(matches!(result, Err(ParseError::Truncated { .. })));
+        }
+    }
+
+    #[test]
+    fn parses_adv_hop_sequences() {
+        assert_eq!(parse_adv_hop_seq("37,38,39"), Some(vec![37, 38, 39]));
+        assert_eq!(parse_adv_hop_seq(" 39 , 37"), Some(vec![39, 37]));
+        assert_eq!(parse_adv_hop_seq("38"), Some(vec![38]));
+        for text in [
+            "",
+            "38,",
+            "37,37",
+            "38,39,38",
+            "40",
+            "36",
+            "37,38,39,40",
+            "x",
+        ] {
+            assert_eq!(parse_adv_hop_seq(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn controls_update_the_config_and_give_commands() {
+        let (control_tx, rx) = mpsc::channel();
+        let mut config = SnifferConfig::new();
+        let mut link_decryptor = LinkDecryptor::new();
+        let mut receive = |control| {
+            control_tx.send(control).unwrap();
+            receive_controls(&rx, &mut config, &mut link_decryptor)
+        };
+
+        assert_eq!(
+            receive(SnifferControl::GoIdle),
+            (false, vec![SnifferCommand::GoIdle])
+        );
+        let scan_options = SnifferScanOptions {
+            find_scan_rsp: true,
+            find_aux: true,
+            scan_coded: false,
+        };
+        // Kept for the next scan
+        assert_eq!(
+            receive(SnifferControl::SetScanOptions(scan_options)),
+            (false, vec![])
+        );
+        assert_eq!(
+            receive(SnifferControl::Scan),
+            (false, vec![SnifferCommand::Scan(scan_options)])
+        );
+        let scan_options = SnifferScanOptions::new();
+        assert_eq!(
+            receive(SnifferControl::SetScanOptions(scan_options)),
+            (false, vec![SnifferCommand::Scan(scan_options)])
+        );
+        let target = SnifferFollowTarget {
+            address: BdAddr::new([0x11; 6], false),
+            only_advertisements: true,
+            only_legacy: false,
+            coded: false,
+        };
+        assert_eq!(
+            receive(SnifferControl::Follow(target.clone())),
+            (false, vec![SnifferCommand::Follow(target.clone())])
+        );
+        assert_eq!(
+            receive(SnifferControl::SetAdvHopSequence(vec![39])),
+            (
+                false,
+                vec![SnifferCommand::SetAdvChannelHopSequence(vec![39])]
+            )
+        );
+        let long_term_key: [u8; 16] = hex_bytes("4C68384139F574D836BCF34E9DFB01BF");
+        let keys = vec![SnifferKey::LegacyLongTermKey(long_term_key)];
+        assert_eq!(
+            receive(SnifferControl::SetKeys(keys.clone())),
+            (false, SnifferCommand::from_keys(&keys))
+        );
+        assert_eq!(receive(SnifferControl::Stop), (true, vec![]));
+
+        assert_eq!(config.mode, SnifferMode::Follow(target));
+        assert_eq!(config.scan_options, scan_options);
+        assert_eq!(config.adv_channel_hop_sequence, [39]);
+        assert_eq!(config.keys.len(), 1);
+        let mut reversed = long_term_key;
+        reversed.reverse();
+        assert_eq!(link_decryptor.long_term_keys(), [reversed]);
+    }
+
+    #[test]
+    fn capture_path_verifies_crc() {
+        let packet = adv_uart_packet(PROTOVER_V2, &nonconn_ind_pdu(b"Sensor"));
+        let mut corrupted = packet.clone();
+        // Last byte of the name
+        corrupted[packet.len() - 4] ^= 0x01;
+        let mut input = slip_encode(&packet);
+        input.extend(slip_encode(&corrupted));
+        let checks: Vec<Option<BleCrcCheck>> = replay(&input)
+            .iter()
+            .map(|packet| packet.ll_layer_data.crc_check)
+            .collect();
+        assert_eq!(
+            checks,
+            [Some(BleCrcCheck::Ok), Some(BleCrcCheck::Corrupted)]
+        );
+    }
+
+    // A data PDU of the connection 0x50654A9B sent in the direction given
+    fn link_uart_packet(
+        direction_to_slave: bool,
+        header: u8,
+        payload: &[u8],
+        packet_counter: u16,
+    ) -> Vec<u8> {
+        let mut pdu = vec![header, payload.len() as u8];
+        pdu.extend_from_slice(payload);
+        let mut packet = EmulatedPacket::data(12, 0x50654A9B, 0x123456, &pdu);
+        packet.payload[1] |= (direction_to_slave as u8) << 1;
+        make_event_packet(
+            PROTOVER_V2,
+            packet.packet_id,
+            &packet.payload,
+            packet_counter,
+        )
+    }
+
+    #[test]
+    fn capture_path_decrypts_with_given_long_term_key() {
+        // Encryption start of the Core_v5.4 vol.6 PartC Chapter1 sample data
+        let link = [
+            (
+                true,
+                LLID_CONTROL,
+                "039078563412EFCDAB74241302F1E0DFCEBDAC24ABDCBA",
+            ),
+            (false, LLID_CONTROL, "047968574635241302BEBAAFDE"),
+            (false, LLID_CONTROL | 0b1000, "05"),
+            (true, LLID_CONTROL | 0b1000, "9FCDA7F448"),
+            (false, LLID_CONTROL, "A34C13A415"),
+        ];
+        let mut input = Vec::new();
+        for (index, (direction_to_slave, header, payload)) in link.iter().enumerate() {
+            let payload = parse_hex_bytes(payload).unwrap();
+            let packet = link_uart_packet(*direction_to_slave, *header, &payload, index as u16);
+            input.extend(slip_encode(&packet));
+        }
+        let mut config = SnifferConfig::new();
+        let long_term_key = hex_bytes("4C68384139F574D836BCF34E9DFB01BF");
+        config.keys = vec![SnifferKey::LegacyLongTermKey(long_term_key)];
+        let packets = replay_with(&config, &input);
+        assert_eq!(packets.len(), link.len());
+        for (packet, (_, _, encrypted)) in packets[3..].iter().zip(&link[3..]) {
+            let data_pdu = packet.ll_layer_data.data_pdu.as_ref().unwrap();
+            assert_eq!(data_pdu.payload, [0x06]);
+            assert!(packet.packet_header.data_header.as_ref().unwrap().mic_ok);
+            // The captured PDU is kept
+            let encrypted = parse_hex_bytes(encrypted).unwrap();
+            assert_eq!(packet.ll_layer_data.pdu[2..], encrypted);
+        }
+
+        // Without the key they are reported as captured
+        let packets = replay(&input);
+        let data_pdu = packets[3].ll_layer_data.data_pdu.as_ref().unwrap();
+        assert_eq!(data_pdu.payload, [0x9F, 0xCD, 0xA7, 0xF4, 0x48]);
+    }
+
+    #[test]
+    fn l2cap_analyzer_tracks_signaled_channels() {
+        let signaling = |code: u8, fields: &[u16]| {
+            let mut command = vec![code, 1];
+            command.extend_from_slice(&(fields.len() as u16 * 2).to_le_bytes());
+            for field in fields {
+                command.extend_from_slice(&field.to_le_bytes());
+            }
+            (L2CAP_CID_LE_SIGNALING, command)
+        };
+        let mut analyzer = L2capAnalyzer::new();
+        let mut violations = Vec::new();
+        for (index, (direction_to_slave, (channel_id, payload))) in [
+            (
+                true,
+                signaling(
+                    L2CAP_LE_CREDIT_BASED_CONNECTION_REQ,
+                    &[0x0080, 0x0040, 64, 64, 4],
+                ),
+            ),
+            (
+                false,
+                signaling(
+                    L2CAP_LE_CREDIT_BASED_CONNECTION_RSP,
+                    &[0x0041, 64, 64, 1, 0],
+                ),
+            ),
+            // An SDU of 2 bytes, then a K-frame the peripheral gave no credit for
+            (true, (0x0041, vec![2, 0, 0xAA, 0xBB])),
+            (true, (0x0041, vec![1, 0, 0xCC])),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut l2cap = (payload.len() as u16).to_le_bytes().to_vec();
+            l2cap.extend_from_slice(&channel_id.to_le_bytes());
+            l2cap.extend_from_slice(&payload);
+            let packet =
+                link_uart_packet(direction_to_slave, LLID_DATA_START, &l2cap, index as u16);
+            let mut packet = BlePacket::from(&packet).unwrap();
+            let frame = analyzer.process("mock", &mut packet).unwrap();
+            violations.push(analyzer.credit_violations(&frame));
+        }
+        assert_eq!(analyzer.channels.channels().len(), 2);
+        assert_eq!(violations, [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn capture_path_pairs_att_responses_with_their_requests() {
+        let mut input = Vec::new();
+        for (index, (direction_to_slave, att)) in [
+            (true, vec![ATT_READ_REQ, 0x03, 0x00]),
+            (false, vec![0x0B, 0x12, 0x34]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut l2cap = (att.len() as u16).to_le_bytes().to_vec();
+            l2cap.extend_from_slice(&L2CAP_CID_ATT.to_le_bytes());
+            l2cap.extend_from_slice(&att);
+            input.extend(slip_encode(&link_uart_packet(
+                direction_to_slave,
+                LLID_DATA_START,
+                &l2cap,
+                index as u16,
+            )));
+        }
+        // An empty PDU completes no frame
+        input.extend(slip_encode(&link_uart_packet(
+            true,
+            LLID_DATA_CONTINUATION,
+            &[],
+            2,
+        )));
+        let packets = replay(&input);
+        assert_eq!(packets.len(), 3);
+        let request = packets[0].ll_layer_data.att.as_ref().unwrap();
+        assert_eq!(request.pdu, AttPdu::ReadReq { handle: 0x0003 });
+        assert!(request.request.is_none());
+        let response = packets[1].ll_layer_data.att.as_ref().unwrap();
+        assert_eq!(
+            response.pdu,
+            AttPdu::ReadRsp {
+                value: vec![0x12, 0x34]
+            }
+        );
+        assert_eq!(response.request, Some(request.pdu.clone()));
+        assert!(response.latency_us.is_some());
+        assert!(packets[2].ll_layer_data.att.is_none());
+    }
+
+    #[test]
+    fn l2cap_analyzer_describes_pairings() {
+        let mut analyzer = L2capAnalyzer::new();
+        let mut smp_message = |direction_to_slave: bool, smp: &str| {
+            let frame = BleL2capFrame {
+                access_address: 0x50654A9B,
+                direction_to_slave,
+                channel_id: L2CAP_CID_SMP,
+                payload: parse_hex_bytes(smp).unwrap(),
+            };
+            let pdu = SmpPdu::from(&frame.payload).unwrap();
+            analyzer.smp_message("mock", &frame, &pdu)
+        };
+        assert_eq!(
+            smp_message(true, "01040005100707"),
+            (
+                LogLevel::Info,
+                "Sniffer mock, connection 0x50654A9B Pairing Request from the central: \
+                 KeyboardDisplay, OOB no, AuthReq Bonding MITM, 16 bytes keys, \
+                 initiator keys EncKey IdKey SignKey, responder keys EncKey IdKey SignKey"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            smp_message(false, "02000001100101"),
+            (
+                LogLevel::Info,
+                "Sniffer mock, connection 0x50654A9B Pairing Response from the peripheral: \
+                 DisplayOnly, OOB no, AuthReq Bonding, 16 bytes keys, initiator keys EncKey, \
+                 responder keys EncKey, legacy Passkey Entry pairing"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            smp_message(false, "0504"),
+            (
+                LogLevel::Warning,
+                "Sniffer mock, connection 0x50654A9B Pairing Failed from the peripheral: \
+                 Confirm Value Failed"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn capture_path_cracks_legacy_pairing() {
+        // CONNECT_IND and Just Works pairing of the Core_v5.4 vol.3 PartH 2.2.3 sample
+        let mut connect_ind = vec![ADV_TYPE_CONNECT_REQ | 0x40, 34];
+        connect_ind.extend_from_slice(&[0xA6, 0xA5, 0xA4, 0xA3, 0xA2, 0xA1]);
+        connect_ind.extend_from_slice(&[0xB6, 0xB5, 0xB4, 0xB3, 0xB2, 0xB1]);
+        connect_ind.extend_from_slice(&0x50654A9Bu32.to_le_bytes());
+        connect_ind.extend_from_slice(&[0x56, 0x34, 0x12]);
+        connect_ind.resize(2 + 34, 0);
+        let mut input = slip_encode(&adv_uart_packet(PROTOVER_V2, &connect_ind));
+        let initiator_random = "E02E70C64E2788630E6FAD5621D58357";
+        let responder_random = "5A5A5A5A5A5A5A5A5A5A5A5A5A5A5A5A";
+        let mut link = Vec::new();
+        for (direction_to_slave, smp) in [
+            (true, "01010000100707".to_string()),
+            (false, "02030000080005".to_string()),
+            // Mconfirm and Mrand, the peripheral's confirm isn't needed
+            (true, "03863BF1BEC54DA7D2EA888987EF3F1E1E".to_string()),
+            (true, format!("04{}", initiator_random)),
+            (false, format!("04{}", responder_random)),
+        ] {
+            let smp = parse_hex_bytes(&smp).unwrap();
+            let mut payload = (smp.len() as u16).to_le_bytes().to_vec();
+            payload.extend_from_slice(&L2CAP_CID_SMP.to_le_bytes());
+            payload.extend_from_slice(&smp);
+            link.push((direction_to_slave, LLID_DATA_START, payload));
+        }
+        // Encryption start with the STK, SKD and IV of the vol.6 PartC sample
+        for (direction_to_slave, header, payload) in [
+            (
+                true,
+                LLID_CONTROL,
+                "039078563412EFCDAB74241302F1E0DFCEBDAC24ABDCBA",
+            ),
+            (false, LLID_CONTROL, "047968574635241302BEBAAFDE"),
+            (false, LLID_CONTROL | 0b1000, "05"),
+        ] {
+            link.push((
+                direction_to_slave,
+                header,
+                parse_hex_bytes(payload).unwrap(),
+            ));
+        }
+        // LL_START_ENC_RSP of the central encrypted with the STK of the pairing
+        let short_term_key = ble_s1(
+            &[0; 16],
+            &hex_bytes(responder_random),
+            &hex_bytes(initiator_random),
+        );
+        let skd = hex_bytes("1302F1E0DFCEBDAC7968574635241302");
+        let session_key = ble_e(&short_term_key, &skd);
+        let encrypted = aes_ccm_encrypt(
+            &expand_key(&session_key),
+            &make_nonce(0, true, &hex_bytes("24ABDCBABEBAAFDE")),
+            &[LLID_CONTROL],
+            &[0x06],
+        );
+        for (index, (direction_to_slave, header, payload)) in link.iter().enumerate() {
+            let packet = link_uart_packet(*direction_to_slave, *header, payload, index as u16 + 1);
+            input.extend(slip_encode(&packet));
+        }
+        let encrypted_input = slip_encode(&link_uart_packet(
+            true,
+            LLID_CONTROL | 0b1000,
+            &encrypted,
+            link.len() as u16 + 1,
+        ));
+        let last_payload = |packets: &[BlePacket]| {
+            let data_pdu = packets.last()?.ll_layer_data.data_pdu.as_ref()?;
+            Some(data_pdu.payload.clone())
+        };
+        let mut replayed = input.clone();
+        replayed.extend_from_slice(&encrypted_input);
+        assert_eq!(
+            last_payload(&replay_with(&SnifferConfig::new(), &replayed)),
+            Some(encrypted)
+        );
+
+        // The passkey is cracked on another thread, let it catch up before the
+        // encrypted packet
+        let transport = MockTransport::new();
+        let sniffer = transport.clone();
+        let (tx, packets) = mpsc::channel();
+        let (_control_tx, rx) = mpsc::channel();
+        let mut config = SnifferConfig::new();
+        config.crack_legacy_pairing = true;
+        let handle = thread::spawn(move || {
+            analyze_transport_packets(
+                "mock",
+                || Ok(Box::new(sniffer.clone()) as Box<dyn SnifferTransport>),
+                &config,
+                tx,
+                &rx,
+            )
+        });
+        // Bytes sent during the handshake would be taken for its responses
+        let mut decoder = SlipDecoder::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut scanning = false;
+        while !scanning && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            scanning = decoder
+                .push(&transport.take_output())
+                .into_iter()
+                .flatten()
+                .any(|packet| {
+                    UartPacketHeader::from(&packet)
+                        .is_ok_and(|header| header.packet_id == REQ_SCAN_CONT)
+                });
+        }
+        assert!(scanning);
+        transport.push_input(&input);
+        for _ in 0..=link.len() {
+            assert!(packets.recv_timeout(Duration::from_secs(10)).is_ok());
         }
+        thread::sleep(Duration::from_millis(200));
+        transport.push_input(&encrypted_input);
+        transport.close();
+        assert!(handle.join().unwrap().is_ok());
+        let packets: Vec<BlePacket> = packets.try_iter().collect();
+        assert_eq!(last_payload(&packets), Some(vec![LL_START_ENC_RSP]));
     }
-    zero_count == 6
 }
@@ -0,0 +1,88 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ble_sniffer::{BlePacket, SnifferConfig, ADV_CHANNELS, EVENT_PACKET_ADV_PDU};
+
+// What the sniffer was told to do during a capture, needed to read the
+// statistics right: a channel missing from the hop sequence was never listened to.
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct CaptureMetadata {
+    pub started: SystemTime,
+    pub baud_rate: u32,
+    pub adv_channel_hop_sequence: Vec<u8>,
+    // Advertising packets received on channel 37, 38 and 39
+    pub adv_channel_packets: [u64; 3],
+    // Packets the firmware sent but never got here, see BlePacket::dropped_before
+    pub dropped_packets: u64,
+}
+
+#[allow(unused)]
+impl CaptureMetadata {
+    pub fn new(config: &SnifferConfig) -> CaptureMetadata {
+        CaptureMetadata {
+            started: SystemTime::now(),
+            baud_rate: config.baud_rate,
+            adv_channel_hop_sequence: config.adv_channel_hop_sequence.clone(),
+            adv_channel_packets: [0; 3],
+            dropped_packets: 0,
+        }
+    }
+
+    pub fn record(&mut self, packet: &BlePacket) {
+        self.dropped_packets += packet.dropped_before as u64;
+        if packet.packet_id != EVENT_PACKET_ADV_PDU {
+            return;
+        }
+        if let Some(index) = ADV_CHANNELS
+            .iter()
+            .position(|channel| *channel == packet.packet_header.channel_index)
+        {
+            self.adv_channel_packets[index] += 1;
+        }
+    }
+
+    // Share of the advertising packets received on the channel, None when the
+    // channel isn't part of the hop sequence
+    pub fn adv_channel_share(&self, channel: u8) -> Option<f64> {
+        if !self.adv_channel_hop_sequence.contains(&channel) {
+            return None;
+        }
+        let index = ADV_CHANNELS.iter().position(|c| *c == channel)?;
+        let total: u64 = self.adv_channel_packets.iter().sum();
+        if total == 0 {
+            return Some(0.0);
+        }
+        Some(self.adv_channel_packets[index] as f64 / total as f64)
+    }
+
+    pub fn to_json(&self) -> String {
+        let started = match self.started.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+        let hop_sequence: Vec<String> = self
+            .adv_channel_hop_sequence
+            .iter()
+            .map(|channel| channel.to_string())
+            .collect();
+        let mut channels = Vec::new();
+        for (index, channel) in ADV_CHANNELS.iter().enumerate() {
+            let share = match self.adv_channel_share(*channel) {
+                Some(share) => format!("{:.3}", share),
+                None => String::from("null"),
+            };
+            channels.push(format!(
+                "{{\"channel\":{},\"packets\":{},\"share\":{}}}",
+                channel, self.adv_channel_packets[index], share
+            ));
+        }
+        format!(
+            "{{\"started\":{},\"baud_rate\":{},\"adv_channel_hop_sequence\":[{}],\"adv_channels\":[{}],\"dropped_packets\":{}}}",
+            started,
+            self.baud_rate,
+            hop_sequence.join(","),
+            channels.join(","),
+            self.dropped_packets
+        )
+    }
+}
@@ -0,0 +1,239 @@
+use crate::ble_sniffer::{
+    BlePacket, ADV_ACCESS_ADDRESS, ADV_TYPE_CONNECT_REQ, EVENT_PACKET_DATA_PDU,
+};
+
+// CRC initial value of advertising physical channel PDUs
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartB 3.1.1
+#[allow(unused)]
+pub const BLE_ADV_CRC_INIT: u32 = 0x555555;
+
+// x^24 + x^10 + x^9 + x^6 + x^4 + x^3 + x + 1, bit reversed for the LSB first shift
+const BLE_CRC_POLY_REVERSED: u32 = 0x5A6000;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BleCrcCheck {
+    Ok,
+    // No CRC or no CRCInit known for the connection
+    Unknown,
+    // The CRC matches no known CRCInit, the bits got flipped on air
+    Corrupted,
+    // The CRC matches another access address' CRCInit (or the advertising one),
+    // the packet was attributed to the wrong connection
+    Misattributed { access_address: u32 },
+}
+
+// Computes the CRC of a PDU (header, length and payload) and returns its 3 bytes
+// in the order they are sent on air
+#[allow(unused)]
+pub fn ble_crc24(crc_init: u32, pdu: &[u8]) -> [u8; 3] {
+    // Position 0 of the shift register holds the least significant bit of CRCInit,
+    // position 0 is bit 23 here
+    let mut state = crc_init.reverse_bits() >> 8;
+    for b in pdu {
+        let mut byte = *b;
+        for _ in 0..8 {
+            let next_bit = (state ^ byte as u32) & 1;
+            byte >>= 1;
+            state >>= 1;
+            if next_bit == 1 {
+                state |= 1 << 23;
+                state ^= BLE_CRC_POLY_REVERSED;
+            }
+        }
+    }
+    // Position 23 (bit 0 here) is sent first, bytes go least significant bit first
+    let bytes = state.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+// Recomputes the CRC of every packet instead of trusting crc_ok.
+// CONNECT_IND packets give the CRCInit of data channel PDUs.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct BleCrcVerifier {
+    // Access address and CRCInit of every connection seen
+    crc_inits: Vec<(u32, u32)>,
+}
+
+#[allow(unused)]
+impl BleCrcVerifier {
+    pub fn new() -> BleCrcVerifier {
+        BleCrcVerifier {
+            crc_inits: Vec::new(),
+        }
+    }
+
+    // For connections whose CONNECT_IND was missed
+    pub fn add_connection(&mut self, access_address: u32, crc_init: u32) {
+        self.crc_inits.retain(|(aa, _)| *aa != access_address);
+        self.crc_inits.push((access_address, crc_init & 0xFFFFFF));
+    }
+
+    pub fn process(&mut self, packet: &BlePacket) -> BleCrcCheck {
+        let crc = match packet.ll_layer_data.crc {
+            Some(crc) if !packet.ll_layer_data.pdu.is_empty() => crc,
+            _ => return BleCrcCheck::Unknown,
+        };
+        let pdu = &packet.ll_layer_data.pdu;
+        let crc_init = if packet.packet_id == EVENT_PACKET_DATA_PDU {
+            self.crc_inits
+                .iter()
+                .find(|(aa, _)| *aa == packet.ll_layer_data.access_address)
+                .map(|(_, crc_init)| *crc_init)
+        } else {
+            Some(BLE_ADV_CRC_INIT)
+        };
+        if crc_init.is_some_and(|crc_init| ble_crc24(crc_init, pdu) == crc) {
+            // Only trust CONNECT_IND whose CRC is right
+            if packet.ll_layer_data.pdu_type == ADV_TYPE_CONNECT_REQ {
+                if let Some(connect_ind) = &packet.ll_layer_data.connect_ind {
+                    self.add_connection(connect_ind.access_address, connect_ind.crc_init);
+                }
+            }
+            return BleCrcCheck::Ok;
+        }
+        let misattributed_to = if packet.packet_id == EVENT_PACKET_DATA_PDU
+            && ble_crc24(BLE_ADV_CRC_INIT, pdu) == crc
+        {
+            Some(ADV_ACCESS_ADDRESS)
+        } else {
+            self.crc_inits
+                .iter()
+                .find(|(aa, crc_init)| {
+                    *aa != packet.ll_layer_data.access_address && ble_crc24(*crc_init, pdu) == crc
+                })
+                .map(|(aa, _)| *aa)
+        };
+        match (misattributed_to, crc_init) {
+            (Some(access_address), _) => BleCrcCheck::Misattributed { access_address },
+            (None, Some(_)) => BleCrcCheck::Corrupted,
+            (None, None) => BleCrcCheck::Unknown,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.crc_inits.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ble_sniffer::{make_event_packet, PROTOVER_V2},
+        emulator::EmulatedPacket,
+    };
+
+    const ACCESS_ADDRESS: u32 = 0x50654A9B;
+    const CRC_INIT: u32 = 0x123456;
+    const OTHER_ACCESS_ADDRESS: u32 = 0x71764129;
+    const OTHER_CRC_INIT: u32 = 0xABCDEF;
+
+    fn decode(packet: EmulatedPacket) -> BlePacket {
+        let bytes = make_event_packet(PROTOVER_V2, packet.packet_id, &packet.payload, 0);
+        BlePacket::from(&bytes).unwrap()
+    }
+
+    // NONCONN_IND of C0:11:22:33:44:55 with the flags and Nordic manufacturer data
+    fn nonconn_ind_pdu() -> Vec<u8> {
+        let mut pdu = vec![0x42, 0x10, 0x55, 0x44, 0x33, 0x22, 0x11, 0xc0];
+        pdu.extend_from_slice(&[0x02, 0x01, 0x06, 0x06, 0xff, 0x59, 0x00, 0x01, 0x02, 0x03]);
+        pdu
+    }
+
+    // CONNECT_IND opening ACCESS_ADDRESS with CRC_INIT
+    fn connect_ind_pdu() -> Vec<u8> {
+        let mut pdu = vec![ADV_TYPE_CONNECT_REQ, 34];
+        pdu.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        pdu.extend_from_slice(&[0x55, 0x44, 0x33, 0x22, 0x11, 0xc0]);
+        pdu.extend_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+        pdu.extend_from_slice(&CRC_INIT.to_le_bytes()[..3]);
+        pdu.extend_from_slice(&[0x03, 0x0a, 0x00, 0x18, 0x00, 0x00, 0x00, 0x48, 0x00]);
+        pdu.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f, 0x2b]);
+        pdu
+    }
+
+    // CRC-24/BLE of the CRC catalogue, check value 0xC25A56 sent least significant byte first
+    #[test]
+    fn crc24_check_value() {
+        assert_eq!(
+            ble_crc24(BLE_ADV_CRC_INIT, b"123456789"),
+            [0x56, 0x5a, 0xc2]
+        );
+    }
+
+    // CRC worked out bit by bit with the LFSR of Core v5.4 vol.6 PartB 3.1.1
+    #[test]
+    fn crc24_of_advertising_pdu() {
+        let crc = ble_crc24(BLE_ADV_CRC_INIT, &nonconn_ind_pdu());
+        assert_eq!(crc, [0xc6, 0xa1, 0x5f]);
+        assert_eq!(ble_crc24(CRC_INIT, &[0x01, 0x00]), [0x48, 0xdc, 0x8a]);
+    }
+
+    #[test]
+    fn verifies_advertising_crc() {
+        let mut verifier = BleCrcVerifier::new();
+        let mut packet = decode(EmulatedPacket::adv(37, &nonconn_ind_pdu()));
+        assert_eq!(packet.ll_layer_data.crc, Some([0xc6, 0xa1, 0x5f]));
+        assert_eq!(verifier.process(&packet), BleCrcCheck::Ok);
+
+        packet.ll_layer_data.pdu[5] ^= 0x10;
+        assert_eq!(verifier.process(&packet), BleCrcCheck::Corrupted);
+        packet.ll_layer_data.crc = None;
+        assert_eq!(verifier.process(&packet), BleCrcCheck::Unknown);
+    }
+
+    #[test]
+    fn learns_crc_init_from_connect_ind() {
+        let mut verifier = BleCrcVerifier::new();
+        let empty_pdu = [0x01, 0x00];
+        let data = decode(EmulatedPacket::data(
+            5,
+            ACCESS_ADDRESS,
+            CRC_INIT,
+            &empty_pdu,
+        ));
+        assert_eq!(verifier.process(&data), BleCrcCheck::Unknown);
+
+        let connect_ind = decode(EmulatedPacket::adv(37, &connect_ind_pdu()));
+        assert_eq!(verifier.process(&connect_ind), BleCrcCheck::Ok);
+        assert_eq!(verifier.process(&data), BleCrcCheck::Ok);
+
+        let mut corrupted = data;
+        corrupted.ll_layer_data.pdu[0] ^= 0x04;
+        assert_eq!(verifier.process(&corrupted), BleCrcCheck::Corrupted);
+    }
+
+    #[test]
+    fn detects_misattributed_packets() {
+        let mut verifier = BleCrcVerifier::new();
+        verifier.add_connection(ACCESS_ADDRESS, CRC_INIT);
+        verifier.add_connection(OTHER_ACCESS_ADDRESS, OTHER_CRC_INIT);
+        let empty_pdu = [0x01, 0x00];
+
+        // Reported on ACCESS_ADDRESS, CRC of the other connection
+        let packet = decode(EmulatedPacket::data(
+            5,
+            ACCESS_ADDRESS,
+            OTHER_CRC_INIT,
+            &empty_pdu,
+        ));
+        let expected = BleCrcCheck::Misattributed {
+            access_address: OTHER_ACCESS_ADDRESS,
+        };
+        assert_eq!(verifier.process(&packet), expected);
+
+        // An advertising PDU reported as a data PDU
+        let packet = decode(EmulatedPacket::data(
+            37,
+            ACCESS_ADDRESS,
+            BLE_ADV_CRC_INIT,
+            &empty_pdu,
+        ));
+        let expected = BleCrcCheck::Misattributed {
+            access_address: ADV_ACCESS_ADDRESS,
+        };
+        assert_eq!(verifier.process(&packet), expected);
+    }
+}
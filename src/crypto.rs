@@ -0,0 +1,354 @@
+// AES-128 and the Bluetooth LE security functions built on it.
+// Reference: FIPS-197 & Core v5.4 vol.3 PartH Chapter2.2
+//
+// Aes128 works on blocks in the byte order of FIPS-197 (most significant byte
+// first). The Bluetooth functions below take and return little endian arrays,
+// the byte order used on air, and do the swapping themselves.
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+#[allow(unused)]
+impl Aes128 {
+    pub fn new(key: &[u8; 16]) -> Aes128 {
+        let mut round_keys = [[0; 16]; 11];
+        round_keys[0] = *key;
+        for round in 1..11 {
+            let previous = round_keys[round - 1];
+            let mut word = [previous[13], previous[14], previous[15], previous[12]];
+            for b in word.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            word[0] ^= RCON[round - 1];
+            let mut round_key = [0; 16];
+            for column in 0..4 {
+                for row in 0..4 {
+                    round_key[column * 4 + row] = previous[column * 4 + row] ^ word[row];
+                }
+                word.copy_from_slice(&round_key[column * 4..column * 4 + 4]);
+            }
+            round_keys[round] = round_key;
+        }
+        Aes128 { round_keys }
+    }
+
+    pub fn encrypt(&self, block: &[u8; 16]) -> [u8; 16] {
+        let mut state = *block;
+        add_round_key(&mut state, &self.round_keys[0]);
+        for round in 1..11 {
+            for b in state.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            shift_rows(&mut state);
+            if round != 10 {
+                mix_columns(&mut state);
+            }
+            add_round_key(&mut state, &self.round_keys[round]);
+        }
+        state
+    }
+}
+
+fn add_round_key(state: &mut [u8; 16], round_key: &[u8; 16]) {
+    for (b, k) in state.iter_mut().zip(round_key) {
+        *b ^= *k;
+    }
+}
+
+fn shift_rows(state: &mut [u8; 16]) {
+    let previous = *state;
+    for column in 0..4 {
+        for row in 0..4 {
+            state[column * 4 + row] = previous[((column + row) % 4) * 4 + row];
+        }
+    }
+}
+
+fn xtime(b: u8) -> u8 {
+    if b & 0x80 != 0 {
+        (b << 1) ^ 0x1b
+    } else {
+        b << 1
+    }
+}
+
+fn mix_columns(state: &mut [u8; 16]) {
+    for column in state.chunks_mut(4) {
+        let a = [column[0], column[1], column[2], column[3]];
+        let all = a[0] ^ a[1] ^ a[2] ^ a[3];
+        for row in 0..4 {
+            column[row] = a[row] ^ all ^ xtime(a[row] ^ a[(row + 1) % 4]);
+        }
+    }
+}
+
+fn reversed(bytes: &[u8; 16]) -> [u8; 16] {
+    let mut result = *bytes;
+    result.reverse();
+    result
+}
+
+fn xor_block(a: &[u8; 16], b: &[u8; 16]) -> [u8; 16] {
+    let mut result = [0; 16];
+    for index in 0..16 {
+        result[index] = a[index] ^ b[index];
+    }
+    result
+}
+
+// Security function e with an already expanded key, little endian in and out
+#[allow(unused)]
+pub fn ble_e_with(cipher: &Aes128, plaintext: &[u8; 16]) -> [u8; 16] {
+    reversed(&cipher.encrypt(&reversed(plaintext)))
+}
+
+#[allow(unused)]
+pub fn ble_e(key: &[u8; 16], plaintext: &[u8; 16]) -> [u8; 16] {
+    ble_e_with(&Aes128::new(&reversed(key)), plaintext)
+}
+
+// Builds p1 and p2 of the confirm value generation function c1.
+// preq/pres are the 7 bytes Pairing Request/Response PDUs as sent on air,
+// addresses are given most significant byte first like BlePacket's macs.
+#[allow(unused)]
+pub fn ble_c1_p1_p2(
+    preq: &[u8; 7],
+    pres: &[u8; 7],
+    initiator_address_random: bool,
+    initiator_address: &[u8; 6],
+    responder_address_random: bool,
+    responder_address: &[u8; 6],
+) -> ([u8; 16], [u8; 16]) {
+    let mut p1 = [0; 16];
+    p1[0] = initiator_address_random as u8;
+    p1[1] = responder_address_random as u8;
+    p1[2..9].copy_from_slice(preq);
+    p1[9..16].copy_from_slice(pres);
+    let mut p2 = [0; 16];
+    for index in 0..6 {
+        p2[index] = responder_address[5 - index];
+        p2[6 + index] = initiator_address[5 - index];
+    }
+    (p1, p2)
+}
+
+#[allow(unused)]
+pub fn ble_c1_with(cipher: &Aes128, r: &[u8; 16], p1: &[u8; 16], p2: &[u8; 16]) -> [u8; 16] {
+    let first = ble_e_with(cipher, &xor_block(r, p1));
+    ble_e_with(cipher, &xor_block(&first, p2))
+}
+
+#[allow(unused)]
+pub fn ble_c1(key: &[u8; 16], r: &[u8; 16], p1: &[u8; 16], p2: &[u8; 16]) -> [u8; 16] {
+    ble_c1_with(&Aes128::new(&reversed(key)), r, p1, p2)
+}
+
+// Key generation function s1, r1 is the responder's random and r2 the initiator's
+#[allow(unused)]
+pub fn ble_s1(key: &[u8; 16], r1: &[u8; 16], r2: &[u8; 16]) -> [u8; 16] {
+    let mut r = [0; 16];
+    r[..8].copy_from_slice(&r2[..8]);
+    r[8..].copy_from_slice(&r1[..8]);
+    ble_e(key, &r)
+}
+
+// The passkey is used as TK, zero padded to 128 bits. Just Works uses passkey 0.
+#[allow(unused)]
+pub fn passkey_to_temporary_key(passkey: u32) -> [u8; 16] {
+    let mut temporary_key = [0; 16];
+    temporary_key[..4].copy_from_slice(&passkey.to_le_bytes());
+    temporary_key
+}
+
+#[allow(unused)]
+pub fn expand_key(key: &[u8; 16]) -> Aes128 {
+    Aes128::new(&reversed(key))
+}
+
+// Length of the MIC appended to encrypted LL data PDUs
+#[allow(unused)]
+pub const CCM_MIC_LENGTH: usize = 4;
+
+// AES-CCM as used by the link layer: 4 bytes MIC, 2 bytes length field and
+// a 13 bytes nonce (Core v5.4 vol.6 PartE Chapter2). The cipher takes the
+// session key most significant byte first, see expand_key.
+#[allow(unused)]
+pub fn aes_ccm_encrypt(cipher: &Aes128, nonce: &[u8; 13], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mic = ccm_mic(cipher, nonce, aad, plaintext);
+    let mut result = ccm_ctr(cipher, nonce, plaintext);
+    result.extend_from_slice(&mic);
+    result
+}
+
+// Returns None when the MIC does not match
+#[allow(unused)]
+pub fn aes_ccm_decrypt(
+    cipher: &Aes128,
+    nonce: &[u8; 13],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Option<Vec<u8>> {
+    if ciphertext.len() < CCM_MIC_LENGTH {
+        return None;
+    }
+    let (ciphertext, mic) = ciphertext.split_at(ciphertext.len() - CCM_MIC_LENGTH);
+    let plaintext = ccm_ctr(cipher, nonce, ciphertext);
+    if ccm_mic(cipher, nonce, aad, &plaintext) != mic {
+        return None;
+    }
+    Some(plaintext)
+}
+
+fn ccm_counter_block(nonce: &[u8; 13], counter: u16) -> [u8; 16] {
+    let mut block = [0; 16];
+    // Flags: L - 1 = 1
+    block[0] = 0x01;
+    block[1..14].copy_from_slice(nonce);
+    block[14..].copy_from_slice(&counter.to_be_bytes());
+    block
+}
+
+fn ccm_ctr(cipher: &Aes128, nonce: &[u8; 13], data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    for (index, chunk) in data.chunks(16).enumerate() {
+        let stream = cipher.encrypt(&ccm_counter_block(nonce, index as u16 + 1));
+        for (b, s) in chunk.iter().zip(stream.iter()) {
+            result.push(*b ^ *s);
+        }
+    }
+    result
+}
+
+fn ccm_mic(
+    cipher: &Aes128,
+    nonce: &[u8; 13],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> [u8; CCM_MIC_LENGTH] {
+    let mut block = [0; 16];
+    // Flags: Adata, (M - 2) / 2 = 1, L - 1 = 1
+    block[0] = if aad.is_empty() { 0x09 } else { 0x49 };
+    block[1..14].copy_from_slice(nonce);
+    block[14..].copy_from_slice(&(plaintext.len() as u16).to_be_bytes());
+    let mut mac = cipher.encrypt(&block);
+    if !aad.is_empty() {
+        let mut aad_bytes = (aad.len() as u16).to_be_bytes().to_vec();
+        aad_bytes.extend_from_slice(aad);
+        for chunk in aad_bytes.chunks(16) {
+            let mut padded = [0; 16];
+            padded[..chunk.len()].copy_from_slice(chunk);
+            mac = cipher.encrypt(&xor_block(&mac, &padded));
+        }
+    }
+    for chunk in plaintext.chunks(16) {
+        let mut padded = [0; 16];
+        padded[..chunk.len()].copy_from_slice(chunk);
+        mac = cipher.encrypt(&xor_block(&mac, &padded));
+    }
+    let s0 = cipher.encrypt(&ccm_counter_block(nonce, 0));
+    let mut mic = [0; CCM_MIC_LENGTH];
+    for index in 0..CCM_MIC_LENGTH {
+        mic[index] = mac[index] ^ s0[index];
+    }
+    mic
+}
+
+// Random address hash function ah, r is prand and the result the hash (24 bits each)
+#[allow(unused)]
+pub fn ble_ah(irk: &[u8; 16], r: &[u8; 3]) -> [u8; 3] {
+    let mut r_padded = [0; 16];
+    r_padded[..3].copy_from_slice(r);
+    let result = ble_e(irk, &r_padded);
+    [result[0], result[1], result[2]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Most significant byte first like the sample data of the specification
+    fn hex_bytes<const N: usize>(hex: &str) -> [u8; N] {
+        crate::ble_sniffer::parse_hex_bytes(hex)
+            .unwrap()
+            .try_into()
+            .unwrap()
+    }
+
+    fn le_bytes<const N: usize>(hex: &str) -> [u8; N] {
+        let mut bytes = hex_bytes::<N>(hex);
+        bytes.reverse();
+        bytes
+    }
+
+    // FIPS-197 Appendix C.1
+    #[test]
+    fn aes128_encrypts_fips_197_example() {
+        let cipher = Aes128::new(&hex_bytes("000102030405060708090a0b0c0d0e0f"));
+        let plaintext = hex_bytes("00112233445566778899aabbccddeeff");
+        let ciphertext = hex_bytes("69c4e0d86a7b0430d8cdb78070b4c55a");
+        assert_eq!(cipher.encrypt(&plaintext), ciphertext);
+    }
+
+    // Core v5.4 vol.3 PartH D.7
+    #[test]
+    fn ah_matches_sample_data() {
+        let irk = le_bytes("ec0234a357c8ad05341010a60a397d9b");
+        let prand = le_bytes("708194");
+        assert_eq!(ble_ah(&irk, &prand), le_bytes::<3>("0dfbaa"));
+    }
+
+    // Core v5.4 vol.3 PartH 2.2.3, preq and pres as sent on air (opcode first)
+    #[test]
+    fn c1_matches_sample_data() {
+        let preq = le_bytes("07071000000101");
+        let pres = le_bytes("05000800000302");
+        let (p1, p2) = ble_c1_p1_p2(
+            &preq,
+            &pres,
+            true,
+            &hex_bytes("A1A2A3A4A5A6"),
+            false,
+            &hex_bytes("B1B2B3B4B5B6"),
+        );
+        assert_eq!(p1, le_bytes("05000800000302070710000001010001"));
+        assert_eq!(p2, le_bytes("00000000A1A2A3A4A5A6B1B2B3B4B5B6"));
+        let r = le_bytes("5783D52156AD6F0E6388274EC6702EE0");
+        let confirm = ble_c1(&[0; 16], &r, &p1, &p2);
+        assert_eq!(confirm, le_bytes("1E1E3FEF878988EAD2A74DC5BEF13B86"));
+    }
+
+    // Core v5.4 vol.3 PartH 2.2.4
+    #[test]
+    fn s1_matches_sample_data() {
+        let r1 = le_bytes("000F0E0D0C0B0A091122334455667788");
+        let r2 = le_bytes("010203040506070899AABBCCDDEEFF00");
+        assert_eq!(
+            ble_s1(&[0; 16], &r1, &r2),
+            le_bytes("9A1FE1F0E8B0F49B5B4216AE796DA062")
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ble_sniffer::{
+        make_event_bytes, BlePacket, SnifferMode, ADV_ACCESS_ADDRESS, ADV_CHANNELS,
+        ADV_TYPE_ADV_NONCONN_IND, EVENT_FOLLOW, EVENT_PACKET_ADV_PDU, EVENT_PACKET_DATA_PDU,
+        HEADER_LENGTH, PACKET_HEADER_LENGTH, PING_RESP, PROTOVER_V3, RESP_TIMESTAMP, RESP_VERSION,
+        SNIFFER_BAUDRATE, SWITCH_BAUD_RATE_RESP, UART_PDU_PADDING_LENGTH,
+    },
+    crc::{ble_crc24, BLE_ADV_CRC_INIT},
+    firmware::SNIFFER_BAUD_RATES,
+    protocol::SnifferCommand,
+    slip::SlipDecoder,
+    transport::SnifferTransport,
+};
+
+// Firmware ID answered to PING_REQ by the emulator
+#[allow(unused)]
+pub const EMULATOR_FIRMWARE_ID: u16 = 0xE0E0;
+#[allow(unused)]
+pub const EMULATOR_FIRMWARE_VERSION: &str = "4.1.1";
+
+// A packet the emulated firmware reports, the payload of EVENT_PACKET_ADV_PDU or
+// EVENT_PACKET_DATA_PDU from the packet header to the CRC
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulatedPacket {
+    pub packet_id: u8,
+    pub payload: Vec<u8>,
+}
+
+#[allow(unused)]
+impl EmulatedPacket {
+    // pdu is the on-air PDU (header, length and payload), its CRC is computed with
+    // crc_init. Sent with crc_ok on the 1M PHY at -60 dBm, the emulator fills the time.
+    pub fn new(
+        packet_id: u8,
+        channel: u8,
+        access_address: u32,
+        crc_init: u32,
+        pdu: &[u8],
+    ) -> EmulatedPacket {
+        let mut payload = vec![PACKET_HEADER_LENGTH, 1, channel, 60, 0, 0, 0, 0, 0, 0];
+        payload.extend_from_slice(&access_address.to_le_bytes());
+        payload.extend_from_slice(&pdu[..pdu.len().min(2)]);
+        payload.extend_from_slice(&[0; UART_PDU_PADDING_LENGTH]);
+        if pdu.len() > 2 {
+            payload.extend_from_slice(&pdu[2..]);
+        }
+        payload.extend_from_slice(&ble_crc24(crc_init, pdu));
+        EmulatedPacket { packet_id, payload }
+    }
+
+    pub fn adv(channel: u8, pdu: &[u8]) -> EmulatedPacket {
+        EmulatedPacket::new(
+            EVENT_PACKET_ADV_PDU,
+            channel,
+            ADV_ACCESS_ADDRESS,
+            BLE_ADV_CRC_INIT,
+            pdu,
+        )
+    }
+
+    pub fn data(channel: u8, access_address: u32, crc_init: u32, pdu: &[u8]) -> EmulatedPacket {
+        EmulatedPacket::new(
+            EVENT_PACKET_DATA_PDU,
+            channel,
+            access_address,
+            crc_init,
+            pdu,
+        )
+    }
+
+    // bytes is a whole unescaped UART packet, e.g. from a raw dump of a real sniffer
+    pub fn from(bytes: &[u8]) -> Option<EmulatedPacket> {
+        let header_length = HEADER_LENGTH as usize;
+        if bytes.len() < header_length {
+            return None;
+        }
+        Some(EmulatedPacket {
+            packet_id: bytes[5],
+            payload: bytes[header_length..].to_vec(),
+        })
+    }
+
+    // Reports a decoded (possibly rewritten) packet, see BlePacket::to_uart_packet
+    pub fn from_packet(packet: &BlePacket) -> Option<EmulatedPacket> {
+        EmulatedPacket::from(&packet.to_uart_packet())
+    }
+
+    fn channel(&self) -> Option<u8> {
+        self.payload.get(2).copied()
+    }
+}
+
+// A simulated nRF sniffer behind the SnifferTransport trait, to run the sniffer
+// without a dongle. It answers PING_REQ, REQ_VERSION, REQ_TIMESTAMP and
+// SWITCH_BAUD_RATE_REQ, and reports its scripted packets: advertising packets on the
+// channels of the hop sequence while scanning, the others after REQ_FOLLOW
+// (EVENT_FOLLOW first). Nothing is reported while idle.
+#[allow(unused)]
+pub struct FirmwareEmulator {
+    protocol_version: u8,
+    firmware_id: u16,
+    // None for firmwares older than 3.0.0, which ignore REQ_VERSION
+    version: Option<String>,
+    // The rate the firmware talks at, the host has to follow it
+    firmware_baud_rate: u32,
+    // Switched to once SWITCH_BAUD_RATE_RESP is sent, at the previous rate
+    next_baud_rate: Option<u32>,
+    host_baud_rate: u32,
+    mode: SnifferMode,
+    adv_channel_hop_sequence: Vec<u8>,
+    scan_script: VecDeque<EmulatedPacket>,
+    follow_script: VecDeque<EmulatedPacket>,
+    close_when_done: bool,
+    decoder: SlipDecoder,
+    output: VecDeque<u8>,
+    packet_counter: u16,
+    started: Instant,
+    last_packet: Instant,
+    commands: Arc<Mutex<Vec<SnifferCommand>>>,
+}
+
+#[allow(unused)]
+impl FirmwareEmulator {
+    // Boots idle at SNIFFER_BAUDRATE like the real firmware
+    pub fn new(protocol_version: u8) -> FirmwareEmulator {
+        FirmwareEmulator {
+            protocol_version,
+            firmware_id: EMULATOR_FIRMWARE_ID,
+            version: Some(EMULATOR_FIRMWARE_VERSION.to_string()),
+            firmware_baud_rate: SNIFFER_BAUDRATE,
+            next_baud_rate: None,
+            host_baud_rate: SNIFFER_BAUDRATE,
+            mode: SnifferMode::Idle,
+            adv_channel_hop_sequence: ADV_CHANNELS.to_vec(),
+            scan_script: VecDeque::new(),
+            follow_script: VecDeque::new(),
+            close_when_done: false,
+            decoder: SlipDecoder::new(),
+            output: VecDeque::new(),
+            packet_counter: 0,
+            started: Instant::now(),
+            last_packet: Instant::now(),
+            commands: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // A few non-connectable advertisements on every advertising channel
+    pub fn demo(protocol_version: u8) -> FirmwareEmulator {
+        let mut emulator = FirmwareEmulator::new(protocol_version);
+        let name = b"Emulated";
+        let mut pdu = vec![ADV_TYPE_ADV_NONCONN_IND, 0];
+        pdu.extend_from_slice(&[0x66, 0x55, 0x44, 0x33, 0x22, 0x11]);
+        pdu.extend_from_slice(&[0x02, 0x01, 0x06]);
+        pdu.extend_from_slice(&[name.len() as u8 + 1, 0x09]);
+        pdu.extend_from_slice(name);
+        pdu[1] = (pdu.len() - 2) as u8;
+        for channel in ADV_CHANNELS {
+            emulator.script(EmulatedPacket::adv(channel, &pdu));
+        }
+        emulator
+    }
+
+    // Replays the packets of a raw dump of the UART bytes of a sniffer
+    pub fn from_dump(protocol_version: u8, bytes: &[u8]) -> FirmwareEmulator {
+        let mut emulator = FirmwareEmulator::new(protocol_version);
+        for frame in SlipDecoder::new().push(bytes).into_iter().flatten() {
+            if let Some(packet) = EmulatedPacket::from(&frame) {
+                if packet.packet_id == EVENT_PACKET_ADV_PDU
+                    || packet.packet_id == EVENT_PACKET_DATA_PDU
+                {
+                    emulator.script(packet);
+                }
+            }
+        }
+        emulator
+    }
+
+    // Advertising packets are reported while scanning, the others once following
+    pub fn script(&mut self, packet: EmulatedPacket) {
+        if packet.packet_id == EVENT_PACKET_ADV_PDU {
+            self.scan_script.push_back(packet);
+        } else {
+            self.follow_script.push_back(packet);
+        }
+    }
+
+    pub fn set_firmware_id(&mut self, firmware_id: u16) {
+        self.firmware_id = firmware_id;
+    }
+
+    pub fn set_version(&mut self, version: Option<&str>) {
+        self.version = version.map(|version| version.to_string());
+    }
+
+    // Reads fail with UnexpectedEof once the packets of the current mode are all
+    // reported, so a run over a finite script ends
+    pub fn set_close_when_done(&mut self, close_when_done: bool) {
+        self.close_when_done = close_when_done;
+    }
+
+    // Commands received so far, shared to be checked once the emulator is boxed
+    pub fn command_log(&self) -> Arc<Mutex<Vec<SnifferCommand>>> {
+        self.commands.clone()
+    }
+
+    pub fn mode(&self) -> &SnifferMode {
+        &self.mode
+    }
+
+    pub fn firmware_baud_rate(&self) -> u32 {
+        self.firmware_baud_rate
+    }
+
+    fn send(&mut self, id: u8, payload: &[u8]) {
+        let bytes = make_event_bytes(self.protocol_version, id, payload, self.packet_counter);
+        self.packet_counter = self.packet_counter.wrapping_add(1);
+        self.output.extend(bytes);
+    }
+
+    fn handle(&mut self, command: SnifferCommand) {
+        if let Ok(mut commands) = self.commands.lock() {
+            commands.push(command.clone());
+        }
+        match command {
+            SnifferCommand::Ping => {
+                let firmware_id = self.firmware_id.to_le_bytes();
+                self.send(PING_RESP, &firmware_id);
+            }
+            SnifferCommand::RequestVersion => {
+                if let Some(version) = self.version.clone() {
+                    self.send(RESP_VERSION, version.as_bytes());
+                }
+            }
+            SnifferCommand::RequestTimestamp => {
+                let timestamp_us = self.started.elapsed().as_micros() as u32;
+                self.send(RESP_TIMESTAMP, &timestamp_us.to_le_bytes());
+            }
+            SnifferCommand::SwitchBaudRate(baud_rate) => {
+                // Unsupported rates are answered with the current one
+                if SNIFFER_BAUD_RATES.contains(&baud_rate) {
+                    self.send(SWITCH_BAUD_RATE_RESP, &baud_rate.to_le_bytes());
+                    self.next_baud_rate = Some(baud_rate);
+                } else {
+                    let current = self.firmware_baud_rate.to_le_bytes();
+                    self.send(SWITCH_BAUD_RATE_RESP, &current);
+                }
+            }
+            SnifferCommand::Scan(_) => self.mode = SnifferMode::Scan,
+            SnifferCommand::Follow(target) => {
+                self.mode = SnifferMode::Follow(target);
+                self.send(EVENT_FOLLOW, &[]);
+            }
+            SnifferCommand::GoIdle => self.mode = SnifferMode::Idle,
+            SnifferCommand::SetAdvChannelHopSequence(channels) => {
+                self.adv_channel_hop_sequence = channels;
+            }
+            _ => {}
+        }
+    }
+
+    // Reports the next scripted packet of the current mode, if any
+    fn report_next(&mut self) {
+        let packet = match self.mode {
+            SnifferMode::Idle => None,
+            SnifferMode::Scan => loop {
+                match self.scan_script.pop_front() {
+                    Some(packet)
+                        if packet
+                            .channel()
+                            .is_some_and(|c| self.adv_channel_hop_sequence.contains(&c)) =>
+                    {
+                        break Some(packet)
+                    }
+                    Some(_) => continue,
+                    None => break None,
+                }
+            },
+            SnifferMode::Follow(_) => self.follow_script.pop_front(),
+        };
+        let mut packet = match packet {
+            Some(packet) => packet,
+            None => return,
+        };
+        let time = if self.protocol_version >= PROTOVER_V3 {
+            self.started.elapsed().as_micros() as u32
+        } else {
+            self.last_packet.elapsed().as_micros() as u32
+        };
+        self.last_packet = Instant::now();
+        if packet.payload.len() >= PACKET_HEADER_LENGTH as usize {
+            packet.payload[6..10].copy_from_slice(&time.to_le_bytes());
+        }
+        self.send(packet.packet_id, &packet.payload);
+    }
+
+    fn is_done(&self) -> bool {
+        match self.mode {
+            SnifferMode::Idle => false,
+            SnifferMode::Scan => self.scan_script.is_empty(),
+            SnifferMode::Follow(_) => self.follow_script.is_empty(),
+        }
+    }
+}
+
+impl SnifferTransport for FirmwareEmulator {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            self.report_next();
+        }
+        // At another rate than the firmware's, the host would only read garbage
+        if self.host_baud_rate != self.firmware_baud_rate {
+            self.output.clear();
+        }
+        if self.output.is_empty() {
+            if self.close_when_done && self.is_done() {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "script done"));
+            }
+            // Stand in for the read timeout without spinning
+            thread::sleep(Duration::from_millis(1));
+            return Ok(0);
+        }
+        let read_len = self.output.len().min(buffer.len());
+        for (index, b) in self.output.drain(..read_len).enumerate() {
+            buffer[index] = b;
+        }
+        if self.output.is_empty() {
+            if let Some(baud_rate) = self.next_baud_rate.take() {
+                self.firmware_baud_rate = baud_rate;
+            }
+        }
+        Ok(read_len)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.host_baud_rate != self.firmware_baud_rate {
+            return Ok(());
+        }
+        for frame in self.decoder.push(bytes).into_iter().flatten() {
+            if let Some(command) = SnifferCommand::decode(&frame) {
+                self.handle(command);
+            }
+        }
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        Some(self.host_baud_rate)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.host_baud_rate = baud_rate;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        ble_sniffer::{analyze_transport_packets, SnifferConfig},
+        protocol::SnifferEvent,
+    };
+
+    // Keeps what the sniffer thread reads from the emulator
+    struct RecordingTransport {
+        emulator: FirmwareEmulator,
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl SnifferTransport for RecordingTransport {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let read_len = self.emulator.read(buffer)?;
+            self.received
+                .lock()
+                .unwrap()
+                .extend_from_slice(&buffer[..read_len]);
+            Ok(read_len)
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.emulator.write(bytes)
+        }
+
+        fn baud_rate(&self) -> Option<u32> {
+            self.emulator.baud_rate()
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            self.emulator.set_baud_rate(baud_rate)
+        }
+    }
+
+    #[test]
+    fn demo_runs_end_to_end() {
+        let mut emulator = FirmwareEmulator::demo(PROTOVER_V3);
+        emulator.set_close_when_done(true);
+        let commands = emulator.command_log();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut transport = Some(RecordingTransport {
+            emulator,
+            received: received.clone(),
+        });
+        let mut config = SnifferConfig::new();
+        config.baud_rate = 1000000;
+        let (tx, rx_packets) = mpsc::channel();
+        let (_control_tx, rx) = mpsc::channel();
+        let result = analyze_transport_packets(
+            "emulator://",
+            || match transport.take() {
+                Some(transport) => Ok(Box::new(transport) as Box<dyn SnifferTransport>),
+                None => Err(io::Error::new(ErrorKind::NotFound, "emulator used")),
+            },
+            &config,
+            tx,
+            &rx,
+        );
+        assert!(result.is_ok());
+
+        let commands = commands.lock().unwrap();
+        assert_eq!(
+            commands[..3],
+            [
+                SnifferCommand::Ping,
+                SnifferCommand::RequestVersion,
+                SnifferCommand::SwitchBaudRate(1000000),
+            ]
+        );
+        assert!(commands.contains(&SnifferCommand::RequestTimestamp));
+
+        let events: Vec<SnifferEvent> = SlipDecoder::new()
+            .push(&received.lock().unwrap())
+            .into_iter()
+            .flatten()
+            .filter_map(|frame| SnifferEvent::decode(&frame).ok())
+            .collect();
+        let version = events.iter().find_map(|event| match event {
+            SnifferEvent::Version(version) => Some(version.clone()),
+            _ => None,
+        });
+        let version = version.unwrap();
+        assert_eq!(version.text, EMULATOR_FIRMWARE_VERSION);
+        assert_eq!(version.protocol_version, PROTOVER_V3);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, SnifferEvent::SwitchBaudRate(1000000))));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, SnifferEvent::Timestamp(_))));
+
+        let packets: Vec<BlePacket> = rx_packets.try_iter().collect();
+        let channels: Vec<u8> = packets
+            .iter()
+            .map(|packet| packet.packet_header.channel_index)
+            .collect();
+        assert_eq!(channels, ADV_CHANNELS);
+        for packet in &packets {
+            assert!(packet.packet_header.timestamp_us.is_some());
+            let non_conn_ind = packet.ll_layer_data.non_conn_ind.as_ref().unwrap();
+            let name = non_conn_ind.complete_local_name.as_ref().unwrap();
+            assert_eq!(name.device_name, "Emulated");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, io};
+
+use crate::{firmware::SnifferFirmwareVersion, protocol::SnifferCommand, slip::SlipError};
+
+// Why a UART packet from the firmware couldn't be decoded
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // The field at offset needs more bytes than the packet has
+    Truncated { offset: usize, needed: usize },
+    // Header length byte of a protocol version 1 UART header, or of the packet header
+    BadHeaderLength(u8),
+    UnsupportedProtocolVersion(u8),
+    // Packet id of a UART packet that isn't EVENT_PACKET_ADV_PDU/DATA_PDU
+    NotAPacket(u8),
+    // Advertising PDU types BlePacket doesn't decode
+    UnsupportedPduType(u8),
+    // Payload length byte not allowed for the PDU type
+    BadPayloadLength { pdu_type: u8, length: u8 },
+    // AD structure (Core v5.4 vol.3 PartC 11) running past the advertising data
+    MalformedAdStructure { offset: usize },
+    // All zero advertiser address
+    MissingAddress,
+    // Payload of a response the firmware sent, by packet id
+    MalformedResponse(u8),
+}
+
+// What can go wrong while talking to a sniffer
+#[allow(unused)]
+#[derive(Debug)]
+pub enum SnifferError {
+    // Neither a serial port nor a known scheme, see SnifferSource
+    InvalidSource(String),
+    Io(io::Error),
+    Framing(SlipError),
+    Parse(ParseError),
+    // The command's timeout passed without its response
+    NoResponse(SnifferCommand),
+    // Nothing came from the firmware for WATCHDOG_TIMEOUT
+    Unresponsive,
+    UnsupportedFirmware(SnifferFirmwareVersion),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { offset, needed } => {
+                write!(f, "truncated packet, {} bytes needed at {}", needed, offset)
+            }
+            ParseError::BadHeaderLength(length) => write!(f, "bad header length {}", length),
+            ParseError::UnsupportedProtocolVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ParseError::NotAPacket(packet_id) => {
+                write!(f, "packet id 0x{:02X} doesn't carry a packet", packet_id)
+            }
+            ParseError::UnsupportedPduType(pdu_type) => {
+                write!(f, "unsupported PDU type 0x{:X}", pdu_type)
+            }
+            ParseError::BadPayloadLength { pdu_type, length } => {
+                write!(
+                    f,
+                    "bad payload length {} for PDU type 0x{:X}",
+                    length, pdu_type
+                )
+            }
+            ParseError::MalformedAdStructure { offset } => {
+                write!(f, "malformed AD structure at {}", offset)
+            }
+            ParseError::MissingAddress => write!(f, "missing advertiser address"),
+            ParseError::MalformedResponse(packet_id) => {
+                write!(f, "malformed response 0x{:02X}", packet_id)
+            }
+        }
+    }
+}
+
+impl fmt::Display for SnifferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnifferError::InvalidSource(source) => write!(f, "invalid sniffer source {}", source),
+            SnifferError::Io(error) => write!(f, "{}", error),
+            SnifferError::Framing(error) => write!(f, "framing error, {:?}", error),
+            SnifferError::Parse(error) => write!(f, "{}", error),
+            SnifferError::NoResponse(command) => write!(f, "no response to {:?}", command),
+            SnifferError::Unresponsive => write!(f, "sniffer stopped answering"),
+            SnifferError::UnsupportedFirmware(version) => write!(
+                f,
+                "protocol version {} of the firmware isn't supported",
+                version.protocol_version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl std::error::Error for SnifferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnifferError::Io(error) => Some(error),
+            SnifferError::Parse(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnifferError {
+    fn from(error: io::Error) -> SnifferError {
+        SnifferError::Io(error)
+    }
+}
+
+impl From<SlipError> for SnifferError {
+    fn from(error: SlipError) -> SnifferError {
+        SnifferError::Framing(error)
+    }
+}
+
+impl From<ParseError> for SnifferError {
+    fn from(error: ParseError) -> SnifferError {
+        SnifferError::Parse(error)
+    }
+}
@@ -0,0 +1,608 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{
+    ble_sniffer::{
+        BlePacket, UartPacketHeader, HEADER_LENGTH, PING_RESP, PROTOVER_V1, PROTOVER_V2,
+        PROTOVER_V3, RESP_TIMESTAMP, RESP_VERSION, SNIFFER_BAUDRATE, SWITCH_BAUD_RATE_RESP,
+    },
+    error::SnifferError,
+    logger::{log, LogLevel},
+    protocol::{SnifferCommand, SnifferCommander, SnifferEvent},
+    slip::SlipDecoder,
+    transport::{SerialTransport, SnifferTransport},
+};
+
+// Protocol versions BlePacket::from can parse
+#[allow(unused)]
+pub const SUPPORTED_PROTOCOL_VERSIONS: [u8; 3] = [PROTOVER_V1, PROTOVER_V2, PROTOVER_V3];
+// The firmware boots at SNIFFER_BAUDRATE, the faster rates have to be negotiated
+#[allow(unused)]
+pub const SNIFFER_BAUD_RATES: [u32; 3] = [SNIFFER_BAUDRATE, 1000000, 2000000];
+// Firmwares older than 3.0.0 don't answer REQ_VERSION at all
+#[allow(unused)]
+pub const FIRMWARE_VERSION_TIMEOUT: Duration = Duration::from_secs(1);
+#[allow(unused)]
+pub const PING_TIMEOUT: Duration = Duration::from_millis(500);
+// The watchdog pings the firmware every WATCHDOG_INTERVAL and reconnects when
+// nothing came back for WATCHDOG_TIMEOUT
+#[allow(unused)]
+pub const WATCHDOG_INTERVAL: Duration = Duration::from_secs(2);
+#[allow(unused)]
+pub const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(7);
+// Reads block up to SERIAL_READ_TIMEOUT, so the reader loop wakes up for the watchdog
+// and the controls without sleeping while bytes wait
+#[allow(unused)]
+pub const SERIAL_READ_TIMEOUT: Duration = Duration::from_millis(50);
+// Between two attempts to open the port
+#[allow(unused)]
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+#[allow(unused)]
+pub const TIMESTAMP_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+// Drift between the firmware and host clocks worth a warning
+#[allow(unused)]
+pub const TIMESTAMP_DRIFT_WARNING_PPM: f64 = 100.0;
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnifferFirmwareVersion {
+    // As sent by the firmware, e.g. "4.1.1"
+    pub text: String,
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+    // Protocol version of the RESP_VERSION packet header
+    pub protocol_version: u8,
+}
+
+#[allow(unused)]
+impl SnifferFirmwareVersion {
+    // bytes is a whole unescaped UART packet
+    pub fn from(bytes: &[u8]) -> Option<SnifferFirmwareVersion> {
+        let (header, payload) = response_payload(bytes, RESP_VERSION)?;
+        let text: String = String::from_utf8_lossy(payload)
+            .trim_end_matches('\0')
+            .trim()
+            .to_string();
+        let mut numbers = text
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .split(|c: char| !c.is_ascii_digit())
+            .map(|number| number.parse::<u8>().ok());
+        let major = numbers.next()??;
+        let minor = numbers.next().flatten().unwrap_or(0);
+        let patch = numbers.next().flatten().unwrap_or(0);
+        Some(SnifferFirmwareVersion {
+            text,
+            major,
+            minor,
+            patch,
+            protocol_version: header.protocol_version,
+        })
+    }
+
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_PROTOCOL_VERSIONS.contains(&self.protocol_version)
+    }
+}
+
+// Payload of RESP_TIMESTAMP, the firmware clock in microseconds
+#[allow(unused)]
+pub fn parse_timestamp_response(bytes: &[u8]) -> Option<u32> {
+    let (_, payload) = response_payload(bytes, RESP_TIMESTAMP)?;
+    Some(u32::from_le_bytes(payload.get(..4)?.try_into().ok()?))
+}
+
+// Payload of PING_RESP, the firmware ID
+#[allow(unused)]
+pub fn parse_ping_response(bytes: &[u8]) -> Option<u16> {
+    let (_, payload) = response_payload(bytes, PING_RESP)?;
+    Some(u16::from_le_bytes(payload.get(..2)?.try_into().ok()?))
+}
+
+// Payload of SWITCH_BAUD_RATE_RESP, the rate the firmware switches to
+#[allow(unused)]
+pub fn parse_switch_baud_rate_response(bytes: &[u8]) -> Option<u32> {
+    let (_, payload) = response_payload(bytes, SWITCH_BAUD_RATE_RESP)?;
+    Some(u32::from_le_bytes(payload.get(..4)?.try_into().ok()?))
+}
+
+// bytes is a whole unescaped UART packet, None if it isn't a packet_id one
+fn response_payload(bytes: &[u8], packet_id: u8) -> Option<(UartPacketHeader, &[u8])> {
+    let header = UartPacketHeader::from_any_version(bytes).ok()?;
+    if header.packet_id != packet_id {
+        return None;
+    }
+    Some((header, &bytes[HEADER_LENGTH as usize..]))
+}
+
+// Maps the firmware microsecond clock (wrapping every ~71 minutes) to host time,
+// synced from RESP_TIMESTAMP
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct SnifferClock {
+    first_sync: Option<(u64, SystemTime)>,
+    last_sync: Option<(u64, SystemTime)>,
+    // Last raw timestamp, to unwrap the 32 bits counter
+    last_timestamp_us: u32,
+    wraps: u64,
+}
+
+#[allow(unused)]
+impl SnifferClock {
+    pub fn new() -> SnifferClock {
+        SnifferClock {
+            first_sync: None,
+            last_sync: None,
+            last_timestamp_us: 0,
+            wraps: 0,
+        }
+    }
+
+    pub fn sync(&mut self, timestamp_us: u32, host_time: SystemTime) {
+        let timestamp_us = self.unwrap(timestamp_us);
+        if self.first_sync.is_none() {
+            self.first_sync = Some((timestamp_us, host_time));
+        }
+        self.last_sync = Some((timestamp_us, host_time));
+    }
+
+    fn unwrap(&mut self, timestamp_us: u32) -> u64 {
+        if self.last_sync.is_some() && timestamp_us < self.last_timestamp_us {
+            self.wraps += 1;
+        }
+        self.last_timestamp_us = timestamp_us;
+        (self.wraps << 32) | timestamp_us as u64
+    }
+
+    // Host time of a firmware timestamp close to the last sync
+    pub fn host_time(&self, timestamp_us: u32) -> Option<SystemTime> {
+        let (synced_us, synced_time) = self.last_sync?;
+        let offset = timestamp_us.wrapping_sub(synced_us as u32) as i32;
+        if offset >= 0 {
+            synced_time.checked_add(Duration::from_micros(offset as u64))
+        } else {
+            synced_time.checked_sub(Duration::from_micros(offset.unsigned_abs() as u64))
+        }
+    }
+
+    // How much faster (positive) the firmware clock runs than the host one
+    pub fn drift_ppm(&self) -> Option<f64> {
+        let (first_us, first_time) = self.first_sync?;
+        let (last_us, last_time) = self.last_sync?;
+        let host_us = last_time.duration_since(first_time).ok()?.as_micros() as f64;
+        if host_us == 0.0 {
+            return None;
+        }
+        Some(((last_us - first_us) as f64 - host_us) / host_us * 1e6)
+    }
+}
+
+// Protocol version 3 sends the firmware clock at the start of each packet instead of
+// delta_time_us, which is filled back from it (and the airtime of the previous packet)
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct PacketTimeline {
+    // Firmware clock at the end of the previous packet
+    previous_end_us: Option<u32>,
+}
+
+#[allow(unused)]
+impl PacketTimeline {
+    pub fn new() -> PacketTimeline {
+        PacketTimeline {
+            previous_end_us: None,
+        }
+    }
+
+    // Packets have to come in capture order, the ones without timestamp are left untouched
+    pub fn process(&mut self, packet: &mut BlePacket) {
+        let timestamp_us = match packet.packet_header.timestamp_us {
+            Some(timestamp_us) => timestamp_us,
+            None => return,
+        };
+        if let Some(previous_end_us) = self.previous_end_us {
+            packet.packet_header.delta_time_us = timestamp_us.wrapping_sub(previous_end_us);
+        }
+        self.previous_end_us = Some(timestamp_us.wrapping_add(packet.airtime_us().unwrap_or(0)));
+    }
+
+    pub fn clear(&mut self) {
+        self.previous_end_us = None;
+    }
+}
+
+// The firmware numbers every UART packet it sends, a gap in the packet counters
+// means packets were lost on the way (UART overrun, host too slow, framing error)
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct PacketCounterTracker {
+    last_packet_counter: Option<u16>,
+    dropped: u64,
+}
+
+#[allow(unused)]
+impl PacketCounterTracker {
+    pub fn new() -> PacketCounterTracker {
+        PacketCounterTracker {
+            last_packet_counter: None,
+            dropped: 0,
+        }
+    }
+
+    // Returns how many packets were lost right before this one
+    pub fn process(&mut self, packet_counter: u16) -> u16 {
+        let gap = match self.last_packet_counter {
+            Some(last) => packet_counter.wrapping_sub(last).wrapping_sub(1),
+            None => 0,
+        };
+        // A counter going backwards means the firmware restarted, not 65000 lost packets
+        let gap = if gap >= 0x8000 { 0 } else { gap };
+        self.last_packet_counter = Some(packet_counter);
+        self.dropped += gap as u64;
+        gap
+    }
+
+    // Packets lost since the tracker was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // The firmware counter restarts when it is reset, e.g. after a reconnection
+    pub fn clear(&mut self) {
+        self.last_packet_counter = None;
+    }
+}
+
+// Sends REQ_VERSION and waits for the answer, None if the firmware stays silent.
+// Anything else received meanwhile is dropped, scanning hasn't started yet.
+#[allow(unused)]
+pub fn request_firmware_version(
+    transport: &mut dyn SnifferTransport,
+    commander: &mut SnifferCommander,
+) -> Option<SnifferFirmwareVersion> {
+    match request(transport, commander, SnifferCommand::RequestVersion) {
+        Ok(SnifferEvent::Version(version)) => Some(version),
+        _ => None,
+    }
+}
+
+// Sends PING_REQ and returns the firmware ID of PING_RESP
+#[allow(unused)]
+pub fn ping(transport: &mut dyn SnifferTransport, commander: &mut SnifferCommander) -> Option<u16> {
+    match request(transport, commander, SnifferCommand::Ping) {
+        Ok(SnifferEvent::PingResponse(firmware_id)) => Some(firmware_id),
+        _ => None,
+    }
+}
+
+// Opens the port and pings it, returns the firmware ID when a sniffer answers
+#[allow(unused)]
+pub fn probe_port(path: &str, baud_rate: u32) -> Option<u16> {
+    let mut transport = SerialTransport::open(path, baud_rate).ok()?;
+    ping(&mut transport, &mut SnifferCommander::new())
+}
+
+// The firmware keeps a negotiated rate until it is reset, so after a reconnect it
+// may not talk at SNIFFER_BAUDRATE anymore. Leaves the port at the rate found.
+#[allow(unused)]
+pub fn find_baud_rate(
+    transport: &mut dyn SnifferTransport,
+    commander: &mut SnifferCommander,
+) -> Option<u32> {
+    for baud_rate in SNIFFER_BAUD_RATES {
+        if transport.set_baud_rate(baud_rate).is_err() {
+            continue;
+        }
+        if ping(transport, commander).is_some() {
+            return Some(baud_rate);
+        }
+    }
+    None
+}
+
+// Asks the firmware to switch to baud_rate and follows it, returns the rate in use
+// afterwards. Firmwares not supporting the rate keep the current one.
+#[allow(unused)]
+pub fn negotiate_baud_rate(
+    transport: &mut dyn SnifferTransport,
+    commander: &mut SnifferCommander,
+    baud_rate: u32,
+) -> u32 {
+    // Behind a TCP bridge the baud rate can't follow the firmware's
+    let current = match transport.baud_rate() {
+        Some(current) => current,
+        None => return SNIFFER_BAUDRATE,
+    };
+    if baud_rate == current {
+        return current;
+    }
+    let accepted = match request(
+        transport,
+        commander,
+        SnifferCommand::SwitchBaudRate(baud_rate),
+    ) {
+        Ok(SnifferEvent::SwitchBaudRate(accepted)) => Some(accepted),
+        _ => None,
+    };
+    match accepted {
+        Some(accepted) if accepted != current => {
+            // The response is sent at the current rate, the firmware switches right after
+            if transport.set_baud_rate(accepted).is_ok() && ping(transport, commander).is_some() {
+                return accepted;
+            }
+            log(
+                LogLevel::Warning,
+                &format!("Sniffer doesn't answer at {} baud, falling back", accepted),
+            );
+            match find_baud_rate(transport, commander) {
+                Some(found) => found,
+                None => {
+                    let _ = transport.set_baud_rate(current);
+                    current
+                }
+            }
+        }
+        _ => {
+            log(
+                LogLevel::Warning,
+                &format!(
+                    "Sniffer firmware doesn't switch to {} baud, staying at {} baud",
+                    baud_rate, current
+                ),
+            );
+            current
+        }
+    }
+}
+
+// Pings every serial port of the system, returns the paths answering and their
+// firmware IDs
+#[allow(unused)]
+pub fn detect_sniffers(baud_rate: u32) -> Vec<(String, u16)> {
+    let ports = match serialport::available_ports() {
+        Ok(ports) => ports,
+        Err(error) => {
+            log(
+                LogLevel::Error,
+                &format!("Cannot list serial ports, {}", error),
+            );
+            return Vec::new();
+        }
+    };
+    ports
+        .into_iter()
+        .filter_map(|port| {
+            probe_port(&port.port_name, baud_rate).map(|firmware_id| (port.port_name, firmware_id))
+        })
+        .collect()
+}
+
+// Sends command and waits for its response until the command's timeout, anything
+// else received meanwhile is dropped
+fn request(
+    transport: &mut dyn SnifferTransport,
+    commander: &mut SnifferCommander,
+    command: SnifferCommand,
+) -> Result<SnifferEvent, SnifferError> {
+    let timeout = match command.response_timeout() {
+        Some(timeout) => timeout,
+        None => return Err(SnifferError::NoResponse(command)),
+    };
+    if let Err(error) = transport.write(&commander.encode(command.clone())) {
+        commander.clear();
+        return Err(SnifferError::Io(error));
+    }
+    let start = Instant::now();
+    let mut slip_decoder = SlipDecoder::new();
+    let mut recv_buffer = [0; 256];
+    while start.elapsed() < timeout {
+        let available_len = transport.read(&mut recv_buffer)?;
+        // Framing errors are expected while probing the wrong baud rate
+        for packet in slip_decoder
+            .push(&recv_buffer[..available_len])
+            .into_iter()
+            .flatten()
+        {
+            if let Ok(event) = SnifferEvent::decode(&packet) {
+                if commander.correlate(&event).is_some() {
+                    return Ok(event);
+                }
+            }
+        }
+    }
+    commander.expired();
+    Err(SnifferError::NoResponse(command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ble_sniffer::make_event_packet, emulator::FirmwareEmulator};
+
+    #[test]
+    fn parses_firmware_versions() {
+        let bytes = make_event_packet(PROTOVER_V3, RESP_VERSION, b"4.1.1\0", 7);
+        let version = SnifferFirmwareVersion::from(&bytes).unwrap();
+        assert_eq!(version.text, "4.1.1");
+        assert_eq!((version.major, version.minor, version.patch), (4, 1, 1));
+        assert_eq!(version.protocol_version, PROTOVER_V3);
+        assert!(version.is_supported());
+
+        let bytes = make_event_packet(PROTOVER_V2, RESP_VERSION, b"v3.2", 7);
+        let version = SnifferFirmwareVersion::from(&bytes).unwrap();
+        assert_eq!((version.major, version.minor, version.patch), (3, 2, 0));
+
+        assert!(SnifferFirmwareVersion::from(&make_event_packet(
+            PROTOVER_V3,
+            RESP_VERSION,
+            b"unknown",
+            7
+        ))
+        .is_none());
+        assert!(SnifferFirmwareVersion::from(&make_event_packet(
+            PROTOVER_V3,
+            RESP_TIMESTAMP,
+            b"4.1.1",
+            7
+        ))
+        .is_none());
+    }
+
+    #[test]
+    fn newer_protocol_versions_are_read_and_rejected() {
+        let bytes = make_event_packet(PROTOVER_V3 + 1, RESP_VERSION, b"5.0.0", 7);
+        let version = SnifferFirmwareVersion::from(&bytes).unwrap();
+        assert_eq!(version.major, 5);
+        assert_eq!(version.protocol_version, PROTOVER_V3 + 1);
+        assert!(!version.is_supported());
+    }
+
+    #[test]
+    fn clock_maps_firmware_time_to_host_time() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut clock = SnifferClock::new();
+        assert!(clock.host_time(0).is_none());
+        assert!(clock.drift_ppm().is_none());
+
+        clock.sync(u32::MAX - 999_999, start);
+        assert_eq!(clock.host_time(u32::MAX - 999_999), Some(start));
+        assert!(clock.drift_ppm().is_none());
+        assert_eq!(
+            clock.host_time(u32::MAX - 1_999_999),
+            Some(start - Duration::from_secs(1))
+        );
+
+        // 10 seconds later on the host, the firmware clock wrapped and ran 1 ms more
+        let later = start + Duration::from_secs(10);
+        clock.sync(9_001_000, later);
+        let drift = clock.drift_ppm().unwrap();
+        assert!((drift - 100.0).abs() < 0.01, "{}", drift);
+        assert_eq!(clock.host_time(9_001_000), Some(later));
+        assert_eq!(
+            clock.host_time(9_501_000),
+            Some(later + Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn counts_packets_lost_between_counters() {
+        let mut tracker = PacketCounterTracker::new();
+        assert_eq!(tracker.process(10), 0);
+        assert_eq!(tracker.process(11), 0);
+        // 12 to 14 lost
+        assert_eq!(tracker.process(15), 3);
+        assert_eq!(tracker.dropped(), 3);
+    }
+
+    #[test]
+    fn packet_counters_wrap_around() {
+        let mut tracker = PacketCounterTracker::new();
+        tracker.process(u16::MAX - 1);
+        assert_eq!(tracker.process(u16::MAX), 0);
+        assert_eq!(tracker.process(0), 0);
+        // u16::MAX and 0 lost
+        tracker.process(u16::MAX - 1);
+        assert_eq!(tracker.process(1), 2);
+        assert_eq!(tracker.dropped(), 2);
+    }
+
+    #[test]
+    fn firmware_restart_loses_no_packets() {
+        let mut tracker = PacketCounterTracker::new();
+        tracker.process(5000);
+        // Counting from 0 again
+        assert_eq!(tracker.process(0), 0);
+        assert_eq!(tracker.process(1), 0);
+        tracker.clear();
+        assert_eq!(tracker.process(200), 0);
+        assert_eq!(tracker.dropped(), 0);
+    }
+
+    #[test]
+    fn parses_ping_responses() {
+        let bytes = make_event_packet(PROTOVER_V3, PING_RESP, &[0x34, 0x12], 1);
+        assert_eq!(parse_ping_response(&bytes), Some(0x1234));
+        let bytes = make_event_packet(PROTOVER_V1, PING_RESP, &[0x34, 0x12], 1);
+        assert_eq!(parse_ping_response(&bytes), Some(0x1234));
+        assert!(
+            parse_ping_response(&make_event_packet(PROTOVER_V3, PING_RESP, &[0x34], 1)).is_none()
+        );
+        let bytes = make_event_packet(PROTOVER_V3, RESP_TIMESTAMP, &[0x34, 0x12], 1);
+        assert!(parse_ping_response(&bytes).is_none());
+    }
+
+    #[test]
+    fn ping_gets_the_firmware_id() {
+        let mut emulator = FirmwareEmulator::new(PROTOVER_V3);
+        emulator.set_firmware_id(0xbeef);
+        assert_eq!(
+            ping(&mut emulator, &mut SnifferCommander::new()),
+            Some(0xbeef)
+        );
+    }
+
+    #[test]
+    fn ping_without_reply_times_out() {
+        let mut emulator = FirmwareEmulator::new(PROTOVER_V3);
+        // The firmware can't be heard at another rate
+        emulator.set_baud_rate(1000000).unwrap();
+        let mut commander = SnifferCommander::new();
+        let start = Instant::now();
+        assert!(ping(&mut emulator, &mut commander).is_none());
+        assert!(start.elapsed() >= PING_TIMEOUT);
+    }
+
+    #[test]
+    fn probing_a_missing_port_finds_no_sniffer() {
+        assert!(probe_port("/dev/no-such-sniffer", SNIFFER_BAUDRATE).is_none());
+    }
+
+    #[test]
+    fn negotiates_a_supported_baud_rate() {
+        let mut emulator = FirmwareEmulator::new(PROTOVER_V3);
+        let mut commander = SnifferCommander::new();
+        assert_eq!(
+            negotiate_baud_rate(&mut emulator, &mut commander, 1000000),
+            1000000
+        );
+        assert_eq!(emulator.firmware_baud_rate(), 1000000);
+        assert_eq!(emulator.baud_rate(), Some(1000000));
+        assert!(ping(&mut emulator, &mut commander).is_some());
+    }
+
+    #[test]
+    fn refused_baud_rate_keeps_the_current_one() {
+        let mut emulator = FirmwareEmulator::new(PROTOVER_V3);
+        let mut commander = SnifferCommander::new();
+        // Answered with SWITCH_BAUD_RATE_RESP at the current rate
+        assert_eq!(
+            negotiate_baud_rate(&mut emulator, &mut commander, 115200),
+            SNIFFER_BAUDRATE
+        );
+        assert_eq!(emulator.firmware_baud_rate(), SNIFFER_BAUDRATE);
+        assert_eq!(emulator.baud_rate(), Some(SNIFFER_BAUDRATE));
+    }
+
+    #[test]
+    fn finds_the_rate_a_firmware_kept() {
+        let mut emulator = FirmwareEmulator::new(PROTOVER_V3);
+        let mut commander = SnifferCommander::new();
+        negotiate_baud_rate(&mut emulator, &mut commander, 2000000);
+        // As after a reconnection
+        emulator.set_baud_rate(SNIFFER_BAUDRATE).unwrap();
+        assert_eq!(find_baud_rate(&mut emulator, &mut commander), Some(2000000));
+        assert_eq!(emulator.baud_rate(), Some(2000000));
+    }
+
+    #[test]
+    fn parses_switch_baud_rate_responses() {
+        let bytes = make_event_packet(
+            PROTOVER_V3,
+            SWITCH_BAUD_RATE_RESP,
+            &1000000u32.to_le_bytes(),
+            1,
+        );
+        assert_eq!(parse_switch_baud_rate_response(&bytes), Some(1000000));
+        let bytes = make_event_packet(PROTOVER_V3, SWITCH_BAUD_RATE_RESP, &[0x40, 0x42], 1);
+        assert!(parse_switch_baud_rate_response(&bytes).is_none());
+    }
+}
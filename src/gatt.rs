@@ -0,0 +1,704 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    att::{AttPdu, AttRecord, BleUuid, ATT_EXECUTE_WRITE_IMMEDIATELY},
+    ble_sniffer::read_u16_le,
+};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.3 PartG Chapter3
+#[allow(unused)]
+pub const GATT_PRIMARY_SERVICE_UUID: u16 = 0x2800;
+#[allow(unused)]
+pub const GATT_SECONDARY_SERVICE_UUID: u16 = 0x2801;
+#[allow(unused)]
+pub const GATT_INCLUDE_UUID: u16 = 0x2802;
+#[allow(unused)]
+pub const GATT_CHARACTERISTIC_UUID: u16 = 0x2803;
+
+#[allow(unused)]
+pub const GATT_PROP_BROADCAST: u8 = 0x01;
+#[allow(unused)]
+pub const GATT_PROP_READ: u8 = 0x02;
+#[allow(unused)]
+pub const GATT_PROP_WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+#[allow(unused)]
+pub const GATT_PROP_WRITE: u8 = 0x08;
+#[allow(unused)]
+pub const GATT_PROP_NOTIFY: u8 = 0x10;
+#[allow(unused)]
+pub const GATT_PROP_INDICATE: u8 = 0x20;
+#[allow(unused)]
+pub const GATT_PROP_AUTHENTICATED_SIGNED_WRITES: u8 = 0x40;
+#[allow(unused)]
+pub const GATT_PROP_EXTENDED_PROPERTIES: u8 = 0x80;
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct GattService {
+    pub handle: u16,
+    pub end_group_handle: u16,
+    pub uuid: BleUuid,
+    pub primary: bool,
+    pub includes: Vec<GattInclude>,
+    pub characteristics: Vec<GattCharacteristic>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct GattInclude {
+    pub handle: u16,
+    pub service_handle: u16,
+    pub end_group_handle: u16,
+    pub uuid: Option<BleUuid>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct GattCharacteristic {
+    pub handle: u16,
+    pub value_handle: u16,
+    pub properties: u8,
+    pub uuid: BleUuid,
+    pub value: Option<Vec<u8>>,
+    pub descriptors: Vec<GattDescriptor>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct GattDescriptor {
+    pub handle: u16,
+    pub uuid: Option<BleUuid>,
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+struct GattAttribute {
+    uuid: Option<BleUuid>,
+    value: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+struct GattPreparedWrite {
+    handle: u16,
+    offset: u16,
+    value: Vec<u8>,
+}
+
+// Builds the peer's attribute table from the ATT records of one connection,
+// the tree is assembled on demand by services() so discovery order does not matter.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct GattDatabase {
+    services: Vec<GattService>,
+    includes: Vec<GattInclude>,
+    characteristics: Vec<GattCharacteristic>,
+    attributes: BTreeMap<u16, GattAttribute>,
+    // Queued by Prepare Write, applied or dropped by Execute Write
+    prepared_writes: Vec<GattPreparedWrite>,
+}
+
+// One GattDatabase per connection (access address) of a capture
+#[allow(unused)]
+#[derive(Debug)]
+pub struct GattConnections {
+    databases: Vec<(u32, GattDatabase)>,
+}
+
+#[allow(unused)]
+impl GattDatabase {
+    pub fn new() -> GattDatabase {
+        GattDatabase {
+            services: Vec::new(),
+            includes: Vec::new(),
+            characteristics: Vec::new(),
+            attributes: BTreeMap::new(),
+            prepared_writes: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, record: &AttRecord) {
+        match (&record.pdu, &record.request) {
+            (
+                AttPdu::ReadByGroupTypeRsp { entries },
+                Some(AttPdu::ReadByGroupTypeReq { group_type, .. }),
+            ) => {
+                let primary = *group_type == BleUuid::Uuid16(GATT_PRIMARY_SERVICE_UUID);
+                if !primary && *group_type != BleUuid::Uuid16(GATT_SECONDARY_SERVICE_UUID) {
+                    return;
+                }
+                for entry in entries {
+                    if let Some(uuid) = BleUuid::from(&entry.value) {
+                        self.add_service(entry.handle, entry.end_group_handle, uuid, primary);
+                    }
+                }
+            }
+            (
+                AttPdu::FindByTypeValueRsp { entries },
+                Some(AttPdu::FindByTypeValueReq {
+                    attribute_type,
+                    value,
+                    ..
+                }),
+            ) => {
+                if *attribute_type != GATT_PRIMARY_SERVICE_UUID {
+                    return;
+                }
+                if let Some(uuid) = BleUuid::from(value) {
+                    for entry in entries {
+                        self.add_service(entry.handle, entry.end_group_handle, uuid, true);
+                    }
+                }
+            }
+            (
+                AttPdu::ReadByTypeRsp { entries },
+                Some(AttPdu::ReadByTypeReq { attribute_type, .. }),
+            ) => {
+                for entry in entries {
+                    if *attribute_type == BleUuid::Uuid16(GATT_CHARACTERISTIC_UUID) {
+                        self.add_characteristic(entry.handle, &entry.value);
+                    } else if *attribute_type == BleUuid::Uuid16(GATT_INCLUDE_UUID) {
+                        self.add_include(entry.handle, &entry.value);
+                    } else {
+                        self.set_attribute(entry.handle, Some(*attribute_type), &entry.value);
+                    }
+                }
+            }
+            (AttPdu::FindInformationRsp { entries }, _) => {
+                for entry in entries {
+                    self.attributes
+                        .entry(entry.handle)
+                        .or_insert(GattAttribute {
+                            uuid: None,
+                            value: None,
+                        })
+                        .uuid = Some(entry.uuid);
+                }
+            }
+            (AttPdu::ReadRsp { value }, Some(AttPdu::ReadReq { handle })) => {
+                self.set_attribute(*handle, None, value);
+            }
+            (AttPdu::ReadBlobRsp { value }, Some(AttPdu::ReadBlobReq { handle, offset })) => {
+                self.set_attribute_part(*handle, *offset, value);
+            }
+            (AttPdu::WriteRsp, Some(AttPdu::WriteReq { handle, value })) => {
+                self.set_attribute(*handle, None, value);
+            }
+            // The response echoes what the server queued
+            (
+                AttPdu::PrepareWriteRsp {
+                    handle,
+                    offset,
+                    value,
+                },
+                Some(AttPdu::PrepareWriteReq { .. }),
+            ) => {
+                self.prepared_writes.push(GattPreparedWrite {
+                    handle: *handle,
+                    offset: *offset,
+                    value: value.clone(),
+                });
+            }
+            (AttPdu::ExecuteWriteRsp, Some(AttPdu::ExecuteWriteReq { flags })) => {
+                let prepared_writes = std::mem::take(&mut self.prepared_writes);
+                if *flags == ATT_EXECUTE_WRITE_IMMEDIATELY {
+                    for write in prepared_writes {
+                        self.set_attribute_part(write.handle, write.offset, &write.value);
+                    }
+                }
+            }
+            // A failed execution leaves the values unknown, the old ones are kept
+            (AttPdu::ErrorRsp { .. }, Some(AttPdu::ExecuteWriteReq { .. })) => {
+                self.prepared_writes.clear();
+            }
+            (AttPdu::WriteCmd { handle, value }, _)
+            | (AttPdu::SignedWriteCmd { handle, value, .. }, _)
+            | (AttPdu::HandleValueNtf { handle, value }, _)
+            | (AttPdu::HandleValueInd { handle, value }, _) => {
+                self.set_attribute(*handle, None, value);
+            }
+            (AttPdu::MultipleHandleValueNtf { entries }, _) => {
+                for entry in entries {
+                    self.set_attribute(entry.handle, None, &entry.value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Service/characteristic/descriptor tree of everything discovered so far
+    pub fn services(&self) -> Vec<GattService> {
+        let mut services = self.services.clone();
+        services.sort_by_key(|service| service.handle);
+        let mut characteristics = self.characteristics.clone();
+        characteristics.sort_by_key(|characteristic| characteristic.handle);
+        for service in &mut services {
+            service.includes = self
+                .includes
+                .iter()
+                .filter(|include| {
+                    include.handle > service.handle && include.handle <= service.end_group_handle
+                })
+                .cloned()
+                .collect();
+        }
+        for (index, characteristic) in characteristics.iter().enumerate() {
+            let service = match services.iter_mut().find(|service| {
+                characteristic.handle > service.handle
+                    && characteristic.handle <= service.end_group_handle
+            }) {
+                Some(service) => service,
+                None => continue,
+            };
+            let mut end_handle = service.end_group_handle;
+            if let Some(next) = characteristics.get(index + 1) {
+                if next.handle <= end_handle {
+                    end_handle = next.handle - 1;
+                }
+            }
+            let mut characteristic = characteristic.clone();
+            if let Some(attribute) = self.attributes.get(&characteristic.value_handle) {
+                characteristic.value = attribute.value.clone();
+            }
+            // The value handle may be the last one of the characteristic
+            let first_descriptor_handle = characteristic.value_handle.saturating_add(1);
+            if first_descriptor_handle <= end_handle {
+                characteristic.descriptors = self
+                    .attributes
+                    .range(first_descriptor_handle..=end_handle)
+                    .map(|(handle, attribute)| GattDescriptor {
+                        handle: *handle,
+                        uuid: attribute.uuid,
+                        value: attribute.value.clone(),
+                    })
+                    .collect();
+            }
+            service.characteristics.push(characteristic);
+        }
+        services
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"services\":[");
+        for (service_index, service) in self.services().iter().enumerate() {
+            if service_index > 0 {
+                json.push(',');
+            }
+            json.push_str(&format!(
+                "{{\"handle\":{},\"end_group_handle\":{},\"uuid\":\"{}\",\"primary\":{},\"includes\":[",
+                service.handle, service.end_group_handle, service.uuid, service.primary
+            ));
+            for (include_index, include) in service.includes.iter().enumerate() {
+                if include_index > 0 {
+                    json.push(',');
+                }
+                json.push_str(&format!(
+                    "{{\"handle\":{},\"service_handle\":{},\"end_group_handle\":{},\"uuid\":{}}}",
+                    include.handle,
+                    include.service_handle,
+                    include.end_group_handle,
+                    json_uuid(&include.uuid)
+                ));
+            }
+            json.push_str("],\"characteristics\":[");
+            for (characteristic_index, characteristic) in service.characteristics.iter().enumerate()
+            {
+                if characteristic_index > 0 {
+                    json.push(',');
+                }
+                json.push_str(&format!(
+                    "{{\"handle\":{},\"value_handle\":{},\"uuid\":\"{}\",\"properties\":[",
+                    characteristic.handle, characteristic.value_handle, characteristic.uuid
+                ));
+                let property_names: Vec<String> = gatt_property_names(characteristic.properties)
+                    .iter()
+                    .map(|name| format!("\"{}\"", name))
+                    .collect();
+                json.push_str(&property_names.join(","));
+                json.push_str(&format!(
+                    "],\"value\":{},\"descriptors\":[",
+                    json_value(&characteristic.value)
+                ));
+                for (descriptor_index, descriptor) in characteristic.descriptors.iter().enumerate()
+                {
+                    if descriptor_index > 0 {
+                        json.push(',');
+                    }
+                    json.push_str(&format!(
+                        "{{\"handle\":{},\"uuid\":{},\"value\":{}}}",
+                        descriptor.handle,
+                        json_uuid(&descriptor.uuid),
+                        json_value(&descriptor.value)
+                    ));
+                }
+                json.push_str("]}");
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+
+    pub fn clear(&mut self) {
+        self.services.clear();
+        self.includes.clear();
+        self.characteristics.clear();
+        self.attributes.clear();
+        self.prepared_writes.clear();
+    }
+
+    fn add_service(&mut self, handle: u16, end_group_handle: u16, uuid: BleUuid, primary: bool) {
+        self.services.retain(|service| service.handle != handle);
+        self.services.push(GattService {
+            handle,
+            end_group_handle,
+            uuid,
+            primary,
+            includes: Vec::new(),
+            characteristics: Vec::new(),
+        });
+        let service_type = if primary {
+            GATT_PRIMARY_SERVICE_UUID
+        } else {
+            GATT_SECONDARY_SERVICE_UUID
+        };
+        self.attributes
+            .entry(handle)
+            .or_insert(GattAttribute {
+                uuid: None,
+                value: None,
+            })
+            .uuid = Some(BleUuid::Uuid16(service_type));
+    }
+
+    // Characteristic declaration value: properties(1) + value handle(2) + uuid(2 or 16)
+    fn add_characteristic(&mut self, handle: u16, value: &[u8]) {
+        if value.len() < 3 {
+            return;
+        }
+        let uuid = match BleUuid::from(&value[3..]) {
+            Some(uuid) => uuid,
+            None => return,
+        };
+        self.characteristics
+            .retain(|characteristic| characteristic.handle != handle);
+        self.characteristics.push(GattCharacteristic {
+            handle,
+            value_handle: read_u16_le(value, 1),
+            properties: value[0],
+            uuid,
+            value: None,
+            descriptors: Vec::new(),
+        });
+        self.set_attribute(
+            handle,
+            Some(BleUuid::Uuid16(GATT_CHARACTERISTIC_UUID)),
+            value,
+        );
+        self.attributes
+            .entry(read_u16_le(value, 1))
+            .or_insert(GattAttribute {
+                uuid: None,
+                value: None,
+            })
+            .uuid = Some(uuid);
+    }
+
+    // Include declaration value: service handle(2) + end group handle(2) + optional uuid16
+    fn add_include(&mut self, handle: u16, value: &[u8]) {
+        if value.len() < 4 {
+            return;
+        }
+        self.includes.retain(|include| include.handle != handle);
+        self.includes.push(GattInclude {
+            handle,
+            service_handle: read_u16_le(value, 0),
+            end_group_handle: read_u16_le(value, 2),
+            uuid: BleUuid::from(&value[4..]),
+        });
+        self.set_attribute(handle, Some(BleUuid::Uuid16(GATT_INCLUDE_UUID)), value);
+    }
+
+    // Part of a long value from offset on, e.g. Read Blob and Prepare Write
+    fn set_attribute_part(&mut self, handle: u16, offset: u16, value: &[u8]) {
+        let attribute = self.attributes.entry(handle).or_insert(GattAttribute {
+            uuid: None,
+            value: None,
+        });
+        let mut full_value = attribute.value.take().unwrap_or_default();
+        full_value.truncate(offset as usize);
+        full_value.extend_from_slice(value);
+        attribute.value = Some(full_value);
+    }
+
+    fn set_attribute(&mut self, handle: u16, uuid: Option<BleUuid>, value: &[u8]) {
+        let attribute = self.attributes.entry(handle).or_insert(GattAttribute {
+            uuid: None,
+            value: None,
+        });
+        if uuid.is_some() {
+            attribute.uuid = uuid;
+        }
+        attribute.value = Some(value.to_vec());
+    }
+}
+
+#[allow(unused)]
+pub fn gatt_property_names(properties: u8) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    let all_properties = [
+        (GATT_PROP_BROADCAST, "broadcast"),
+        (GATT_PROP_READ, "read"),
+        (GATT_PROP_WRITE_WITHOUT_RESPONSE, "write_without_response"),
+        (GATT_PROP_WRITE, "write"),
+        (GATT_PROP_NOTIFY, "notify"),
+        (GATT_PROP_INDICATE, "indicate"),
+        (
+            GATT_PROP_AUTHENTICATED_SIGNED_WRITES,
+            "authenticated_signed_writes",
+        ),
+        (GATT_PROP_EXTENDED_PROPERTIES, "extended_properties"),
+    ];
+    for (property, name) in all_properties {
+        if properties & property != 0 {
+            names.push(name);
+        }
+    }
+    names
+}
+
+#[allow(unused)]
+impl GattConnections {
+    pub fn new() -> GattConnections {
+        GattConnections {
+            databases: Vec::new(),
+        }
+    }
+
+    pub fn process(&mut self, record: &AttRecord) {
+        let index = match self
+            .databases
+            .iter()
+            .position(|(access_address, _)| *access_address == record.access_address)
+        {
+            Some(index) => index,
+            None => {
+                self.databases
+                    .push((record.access_address, GattDatabase::new()));
+                self.databases.len() - 1
+            }
+        };
+        self.databases[index].1.process(record);
+    }
+
+    pub fn database(&self, access_address: u32) -> Option<&GattDatabase> {
+        self.databases
+            .iter()
+            .find(|(aa, _)| *aa == access_address)
+            .map(|(_, database)| database)
+    }
+
+    pub fn to_json(&self) -> String {
+        let connections: Vec<String> = self
+            .databases
+            .iter()
+            .map(|(access_address, database)| {
+                format!(
+                    "{{\"access_address\":\"0x{:08X}\",\"database\":{}}}",
+                    access_address,
+                    database.to_json()
+                )
+            })
+            .collect();
+        format!("{{\"connections\":[{}]}}", connections.join(","))
+    }
+}
+
+fn json_uuid(uuid: &Option<BleUuid>) -> String {
+    match uuid {
+        Some(uuid) => format!("\"{}\"", uuid),
+        None => String::from("null"),
+    }
+}
+
+fn json_value(value: &Option<Vec<u8>>) -> String {
+    match value {
+        Some(value) => {
+            let hex: Vec<String> = value.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("\"{}\"", hex.concat())
+        }
+        None => String::from("null"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Response decoded from its bytes, paired with the request as AttTransactionTracker does
+    fn record(pdu: &[u8], request: &[u8]) -> AttRecord {
+        AttRecord {
+            access_address: 0x50654A9B,
+            direction_to_slave: false,
+            timestamp_us: 0,
+            pdu: AttPdu::from(pdu).unwrap(),
+            request: AttPdu::from(request),
+            latency_us: None,
+        }
+    }
+
+    // Discovery of a GAP and a Battery service, as sent by a central after connecting
+    fn discovered_database() -> GattDatabase {
+        let mut database = GattDatabase::new();
+        let records = [
+            record(
+                &[
+                    0x11, 0x06, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18, 0x06, 0x00, 0x09, 0x00, 0x0F,
+                    0x18,
+                ],
+                &[0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28],
+            ),
+            record(
+                &[
+                    0x09, 0x07, 0x02, 0x00, 0x02, 0x03, 0x00, 0x00, 0x2A, 0x04, 0x00, 0x02, 0x05,
+                    0x00, 0x01, 0x2A,
+                ],
+                &[0x08, 0x01, 0x00, 0x05, 0x00, 0x03, 0x28],
+            ),
+            record(
+                &[0x09, 0x07, 0x07, 0x00, 0x12, 0x08, 0x00, 0x19, 0x2A],
+                &[0x08, 0x06, 0x00, 0x09, 0x00, 0x03, 0x28],
+            ),
+            record(
+                &[0x05, 0x01, 0x09, 0x00, 0x02, 0x29],
+                &[0x04, 0x09, 0x00, 0x09, 0x00],
+            ),
+            // Device name longer than ATT_MTU - 1, its end is read from offset 22
+            record(b"\x0BABCDEFGHIJKLMNOPQRSTUV", &[0x0A, 0x03, 0x00]),
+            record(b"\x0DWXYZ", &[0x0C, 0x03, 0x00, 0x16, 0x00]),
+            record(&[0x13], &[0x12, 0x09, 0x00, 0x01, 0x00]),
+            record(&[0x1B, 0x08, 0x00, 0x64], &[]),
+        ];
+        for record in &records {
+            database.process(record);
+        }
+        database
+    }
+
+    #[test]
+    fn nests_discovered_attributes() {
+        let services = discovered_database().services();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].uuid, BleUuid::Uuid16(0x1800));
+        assert_eq!(services[1].uuid, BleUuid::Uuid16(0x180F));
+        assert!(services.iter().all(|service| service.primary));
+
+        let gap = &services[0].characteristics;
+        assert_eq!(gap.len(), 2);
+        assert_eq!(gap[0].value_handle, 0x0003);
+        assert_eq!(
+            gap[0].value.as_deref(),
+            Some(&b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"[..])
+        );
+        assert!(gap[0].descriptors.is_empty());
+        assert_eq!(gap[1].uuid, BleUuid::Uuid16(0x2A01));
+        assert_eq!(gap[1].value, None);
+
+        let battery = &services[1].characteristics;
+        assert_eq!(battery.len(), 1);
+        assert_eq!(
+            gatt_property_names(battery[0].properties),
+            ["read", "notify"]
+        );
+        assert_eq!(battery[0].value, Some(vec![0x64]));
+        assert_eq!(battery[0].descriptors.len(), 1);
+        assert_eq!(battery[0].descriptors[0].handle, 0x0009);
+        assert_eq!(
+            battery[0].descriptors[0].uuid,
+            Some(BleUuid::Uuid16(0x2902))
+        );
+        assert_eq!(battery[0].descriptors[0].value, Some(vec![0x01, 0x00]));
+    }
+
+    #[test]
+    fn exports_json() {
+        let expected = concat!(
+            "{\"services\":[",
+            "{\"handle\":1,\"end_group_handle\":5,\"uuid\":\"0x1800\",\"primary\":true,",
+            "\"includes\":[],\"characteristics\":[",
+            "{\"handle\":2,\"value_handle\":3,\"uuid\":\"0x2A00\",\"properties\":[\"read\"],",
+            "\"value\":\"4142434445464748494A4B4C4D4E4F505152535455565758595A\",\"descriptors\":[]},",
+            "{\"handle\":4,\"value_handle\":5,\"uuid\":\"0x2A01\",\"properties\":[\"read\"],",
+            "\"value\":null,\"descriptors\":[]}]},",
+            "{\"handle\":6,\"end_group_handle\":9,\"uuid\":\"0x180F\",\"primary\":true,",
+            "\"includes\":[],\"characteristics\":[",
+            "{\"handle\":7,\"value_handle\":8,\"uuid\":\"0x2A19\",\"properties\":[\"read\",\"notify\"],",
+            "\"value\":\"64\",\"descriptors\":[{\"handle\":9,\"uuid\":\"0x2902\",\"value\":\"0100\"}]}]}",
+            "]}"
+        );
+        assert_eq!(discovered_database().to_json(), expected);
+        assert_eq!(GattDatabase::new().to_json(), "{\"services\":[]}");
+    }
+
+    #[test]
+    fn keeps_a_database_per_connection() {
+        let mut connections = GattConnections::new();
+        assert_eq!(connections.to_json(), "{\"connections\":[]}");
+        let mut other = record(&[0x13], &[0x12, 0x03, 0x00, 0x41]);
+        other.access_address = 0x12345678;
+        connections.process(&other);
+        connections.process(&record(
+            &[0x11, 0x06, 0x01, 0x00, 0x05, 0x00, 0x00, 0x18],
+            &[0x10, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x28],
+        ));
+        assert_eq!(
+            connections.database(0x50654A9B).unwrap().services().len(),
+            1
+        );
+        assert!(connections
+            .database(0x12345678)
+            .unwrap()
+            .services()
+            .is_empty());
+        assert!(connections.database(0x8E89BED6).is_none());
+        assert_eq!(
+            connections.to_json(),
+            concat!(
+                "{\"connections\":[",
+                "{\"access_address\":\"0x12345678\",\"database\":{\"services\":[]}},",
+                "{\"access_address\":\"0x50654A9B\",\"database\":{\"services\":[",
+                "{\"handle\":1,\"end_group_handle\":5,\"uuid\":\"0x1800\",\"primary\":true,",
+                "\"includes\":[],\"characteristics\":[]}]}}]}"
+            )
+        );
+    }
+
+    #[test]
+    fn applies_executed_prepared_writes() {
+        let mut database = discovered_database();
+        let prepare = |offset: u8, value: &[u8]| {
+            let mut pdu = vec![0x17, 0x03, 0x00, offset, 0x00];
+            pdu.extend_from_slice(value);
+            let mut request = pdu.clone();
+            request[0] = 0x16;
+            record(&pdu, &request)
+        };
+        let name =
+            |database: &GattDatabase| database.services()[0].characteristics[0].value.clone();
+        database.process(&prepare(0, b"Hello "));
+        database.process(&prepare(6, b"World"));
+        assert_eq!(
+            name(&database).as_deref(),
+            Some(&b"ABCDEFGHIJKLMNOPQRSTUVWXYZ"[..])
+        );
+        database.process(&record(&[0x19], &[0x18, 0x01]));
+        assert_eq!(name(&database).as_deref(), Some(&b"Hello World"[..]));
+
+        // Cancelled or failed executions leave the value alone
+        database.process(&prepare(0, b"Bye"));
+        database.process(&record(&[0x19], &[0x18, 0x00]));
+        database.process(&prepare(0, b"Bye"));
+        database.process(&record(&[0x01, 0x18, 0x03, 0x00, 0x0D], &[0x18, 0x01]));
+        database.process(&record(&[0x19], &[0x18, 0x01]));
+        assert_eq!(name(&database).as_deref(), Some(&b"Hello World"[..]));
+    }
+}
@@ -0,0 +1,894 @@
+use crate::{
+    ble_sniffer::{read_u16_le, BlePacket, LLID_DATA_CONTINUATION, LLID_DATA_START},
+    smp::SmpPdu,
+};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.3 PartA
+#[allow(unused)]
+pub const L2CAP_HEADER_LENGTH: usize = 4;
+#[allow(unused)]
+pub const L2CAP_CID_ATT: u16 = 0x0004;
+#[allow(unused)]
+pub const L2CAP_CID_LE_SIGNALING: u16 = 0x0005;
+#[allow(unused)]
+pub const L2CAP_CID_SMP: u16 = 0x0006;
+#[allow(unused)]
+pub const L2CAP_CID_DYNAMIC_FIRST: u16 = 0x0040;
+#[allow(unused)]
+pub const L2CAP_CID_DYNAMIC_LAST: u16 = 0x007F;
+
+// LE signaling command codes (vol.3 PartA Chapter4)
+#[allow(unused)]
+pub const L2CAP_COMMAND_REJECT_RSP: u8 = 0x01;
+#[allow(unused)]
+pub const L2CAP_DISCONNECTION_REQ: u8 = 0x06;
+#[allow(unused)]
+pub const L2CAP_DISCONNECTION_RSP: u8 = 0x07;
+#[allow(unused)]
+pub const L2CAP_CONNECTION_PARAMETER_UPDATE_REQ: u8 = 0x12;
+#[allow(unused)]
+pub const L2CAP_CONNECTION_PARAMETER_UPDATE_RSP: u8 = 0x13;
+#[allow(unused)]
+pub const L2CAP_LE_CREDIT_BASED_CONNECTION_REQ: u8 = 0x14;
+#[allow(unused)]
+pub const L2CAP_LE_CREDIT_BASED_CONNECTION_RSP: u8 = 0x15;
+#[allow(unused)]
+pub const L2CAP_FLOW_CONTROL_CREDIT_IND: u8 = 0x16;
+#[allow(unused)]
+pub const L2CAP_CREDIT_BASED_CONNECTION_REQ: u8 = 0x17;
+#[allow(unused)]
+pub const L2CAP_CREDIT_BASED_CONNECTION_RSP: u8 = 0x18;
+#[allow(unused)]
+pub const L2CAP_CREDIT_BASED_RECONFIGURE_REQ: u8 = 0x19;
+#[allow(unused)]
+pub const L2CAP_CREDIT_BASED_RECONFIGURE_RSP: u8 = 0x1A;
+
+#[allow(unused)]
+pub const L2CAP_LE_CREDIT_BASED_CONNECTION_SUCCESSFUL: u16 = 0x0000;
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct BleL2capFrame {
+    pub access_address: u32,
+    pub direction_to_slave: bool,
+    pub channel_id: u16,
+    pub payload: Vec<u8>,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct BleL2capReassembler {
+    pending: Vec<BleL2capPending>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum L2capSignalingCommand {
+    CommandRejectRsp {
+        reason: u16,
+        data: Vec<u8>,
+    },
+    DisconnectionReq {
+        destination_cid: u16,
+        source_cid: u16,
+    },
+    DisconnectionRsp {
+        destination_cid: u16,
+        source_cid: u16,
+    },
+    // Intervals in 1.25 ms units, timeout in 10 ms units
+    ConnectionParameterUpdateReq {
+        interval_min: u16,
+        interval_max: u16,
+        latency: u16,
+        timeout: u16,
+    },
+    ConnectionParameterUpdateRsp {
+        result: u16,
+    },
+    LeCreditBasedConnectionReq {
+        spsm: u16,
+        source_cid: u16,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+    },
+    LeCreditBasedConnectionRsp {
+        destination_cid: u16,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+        result: u16,
+    },
+    FlowControlCreditInd {
+        cid: u16,
+        credits: u16,
+    },
+    CreditBasedConnectionReq {
+        spsm: u16,
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+        source_cids: Vec<u16>,
+    },
+    CreditBasedConnectionRsp {
+        mtu: u16,
+        mps: u16,
+        initial_credits: u16,
+        result: u16,
+        destination_cids: Vec<u16>,
+    },
+    CreditBasedReconfigureReq {
+        mtu: u16,
+        mps: u16,
+        destination_cids: Vec<u16>,
+    },
+    CreditBasedReconfigureRsp {
+        result: u16,
+    },
+    Unknown {
+        code: u8,
+        data: Vec<u8>,
+    },
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct L2capSignalingPacket {
+    pub identifier: u8,
+    pub command: L2capSignalingCommand,
+}
+
+// One receiving end of an LE credit based channel. Frames addressed to it travel
+// in direction_to_slave on the link, credits is how many K-frames the peer may
+// still send before it has to wait for a Flow Control Credit Ind.
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct L2capCocChannel {
+    pub access_address: u32,
+    pub direction_to_slave: bool,
+    pub cid: u16,
+    pub spsm: u16,
+    pub mtu: u16,
+    pub mps: u16,
+    pub credits: u16,
+    pub credit_violations: u32,
+    sdu_len: usize,
+    sdu: Vec<u8>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct L2capSdu {
+    pub access_address: u32,
+    pub direction_to_slave: bool,
+    pub cid: u16,
+    pub spsm: u16,
+    pub payload: Vec<u8>,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum L2capChannelEvent {
+    Signaling(L2capSignalingPacket),
+    Sdu(L2capSdu),
+    // Frames of the Security Manager channel, see smp.rs
+    Smp(SmpPdu),
+}
+
+#[derive(Debug)]
+struct L2capPendingConnection {
+    access_address: u32,
+    identifier: u8,
+    spsm: u16,
+    channels: Vec<L2capCocChannel>,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct L2capChannelTracker {
+    channels: Vec<L2capCocChannel>,
+    pending: Vec<L2capPendingConnection>,
+}
+
+#[derive(Debug)]
+struct BleL2capPending {
+    access_address: u32,
+    direction_to_slave: bool,
+    expected_len: usize,
+    bytes: Vec<u8>,
+}
+
+impl BleL2capFrame {
+    pub fn from(
+        access_address: u32,
+        direction_to_slave: bool,
+        bytes: &[u8],
+    ) -> Option<BleL2capFrame> {
+        if bytes.len() < L2CAP_HEADER_LENGTH {
+            return None;
+        }
+        let payload_len = read_u16_le(bytes, 0) as usize;
+        if bytes.len() < L2CAP_HEADER_LENGTH + payload_len {
+            return None;
+        }
+        Some(BleL2capFrame {
+            access_address,
+            direction_to_slave,
+            channel_id: read_u16_le(bytes, 2),
+            payload: bytes[L2CAP_HEADER_LENGTH..L2CAP_HEADER_LENGTH + payload_len].to_vec(),
+        })
+    }
+}
+
+#[allow(unused)]
+impl BleL2capReassembler {
+    pub fn new() -> BleL2capReassembler {
+        BleL2capReassembler {
+            pending: Vec::new(),
+        }
+    }
+
+    // Feed every data channel packet of a connection, returns the L2CAP frame
+    // once all of its fragments have been received.
+    pub fn process(&mut self, packet: &BlePacket) -> Option<BleL2capFrame> {
+        let data_pdu = packet.ll_layer_data.data_pdu.as_ref()?;
+        let direction_to_slave = match &packet.packet_header.data_header {
+            Some(data_header) => data_header.direction_to_slave,
+            None => false,
+        };
+        let access_address = packet.ll_layer_data.access_address;
+        let pending_index = self.pending.iter().position(|pending| {
+            pending.access_address == access_address
+                && pending.direction_to_slave == direction_to_slave
+        });
+        if data_pdu.llid == LLID_DATA_START {
+            if let Some(index) = pending_index {
+                // The previous frame never completed, drop it
+                self.pending.remove(index);
+            }
+            if data_pdu.payload.len() < L2CAP_HEADER_LENGTH {
+                return None;
+            }
+            let payload_len = read_u16_le(&data_pdu.payload, 0) as usize;
+            let pending = BleL2capPending {
+                access_address,
+                direction_to_slave,
+                expected_len: L2CAP_HEADER_LENGTH + payload_len,
+                bytes: data_pdu.payload.clone(),
+            };
+            if pending.bytes.len() >= pending.expected_len {
+                return BleL2capFrame::from(access_address, direction_to_slave, &pending.bytes);
+            }
+            self.pending.push(pending);
+        } else if data_pdu.llid == LLID_DATA_CONTINUATION && !data_pdu.payload.is_empty() {
+            let index = pending_index?;
+            self.pending[index]
+                .bytes
+                .extend_from_slice(&data_pdu.payload);
+            if self.pending[index].bytes.len() >= self.pending[index].expected_len {
+                let pending = self.pending.remove(index);
+                return BleL2capFrame::from(access_address, direction_to_slave, &pending.bytes);
+            }
+        }
+        None
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[allow(unused)]
+impl L2capSignalingPacket {
+    pub fn from(bytes: &[u8]) -> Option<L2capSignalingPacket> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let code = bytes[0];
+        let identifier = bytes[1];
+        let data_len = read_u16_le(bytes, 2) as usize;
+        if bytes.len() < 4 + data_len {
+            return None;
+        }
+        let data = &bytes[4..4 + data_len];
+        let command = match code {
+            L2CAP_COMMAND_REJECT_RSP => {
+                if data.len() < 2 {
+                    return None;
+                }
+                L2capSignalingCommand::CommandRejectRsp {
+                    reason: read_u16_le(data, 0),
+                    data: data[2..].to_vec(),
+                }
+            }
+            L2CAP_DISCONNECTION_REQ | L2CAP_DISCONNECTION_RSP => {
+                if data.len() != 4 {
+                    return None;
+                }
+                let destination_cid = read_u16_le(data, 0);
+                let source_cid = read_u16_le(data, 2);
+                if code == L2CAP_DISCONNECTION_REQ {
+                    L2capSignalingCommand::DisconnectionReq {
+                        destination_cid,
+                        source_cid,
+                    }
+                } else {
+                    L2capSignalingCommand::DisconnectionRsp {
+                        destination_cid,
+                        source_cid,
+                    }
+                }
+            }
+            L2CAP_CONNECTION_PARAMETER_UPDATE_REQ => {
+                if data.len() != 8 {
+                    return None;
+                }
+                L2capSignalingCommand::ConnectionParameterUpdateReq {
+                    interval_min: read_u16_le(data, 0),
+                    interval_max: read_u16_le(data, 2),
+                    latency: read_u16_le(data, 4),
+                    timeout: read_u16_le(data, 6),
+                }
+            }
+            L2CAP_CONNECTION_PARAMETER_UPDATE_RSP | L2CAP_CREDIT_BASED_RECONFIGURE_RSP => {
+                if data.len() != 2 {
+                    return None;
+                }
+                let result = read_u16_le(data, 0);
+                if code == L2CAP_CONNECTION_PARAMETER_UPDATE_RSP {
+                    L2capSignalingCommand::ConnectionParameterUpdateRsp { result }
+                } else {
+                    L2capSignalingCommand::CreditBasedReconfigureRsp { result }
+                }
+            }
+            L2CAP_LE_CREDIT_BASED_CONNECTION_REQ => {
+                if data.len() != 10 {
+                    return None;
+                }
+                L2capSignalingCommand::LeCreditBasedConnectionReq {
+                    spsm: read_u16_le(data, 0),
+                    source_cid: read_u16_le(data, 2),
+                    mtu: read_u16_le(data, 4),
+                    mps: read_u16_le(data, 6),
+                    initial_credits: read_u16_le(data, 8),
+                }
+            }
+            L2CAP_LE_CREDIT_BASED_CONNECTION_RSP => {
+                if data.len() != 10 {
+                    return None;
+                }
+                L2capSignalingCommand::LeCreditBasedConnectionRsp {
+                    destination_cid: read_u16_le(data, 0),
+                    mtu: read_u16_le(data, 2),
+                    mps: read_u16_le(data, 4),
+                    initial_credits: read_u16_le(data, 6),
+                    result: read_u16_le(data, 8),
+                }
+            }
+            L2CAP_FLOW_CONTROL_CREDIT_IND => {
+                if data.len() != 4 {
+                    return None;
+                }
+                L2capSignalingCommand::FlowControlCreditInd {
+                    cid: read_u16_le(data, 0),
+                    credits: read_u16_le(data, 2),
+                }
+            }
+            L2CAP_CREDIT_BASED_CONNECTION_REQ => {
+                // At least one source CID follows the fixed fields
+                if data.len() < 10 || data.len() & 1 == 1 {
+                    return None;
+                }
+                L2capSignalingCommand::CreditBasedConnectionReq {
+                    spsm: read_u16_le(data, 0),
+                    mtu: read_u16_le(data, 2),
+                    mps: read_u16_le(data, 4),
+                    initial_credits: read_u16_le(data, 6),
+                    source_cids: read_cid_list(&data[8..]),
+                }
+            }
+            L2CAP_CREDIT_BASED_CONNECTION_RSP => {
+                if data.len() < 8 || data.len() & 1 == 1 {
+                    return None;
+                }
+                L2capSignalingCommand::CreditBasedConnectionRsp {
+                    mtu: read_u16_le(data, 0),
+                    mps: read_u16_le(data, 2),
+                    initial_credits: read_u16_le(data, 4),
+                    result: read_u16_le(data, 6),
+                    destination_cids: read_cid_list(&data[8..]),
+                }
+            }
+            L2CAP_CREDIT_BASED_RECONFIGURE_REQ => {
+                if data.len() < 4 || data.len() & 1 == 1 {
+                    return None;
+                }
+                L2capSignalingCommand::CreditBasedReconfigureReq {
+                    mtu: read_u16_le(data, 0),
+                    mps: read_u16_le(data, 2),
+                    destination_cids: read_cid_list(&data[4..]),
+                }
+            }
+            _ => L2capSignalingCommand::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        };
+        Some(L2capSignalingPacket {
+            identifier,
+            command,
+        })
+    }
+}
+
+#[allow(unused)]
+impl L2capCocChannel {
+    fn new(
+        access_address: u32,
+        direction_to_slave: bool,
+        cid: u16,
+        spsm: u16,
+        mtu: u16,
+        mps: u16,
+        credits: u16,
+    ) -> L2capCocChannel {
+        L2capCocChannel {
+            access_address,
+            direction_to_slave,
+            cid,
+            spsm,
+            mtu,
+            mps,
+            credits,
+            credit_violations: 0,
+            sdu_len: 0,
+            sdu: Vec::new(),
+        }
+    }
+}
+
+#[allow(unused)]
+impl L2capChannelTracker {
+    pub fn new() -> L2capChannelTracker {
+        L2capChannelTracker {
+            channels: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Feed every reassembled L2CAP frame of the capture
+    pub fn process(&mut self, frame: &BleL2capFrame) -> Option<L2capChannelEvent> {
+        if frame.channel_id == L2CAP_CID_LE_SIGNALING {
+            let packet = L2capSignalingPacket::from(&frame.payload)?;
+            self.process_signaling(frame, &packet);
+            return Some(L2capChannelEvent::Signaling(packet));
+        }
+        if frame.channel_id == L2CAP_CID_SMP {
+            return SmpPdu::from(&frame.payload).map(L2capChannelEvent::Smp);
+        }
+        if !(L2CAP_CID_DYNAMIC_FIRST..=L2CAP_CID_DYNAMIC_LAST).contains(&frame.channel_id) {
+            return None;
+        }
+        let channel = self.channels.iter_mut().find(|channel| {
+            channel.access_address == frame.access_address
+                && channel.direction_to_slave == frame.direction_to_slave
+                && channel.cid == frame.channel_id
+        })?;
+        if channel.credits == 0 {
+            channel.credit_violations += 1;
+        } else {
+            channel.credits -= 1;
+        }
+        // The first K-frame of an SDU starts with the 2 bytes SDU length
+        if channel.sdu.is_empty() && channel.sdu_len == 0 {
+            if frame.payload.len() < 2 {
+                return None;
+            }
+            channel.sdu_len = read_u16_le(&frame.payload, 0) as usize;
+            channel.sdu.extend_from_slice(&frame.payload[2..]);
+        } else {
+            channel.sdu.extend_from_slice(&frame.payload);
+        }
+        if channel.sdu.len() < channel.sdu_len {
+            return None;
+        }
+        let mut payload = std::mem::take(&mut channel.sdu);
+        payload.truncate(channel.sdu_len);
+        channel.sdu_len = 0;
+        Some(L2capChannelEvent::Sdu(L2capSdu {
+            access_address: channel.access_address,
+            direction_to_slave: channel.direction_to_slave,
+            cid: channel.cid,
+            spsm: channel.spsm,
+            payload,
+        }))
+    }
+
+    pub fn channels(&self) -> &[L2capCocChannel] {
+        &self.channels
+    }
+
+    pub fn clear(&mut self) {
+        self.channels.clear();
+        self.pending.clear();
+    }
+
+    fn process_signaling(&mut self, frame: &BleL2capFrame, packet: &L2capSignalingPacket) {
+        let access_address = frame.access_address;
+        // Frames sent to the device that issued this command travel the other way
+        let sender_direction = !frame.direction_to_slave;
+        match &packet.command {
+            L2capSignalingCommand::LeCreditBasedConnectionReq {
+                spsm,
+                source_cid,
+                mtu,
+                mps,
+                initial_credits,
+            } => {
+                let channel = L2capCocChannel::new(
+                    access_address,
+                    sender_direction,
+                    *source_cid,
+                    *spsm,
+                    *mtu,
+                    *mps,
+                    *initial_credits,
+                );
+                self.add_pending(access_address, packet.identifier, *spsm, vec![channel]);
+            }
+            L2capSignalingCommand::CreditBasedConnectionReq {
+                spsm,
+                mtu,
+                mps,
+                initial_credits,
+                source_cids,
+            } => {
+                let channels = source_cids
+                    .iter()
+                    .map(|source_cid| {
+                        L2capCocChannel::new(
+                            access_address,
+                            sender_direction,
+                            *source_cid,
+                            *spsm,
+                            *mtu,
+                            *mps,
+                            *initial_credits,
+                        )
+                    })
+                    .collect();
+                self.add_pending(access_address, packet.identifier, *spsm, channels);
+            }
+            L2capSignalingCommand::LeCreditBasedConnectionRsp {
+                destination_cid,
+                mtu,
+                mps,
+                initial_credits,
+                result,
+            } => {
+                if let Some(pending) = self.take_pending(access_address, packet.identifier) {
+                    if *result == L2CAP_LE_CREDIT_BASED_CONNECTION_SUCCESSFUL {
+                        self.channels.extend(pending.channels);
+                        self.channels.push(L2capCocChannel::new(
+                            access_address,
+                            sender_direction,
+                            *destination_cid,
+                            pending.spsm,
+                            *mtu,
+                            *mps,
+                            *initial_credits,
+                        ));
+                    }
+                }
+            }
+            L2capSignalingCommand::CreditBasedConnectionRsp {
+                mtu,
+                mps,
+                initial_credits,
+                destination_cids,
+                ..
+            } => {
+                if let Some(pending) = self.take_pending(access_address, packet.identifier) {
+                    // A zero destination CID refuses the matching requested channel
+                    for (channel, destination_cid) in
+                        pending.channels.into_iter().zip(destination_cids)
+                    {
+                        if *destination_cid == 0 {
+                            continue;
+                        }
+                        self.channels.push(channel);
+                        self.channels.push(L2capCocChannel::new(
+                            access_address,
+                            sender_direction,
+                            *destination_cid,
+                            pending.spsm,
+                            *mtu,
+                            *mps,
+                            *initial_credits,
+                        ));
+                    }
+                }
+            }
+            L2capSignalingCommand::FlowControlCreditInd { cid, credits } => {
+                if let Some(channel) = self.channels.iter_mut().find(|channel| {
+                    channel.access_address == access_address
+                        && channel.direction_to_slave == sender_direction
+                        && channel.cid == *cid
+                }) {
+                    channel.credits = channel.credits.saturating_add(*credits);
+                }
+            }
+            L2capSignalingCommand::CreditBasedReconfigureReq {
+                mtu,
+                mps,
+                destination_cids,
+            } => {
+                // The sender announces the new MTU/MPS of its own receiving channels
+                for channel in self.channels.iter_mut() {
+                    if channel.access_address == access_address
+                        && channel.direction_to_slave == sender_direction
+                        && destination_cids.contains(&channel.cid)
+                    {
+                        channel.mtu = *mtu;
+                        channel.mps = *mps;
+                    }
+                }
+            }
+            L2capSignalingCommand::DisconnectionReq {
+                destination_cid,
+                source_cid,
+            } => {
+                self.channels.retain(|channel| {
+                    channel.access_address != access_address
+                        || !((channel.direction_to_slave == frame.direction_to_slave
+                            && channel.cid == *destination_cid)
+                            || (channel.direction_to_slave == sender_direction
+                                && channel.cid == *source_cid))
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn add_pending(
+        &mut self,
+        access_address: u32,
+        identifier: u8,
+        spsm: u16,
+        channels: Vec<L2capCocChannel>,
+    ) {
+        self.pending.retain(|pending| {
+            pending.access_address != access_address || pending.identifier != identifier
+        });
+        self.pending.push(L2capPendingConnection {
+            access_address,
+            identifier,
+            spsm,
+            channels,
+        });
+    }
+
+    fn take_pending(
+        &mut self,
+        access_address: u32,
+        identifier: u8,
+    ) -> Option<L2capPendingConnection> {
+        let index = self.pending.iter().position(|pending| {
+            pending.access_address == access_address && pending.identifier == identifier
+        })?;
+        Some(self.pending.remove(index))
+    }
+}
+
+fn read_cid_list(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks(2).map(|chunk| read_u16_le(chunk, 0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble_sniffer::{BleLLDataPdu, BlePacketHeaderData, EVENT_PACKET_DATA_PDU};
+
+    const ACCESS_ADDRESS: u32 = 0x50654A9B;
+    // Enhanced ATT bearer
+    const SPSM: u16 = 0x0027;
+
+    fn data_packet(direction_to_slave: bool, llid: u8, payload: &[u8]) -> BlePacket {
+        let mut packet = BlePacket::new();
+        packet.packet_id = EVENT_PACKET_DATA_PDU;
+        packet.packet_header.data_header = Some(BlePacketHeaderData {
+            direction_to_slave,
+            encrypted: false,
+            mic_ok: false,
+        });
+        packet.ll_layer_data.access_address = ACCESS_ADDRESS;
+        packet.ll_layer_data.data_pdu = Some(BleLLDataPdu {
+            llid,
+            nesn: false,
+            sn: false,
+            more_data: false,
+            cte_info_present: false,
+            payload: payload.to_vec(),
+        });
+        packet
+    }
+
+    fn frame(direction_to_slave: bool, channel_id: u16, payload: &[u8]) -> BleL2capFrame {
+        BleL2capFrame {
+            access_address: ACCESS_ADDRESS,
+            direction_to_slave,
+            channel_id,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn signaling(direction_to_slave: bool, code: u8, identifier: u8, data: &[u8]) -> BleL2capFrame {
+        let mut payload = vec![code, identifier];
+        payload.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        payload.extend_from_slice(data);
+        frame(direction_to_slave, L2CAP_CID_LE_SIGNALING, &payload)
+    }
+
+    fn u16_fields(fields: &[u16]) -> Vec<u8> {
+        fields
+            .iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn reassembles_start_and_continuation_fragments() {
+        let mut reassembler = BleL2capReassembler::new();
+        // ATT Read By Type Rsp of 9 bytes split over two data PDUs
+        let start = data_packet(
+            false,
+            LLID_DATA_START,
+            &[0x09, 0x00, 0x04, 0x00, 0x09, 0x07],
+        );
+        let continuation = data_packet(
+            false,
+            LLID_DATA_CONTINUATION,
+            &[0x03, 0x00, 0x12, 0x04, 0x00, 0x2a, 0x00],
+        );
+        // An empty PDU of the other side in between doesn't get in the way
+        let empty = data_packet(true, LLID_DATA_CONTINUATION, &[]);
+        assert!(reassembler.process(&start).is_none());
+        assert!(reassembler.process(&empty).is_none());
+        let frame = reassembler.process(&continuation).unwrap();
+        assert_eq!(frame.channel_id, L2CAP_CID_ATT);
+        assert!(!frame.direction_to_slave);
+        assert_eq!(
+            frame.payload,
+            [0x09, 0x07, 0x03, 0x00, 0x12, 0x04, 0x00, 0x2a, 0x00]
+        );
+
+        // A continuation without a start is dropped
+        assert!(reassembler.process(&continuation).is_none());
+        // A single fragment frame comes out right away
+        let single = data_packet(true, LLID_DATA_START, &[0x01, 0x00, 0x06, 0x00, 0x0b]);
+        let frame = reassembler.process(&single).unwrap();
+        assert_eq!(frame.channel_id, L2CAP_CID_SMP);
+        assert_eq!(frame.payload, [0x0b]);
+    }
+
+    #[test]
+    fn tracks_credits_of_le_credit_based_channels() {
+        let mut tracker = L2capChannelTracker::new();
+        // The master opens 0x0040 with 2 credits for the slave, the slave answers
+        // with 0x0041 and 1 credit for the master
+        let request = u16_fields(&[SPSM, 0x0040, 64, 23, 2]);
+        let request = signaling(true, L2CAP_LE_CREDIT_BASED_CONNECTION_REQ, 1, &request);
+        assert!(matches!(
+            tracker.process(&request),
+            Some(L2capChannelEvent::Signaling(_))
+        ));
+        assert!(tracker.channels().is_empty());
+        let response = u16_fields(&[
+            0x0041,
+            64,
+            23,
+            1,
+            L2CAP_LE_CREDIT_BASED_CONNECTION_SUCCESSFUL,
+        ]);
+        let response = signaling(false, L2CAP_LE_CREDIT_BASED_CONNECTION_RSP, 1, &response);
+        tracker.process(&response);
+        assert_eq!(tracker.channels().len(), 2);
+
+        // An SDU of 5 bytes in two K-frames sent by the master to 0x0041
+        assert!(tracker
+            .process(&frame(true, 0x0041, &[0x05, 0x00, 0x01, 0x02]))
+            .is_none());
+        let sdu = match tracker.process(&frame(true, 0x0041, &[0x03, 0x04, 0x05])) {
+            Some(L2capChannelEvent::Sdu(sdu)) => sdu,
+            event => panic!("expected an SDU, got {:?}", event),
+        };
+        assert_eq!(sdu.payload, [0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert_eq!(sdu.spsm, SPSM);
+        let channel_0041 = |tracker: &L2capChannelTracker| {
+            tracker
+                .channels()
+                .iter()
+                .find(|channel| channel.cid == 0x0041)
+                .cloned()
+                .unwrap()
+        };
+        let channel = channel_0041(&tracker);
+        assert!(channel.direction_to_slave);
+        assert_eq!(channel.credits, 0);
+        assert_eq!(channel.credit_violations, 1);
+
+        // The slave gives 3 more credits for 0x0041
+        let credits = u16_fields(&[0x0041, 3]);
+        tracker.process(&signaling(
+            false,
+            L2CAP_FLOW_CONTROL_CREDIT_IND,
+            2,
+            &credits,
+        ));
+        assert_eq!(channel_0041(&tracker).credits, 3);
+        tracker.process(&frame(true, 0x0041, &[0x01, 0x00, 0xff]));
+        let channel = channel_0041(&tracker);
+        assert_eq!(channel.credits, 2);
+        assert_eq!(channel.credit_violations, 1);
+
+        // The slave's channel kept the credits the master didn't use
+        let channel_0040 = tracker
+            .channels()
+            .iter()
+            .find(|channel| channel.cid == 0x0040)
+            .unwrap();
+        assert!(!channel_0040.direction_to_slave);
+        assert_eq!(channel_0040.credits, 2);
+    }
+
+    #[test]
+    fn refused_connection_opens_no_channel() {
+        let mut tracker = L2capChannelTracker::new();
+        let request = u16_fields(&[SPSM, 0x0040, 64, 23, 2]);
+        tracker.process(&signaling(
+            true,
+            L2CAP_LE_CREDIT_BASED_CONNECTION_REQ,
+            3,
+            &request,
+        ));
+        // SPSM not supported
+        let response = u16_fields(&[0, 0, 0, 0, 0x0002]);
+        tracker.process(&signaling(
+            false,
+            L2CAP_LE_CREDIT_BASED_CONNECTION_RSP,
+            3,
+            &response,
+        ));
+        assert!(tracker.channels().is_empty());
+        assert!(tracker
+            .process(&frame(true, 0x0040, &[0x01, 0x00, 0xff]))
+            .is_none());
+    }
+
+    #[test]
+    fn credit_based_connection_req_needs_a_source_cid() {
+        let command = |fields: &[u16], trailing: &[u8]| {
+            let mut data = u16_fields(fields);
+            data.extend_from_slice(trailing);
+            let mut bytes = vec![L2CAP_CREDIT_BASED_CONNECTION_REQ, 5];
+            bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(&data);
+            L2capSignalingPacket::from(&bytes)
+        };
+        assert!(command(&[SPSM, 64, 64, 2], &[]).is_none());
+        assert!(command(&[SPSM, 64, 64, 2, 0x0040], &[0x41]).is_none());
+        match command(&[SPSM, 64, 64, 2, 0x0040, 0x0041], &[]) {
+            Some(L2capSignalingPacket {
+                identifier: 5,
+                command:
+                    L2capSignalingCommand::CreditBasedConnectionReq {
+                        initial_credits: 2,
+                        source_cids,
+                        ..
+                    },
+            }) => assert_eq!(source_cids, vec![0x0040, 0x0041]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
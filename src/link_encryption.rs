@@ -0,0 +1,520 @@
+use crate::{
+    ble_sniffer::{BlePacket, SnifferKey, LLID_CONTROL},
+    crypto::{aes_ccm_decrypt, ble_e, expand_key, Aes128},
+    legacy_pairing::LegacyPairingKeys,
+};
+
+// LL control PDU opcodes taking part in the encryption start procedure
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartB 2.4.2
+#[allow(unused)]
+pub const LL_ENC_REQ: u8 = 0x03;
+#[allow(unused)]
+pub const LL_ENC_RSP: u8 = 0x04;
+#[allow(unused)]
+pub const LL_START_ENC_REQ: u8 = 0x05;
+#[allow(unused)]
+pub const LL_START_ENC_RSP: u8 = 0x06;
+#[allow(unused)]
+pub const LL_PAUSE_ENC_REQ: u8 = 0x0A;
+#[allow(unused)]
+pub const LL_PAUSE_ENC_RSP: u8 = 0x0B;
+
+// How many lost packets are tolerated before the packet counters are out of sync
+#[allow(unused)]
+pub const LINK_PACKET_COUNTER_WINDOW: u64 = 4;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkDecryptStatus {
+    // Not a data PDU, CRC error or the link isn't encrypted
+    NotEncrypted,
+    // Empty PDUs are never encrypted, even on an encrypted link
+    Empty,
+    Decrypted,
+    // The firmware already decrypted it, the payload was left untouched
+    DecryptedByFirmware,
+    // None of the known keys (or counters) give a valid MIC
+    MicFailed,
+    // Encryption started but LL_ENC_REQ/RSP were missed
+    MissingKeyMaterial,
+}
+
+#[derive(Debug)]
+struct LinkEncryptionSession {
+    access_address: u32,
+    // All values little endian, the order used on air
+    skd_m: [u8; 8],
+    iv_m: [u8; 4],
+    skd_s: Option<[u8; 8]>,
+    iv_s: [u8; 4],
+    // Session keys derived from every candidate LTK, until one gives a valid MIC
+    candidates: Vec<([u8; 16], Aes128)>,
+    session_key: Option<usize>,
+    encrypting: bool,
+    // Indexed by direction_to_slave
+    next_counter: [u64; 2],
+    last_counter: [u64; 2],
+    last_sn: [Option<bool>; 2],
+}
+
+#[allow(unused)]
+impl LinkEncryptionSession {
+    // Keys added after LL_ENC_RSP are still tried as long as no session key was found
+    fn add_candidate(&mut self, long_term_key: &[u8; 16]) {
+        let skd_s = match self.skd_s {
+            Some(skd_s) => skd_s,
+            None => return,
+        };
+        if self.session_key.is_some() || self.candidates.iter().any(|(key, _)| key == long_term_key)
+        {
+            return;
+        }
+        let mut skd = [0; 16];
+        skd[..8].copy_from_slice(&self.skd_m);
+        skd[8..].copy_from_slice(&skd_s);
+        self.candidates
+            .push((*long_term_key, expand_key(&ble_e(long_term_key, &skd))));
+    }
+
+    fn iv(&self) -> [u8; 8] {
+        let mut iv = [0; 8];
+        iv[..4].copy_from_slice(&self.iv_m);
+        iv[4..].copy_from_slice(&self.iv_s);
+        iv
+    }
+}
+
+// Decrypts the data PDUs of encrypted connections on the host side.
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartE Chapter2 & PartB 5.1.3
+//
+// The session key is SK = e(LTK, SKDs || SKDm) and the IV is IVs || IVm, both taken
+// from the sniffed LL_ENC_REQ/LL_ENC_RSP. Every packet of the capture has to go
+// through process() so the per direction packet counters stay in sync.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct LinkDecryptor {
+    long_term_keys: Vec<[u8; 16]>,
+    connection_keys: Vec<(u32, [u8; 16])>,
+    sessions: Vec<LinkEncryptionSession>,
+}
+
+#[allow(unused)]
+impl LinkDecryptor {
+    pub fn new() -> LinkDecryptor {
+        LinkDecryptor {
+            long_term_keys: Vec::new(),
+            connection_keys: Vec::new(),
+            sessions: Vec::new(),
+        }
+    }
+
+    // Key tried on every connection, little endian like the crypto module
+    pub fn add_long_term_key(&mut self, long_term_key: [u8; 16]) {
+        if !self.long_term_keys.contains(&long_term_key) {
+            self.long_term_keys.push(long_term_key);
+        }
+        for session in self.sessions.iter_mut() {
+            session.add_candidate(&long_term_key);
+        }
+    }
+
+    // LTKs given on the command line are most significant byte first
+    pub fn add_sniffer_key(&mut self, key: &SnifferKey) {
+        match key {
+            SnifferKey::LegacyLongTermKey(key) | SnifferKey::ScLongTermKey(key) => {
+                let mut long_term_key = *key;
+                long_term_key.reverse();
+                self.add_long_term_key(long_term_key);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn long_term_keys(&self) -> &[[u8; 16]] {
+        &self.long_term_keys
+    }
+
+    // The STK encrypts the link right after legacy pairing, the LTK the later ones
+    pub fn add_legacy_pairing_keys(&mut self, keys: &LegacyPairingKeys) {
+        let mut add = |key: [u8; 16]| {
+            if !self.connection_keys.contains(&(keys.access_address, key)) {
+                self.connection_keys.push((keys.access_address, key));
+            }
+            if let Some(session) = self.session_mut(keys.access_address) {
+                session.add_candidate(&key);
+            }
+        };
+        add(keys.short_term_key);
+        if let Some(long_term_key) = keys.long_term_key {
+            add(long_term_key);
+        }
+    }
+
+    // Decrypts the packet in place: the payload is replaced with the plaintext
+    // (MIC removed) and mic_ok is set.
+    pub fn process(&mut self, packet: &mut BlePacket) -> LinkDecryptStatus {
+        if !packet.packet_header.crc_ok {
+            return LinkDecryptStatus::NotEncrypted;
+        }
+        let (direction_to_slave, firmware_decrypted) = match &packet.packet_header.data_header {
+            Some(header) => (header.direction_to_slave, header.encrypted && header.mic_ok),
+            None => return LinkDecryptStatus::NotEncrypted,
+        };
+        let access_address = packet.ll_layer_data.access_address;
+        let pdu = match packet.ll_layer_data.data_pdu.as_mut() {
+            Some(pdu) => pdu,
+            None => return LinkDecryptStatus::NotEncrypted,
+        };
+        let direction = direction_to_slave as usize;
+
+        let mut status = LinkDecryptStatus::NotEncrypted;
+        if let Some(session) = self
+            .sessions
+            .iter_mut()
+            .find(|session| session.access_address == access_address && session.encrypting)
+        {
+            let retransmission = session.last_sn[direction] == Some(pdu.sn);
+            session.last_sn[direction] = Some(pdu.sn);
+            if pdu.payload.is_empty() {
+                return LinkDecryptStatus::Empty;
+            }
+            let counters: Vec<u64> = if retransmission {
+                vec![session.last_counter[direction]]
+            } else {
+                let next = session.next_counter[direction];
+                (next..=next + LINK_PACKET_COUNTER_WINDOW).collect()
+            };
+            if firmware_decrypted {
+                session.last_counter[direction] = counters[0];
+                session.next_counter[direction] = counters[0] + 1;
+                status = LinkDecryptStatus::DecryptedByFirmware;
+            } else {
+                if session.skd_s.is_none() {
+                    return LinkDecryptStatus::MissingKeyMaterial;
+                }
+                // AAD is the header byte with NESN, SN and MD masked out
+                let aad = [pdu.llid | ((pdu.cte_info_present as u8) << 5)];
+                let iv = session.iv();
+                let mut found = None;
+                'search: for counter in counters.iter() {
+                    let nonce = make_nonce(*counter, direction_to_slave, &iv);
+                    if let Some(index) = session.session_key {
+                        let cipher = &session.candidates[index].1;
+                        if let Some(plaintext) = aes_ccm_decrypt(cipher, &nonce, &aad, &pdu.payload)
+                        {
+                            found = Some((*counter, plaintext, None));
+                            break;
+                        }
+                        continue;
+                    }
+                    for (index, (_, cipher)) in session.candidates.iter().enumerate() {
+                        if let Some(plaintext) = aes_ccm_decrypt(cipher, &nonce, &aad, &pdu.payload)
+                        {
+                            found = Some((*counter, plaintext, Some(index)));
+                            break 'search;
+                        }
+                    }
+                }
+                match found {
+                    Some((counter, plaintext, candidate)) => {
+                        if let Some(index) = candidate {
+                            session.session_key = Some(index);
+                        }
+                        session.last_counter[direction] = counter;
+                        session.next_counter[direction] = counter + 1;
+                        pdu.payload = plaintext;
+                        if let Some(header) = packet.packet_header.data_header.as_mut() {
+                            header.encrypted = true;
+                            header.mic_ok = true;
+                        }
+                        status = LinkDecryptStatus::Decrypted;
+                    }
+                    None => {
+                        // Still count it so a single corrupted packet doesn't desync us
+                        if !retransmission {
+                            session.last_counter[direction] = counters[0];
+                            session.next_counter[direction] = counters[0] + 1;
+                        }
+                        return LinkDecryptStatus::MicFailed;
+                    }
+                }
+            }
+        }
+
+        let pdu = match packet.ll_layer_data.data_pdu.as_ref() {
+            Some(pdu) => pdu,
+            None => return status,
+        };
+        if pdu.llid == LLID_CONTROL && !pdu.payload.is_empty() {
+            self.process_control(access_address, direction_to_slave, &pdu.payload);
+        }
+        status
+    }
+
+    fn process_control(&mut self, access_address: u32, direction_to_slave: bool, payload: &[u8]) {
+        match payload[0] {
+            LL_ENC_REQ if payload.len() >= 23 && direction_to_slave => {
+                self.sessions
+                    .retain(|session| session.access_address != access_address);
+                let mut session = LinkEncryptionSession {
+                    access_address,
+                    skd_m: [0; 8],
+                    iv_m: [0; 4],
+                    skd_s: None,
+                    iv_s: [0; 4],
+                    candidates: Vec::new(),
+                    session_key: None,
+                    encrypting: false,
+                    next_counter: [0; 2],
+                    last_counter: [0; 2],
+                    last_sn: [None; 2],
+                };
+                // Rand and EDIV (bytes 1..11) only tell the peripheral which LTK to use
+                session.skd_m.copy_from_slice(&payload[11..19]);
+                session.iv_m.copy_from_slice(&payload[19..23]);
+                self.sessions.push(session);
+            }
+            LL_ENC_RSP if payload.len() >= 13 && !direction_to_slave => {
+                let keys: Vec<[u8; 16]> = self
+                    .connection_keys
+                    .iter()
+                    .filter(|(aa, _)| *aa == access_address)
+                    .map(|(_, key)| *key)
+                    .chain(self.long_term_keys.iter().copied())
+                    .collect();
+                if let Some(session) = self.session_mut(access_address) {
+                    let mut skd_s = [0; 8];
+                    skd_s.copy_from_slice(&payload[1..9]);
+                    session.skd_s = Some(skd_s);
+                    session.iv_s.copy_from_slice(&payload[9..13]);
+                    session.candidates.clear();
+                    session.session_key = None;
+                    for key in &keys {
+                        session.add_candidate(key);
+                    }
+                }
+            }
+            // Sent unencrypted by the peripheral, everything after it is encrypted
+            LL_START_ENC_REQ if !direction_to_slave => {
+                if let Some(session) = self.session_mut(access_address) {
+                    session.encrypting = true;
+                    session.next_counter = [0; 2];
+                    session.last_counter = [0; 2];
+                    session.last_sn = [None; 2];
+                }
+            }
+            // The central's answer to the peripheral's (encrypted) LL_PAUSE_ENC_RSP is
+            // sent in the clear
+            LL_PAUSE_ENC_RSP if !direction_to_slave => {
+                if let Some(session) = self.session_mut(access_address) {
+                    session.encrypting = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn session_mut(&mut self, access_address: u32) -> Option<&mut LinkEncryptionSession> {
+        self.sessions
+            .iter_mut()
+            .find(|session| session.access_address == access_address)
+    }
+
+    // The LTK whose session key decrypted the connection, little endian
+    pub fn long_term_key(&self, access_address: u32) -> Option<[u8; 16]> {
+        let session = self
+            .sessions
+            .iter()
+            .find(|session| session.access_address == access_address)?;
+        session.session_key.map(|index| session.candidates[index].0)
+    }
+
+    pub fn clear(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+// 39 bits packet counter and the direction bit, followed by the IV
+#[allow(unused)]
+pub fn make_nonce(packet_counter: u64, direction_to_slave: bool, iv: &[u8; 8]) -> [u8; 13] {
+    let mut nonce = [0; 13];
+    nonce[..5].copy_from_slice(&packet_counter.to_le_bytes()[..5]);
+    nonce[4] &= 0x7F;
+    if direction_to_slave {
+        nonce[4] |= 0x80;
+    }
+    nonce[5..].copy_from_slice(iv);
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ble_sniffer::{
+            parse_hex_bytes, BleLLDataPdu, BlePacketHeaderData, EVENT_PACKET_DATA_PDU,
+            LLID_DATA_CONTINUATION, LLID_DATA_START,
+        },
+        crypto::aes_ccm_encrypt,
+    };
+
+    // Sample data of Core_v5.4 vol.6 PartC Chapter1, most significant byte first
+    const LONG_TERM_KEY: &str = "4C68384139F574D836BCF34E9DFB01BF";
+    const SESSION_KEY: &str = "99AD1B5226A37E3E058E3B8E27C2C666";
+    const ACCESS_ADDRESS: u32 = 0x50654A9B;
+
+    fn le_bytes<const N: usize>(hex: &str) -> [u8; N] {
+        let mut bytes: [u8; N] = parse_hex_bytes(hex).unwrap().try_into().unwrap();
+        bytes.reverse();
+        bytes
+    }
+
+    fn data_packet(direction_to_slave: bool, sn: bool, llid: u8, payload: &[u8]) -> BlePacket {
+        let mut packet = BlePacket::new();
+        packet.packet_id = EVENT_PACKET_DATA_PDU;
+        packet.packet_header.crc_ok = true;
+        packet.packet_header.data_header = Some(BlePacketHeaderData {
+            direction_to_slave,
+            encrypted: false,
+            mic_ok: false,
+        });
+        packet.ll_layer_data.access_address = ACCESS_ADDRESS;
+        packet.ll_layer_data.data_pdu = Some(BleLLDataPdu {
+            llid,
+            nesn: false,
+            sn,
+            more_data: false,
+            cte_info_present: false,
+            payload: payload.to_vec(),
+        });
+        packet
+    }
+
+    // LL_ENC_REQ, LL_ENC_RSP and LL_START_ENC_REQ of the sample, sent in the clear
+    fn start_encryption(decryptor: &mut LinkDecryptor) {
+        let mut enc_req = vec![LL_ENC_REQ];
+        enc_req.extend_from_slice(&le_bytes::<8>("ABCDEF1234567890"));
+        enc_req.extend_from_slice(&le_bytes::<2>("2474"));
+        enc_req.extend_from_slice(&le_bytes::<8>("ACBDCEDFE0F10213"));
+        enc_req.extend_from_slice(&le_bytes::<4>("BADCAB24"));
+        let mut enc_rsp = vec![LL_ENC_RSP];
+        enc_rsp.extend_from_slice(&le_bytes::<8>("0213243546576879"));
+        enc_rsp.extend_from_slice(&le_bytes::<4>("DEAFBABE"));
+        for mut packet in [
+            data_packet(true, false, LLID_CONTROL, &enc_req),
+            data_packet(false, false, LLID_CONTROL, &enc_rsp),
+            data_packet(false, true, LLID_CONTROL, &[LL_START_ENC_REQ]),
+        ] {
+            assert_eq!(
+                decryptor.process(&mut packet),
+                LinkDecryptStatus::NotEncrypted
+            );
+        }
+    }
+
+    fn payload(packet: &BlePacket) -> &[u8] {
+        &packet.ll_layer_data.data_pdu.as_ref().unwrap().payload
+    }
+
+    #[test]
+    fn derives_sample_session_key_and_nonces() {
+        let mut skd = [0; 16];
+        skd[..8].copy_from_slice(&le_bytes::<8>("ACBDCEDFE0F10213"));
+        skd[8..].copy_from_slice(&le_bytes::<8>("0213243546576879"));
+        assert_eq!(
+            ble_e(&le_bytes(LONG_TERM_KEY), &skd),
+            le_bytes::<16>(SESSION_KEY)
+        );
+        let iv = le_bytes::<8>("DEAFBABEBADCAB24");
+        assert_eq!(
+            make_nonce(0, true, &iv).to_vec(),
+            parse_hex_bytes("000000008024ABDCBABEBAAFDE").unwrap()
+        );
+        assert_eq!(
+            make_nonce(1, false, &iv).to_vec(),
+            parse_hex_bytes("010000000024ABDCBABEBAAFDE").unwrap()
+        );
+    }
+
+    #[test]
+    fn decrypts_sample_packets() {
+        let mut decryptor = LinkDecryptor::new();
+        // As given by --ltk
+        let long_term_key = parse_hex_bytes(LONG_TERM_KEY).unwrap().try_into().unwrap();
+        decryptor.add_sniffer_key(&SnifferKey::LegacyLongTermKey(long_term_key));
+        start_encryption(&mut decryptor);
+
+        // LL_START_ENC_RSP of the central (packet counter 0) then of the peripheral
+        let mut central = data_packet(true, true, LLID_CONTROL, &[0x9F, 0xCD, 0xA7, 0xF4, 0x48]);
+        assert_eq!(
+            decryptor.process(&mut central),
+            LinkDecryptStatus::Decrypted
+        );
+        assert_eq!(payload(&central), [LL_START_ENC_RSP]);
+        let header = central.packet_header.data_header.as_ref().unwrap();
+        assert!(header.encrypted && header.mic_ok);
+        let mut peripheral =
+            data_packet(false, false, LLID_CONTROL, &[0xA3, 0x4C, 0x13, 0xA4, 0x15]);
+        assert_eq!(
+            decryptor.process(&mut peripheral),
+            LinkDecryptStatus::Decrypted
+        );
+        assert_eq!(payload(&peripheral), [LL_START_ENC_RSP]);
+        assert_eq!(
+            decryptor.long_term_key(ACCESS_ADDRESS),
+            Some(le_bytes(LONG_TERM_KEY))
+        );
+
+        // Empty PDUs are sent in the clear, the next counter is 1
+        let mut empty = data_packet(true, false, LLID_DATA_CONTINUATION, &[]);
+        assert_eq!(decryptor.process(&mut empty), LinkDecryptStatus::Empty);
+        let cipher = expand_key(&le_bytes(SESSION_KEY));
+        let iv = le_bytes::<8>("DEAFBABEBADCAB24");
+        let plaintext = [0x07, 0x00, 0x04, 0x00, 0x0A, 0x03, 0x00];
+        let encrypted = aes_ccm_encrypt(
+            &cipher,
+            &make_nonce(1, false, &iv),
+            &[LLID_DATA_START],
+            &plaintext,
+        );
+        let mut packet = data_packet(false, true, LLID_DATA_START, &encrypted);
+        assert_eq!(decryptor.process(&mut packet), LinkDecryptStatus::Decrypted);
+        assert_eq!(payload(&packet), plaintext);
+    }
+
+    #[test]
+    fn unknown_key_fails_the_mic() {
+        let mut decryptor = LinkDecryptor::new();
+        decryptor.add_long_term_key([0x11; 16]);
+        start_encryption(&mut decryptor);
+        let encrypted = [0x9F, 0xCD, 0xA7, 0xF4, 0x48];
+        let mut packet = data_packet(true, true, LLID_CONTROL, &encrypted);
+        assert_eq!(decryptor.process(&mut packet), LinkDecryptStatus::MicFailed);
+        assert_eq!(payload(&packet), encrypted);
+        assert_eq!(decryptor.long_term_key(ACCESS_ADDRESS), None);
+    }
+
+    #[test]
+    fn keys_found_after_encryption_start_are_tried() {
+        let mut decryptor = LinkDecryptor::new();
+        start_encryption(&mut decryptor);
+        let mut central = data_packet(true, true, LLID_CONTROL, &[0x9F, 0xCD, 0xA7, 0xF4, 0x48]);
+        assert_eq!(
+            decryptor.process(&mut central),
+            LinkDecryptStatus::MicFailed
+        );
+        // e.g. an STK cracked while the link got encrypted
+        decryptor.add_long_term_key(le_bytes(LONG_TERM_KEY));
+        let mut peripheral =
+            data_packet(false, false, LLID_CONTROL, &[0xA3, 0x4C, 0x13, 0xA4, 0x15]);
+        assert_eq!(
+            decryptor.process(&mut peripheral),
+            LinkDecryptStatus::Decrypted
+        );
+        assert_eq!(payload(&peripheral), [LL_START_ENC_RSP]);
+        assert_eq!(
+            decryptor.long_term_key(ACCESS_ADDRESS),
+            Some(le_bytes(LONG_TERM_KEY))
+        );
+    }
+}
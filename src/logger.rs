@@ -0,0 +1,43 @@
+use std::sync::RwLock;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error,
+    Warning,
+    Info,
+    // Packets that failed to parse and the like, dropped without a hook
+    Debug,
+}
+
+type LogHook = Box<dyn Fn(LogLevel, &str) + Send + Sync>;
+
+static LOG_HOOK: RwLock<Option<LogHook>> = RwLock::new(None);
+
+// Routes the diagnostics of the sniffer thread to hook instead of stdout
+#[allow(unused)]
+pub fn set_log_hook(hook: impl Fn(LogLevel, &str) + Send + Sync + 'static) {
+    if let Ok(mut log_hook) = LOG_HOOK.write() {
+        *log_hook = Some(Box::new(hook));
+    }
+}
+
+#[allow(unused)]
+pub fn clear_log_hook() {
+    if let Ok(mut log_hook) = LOG_HOOK.write() {
+        *log_hook = None;
+    }
+}
+
+// Printed to stdout until a hook is set, except LogLevel::Debug
+pub fn log(level: LogLevel, message: &str) {
+    if let Ok(log_hook) = LOG_HOOK.read() {
+        if let Some(hook) = log_hook.as_ref() {
+            hook(level, message);
+            return;
+        }
+    }
+    if level != LogLevel::Debug {
+        println!("{}", message);
+    }
+}
@@ -1,32 +1,126 @@
 use std::{
-    sync::{atomic::AtomicBool, mpsc},
+    io::BufRead,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
     thread,
     time::Duration,
 };
 
 use nix::{libc::SIGINT, sys::signal};
 
-use crate::ble_sniffer::BlePacket;
+use crate::{
+    bd_addr::BdAddr,
+    ble_sniffer::{
+        BlePacket, SnifferConfig, SnifferControl, SnifferFollowTarget, SnifferKey,
+        SnifferScanOptions,
+    },
+    crc::BleCrcCheck,
+    gatt::GattConnections,
+};
 
+mod address_resolution;
+mod att;
+mod bd_addr;
 mod ble_sniffer;
+mod capture;
+mod crc;
+mod crypto;
+mod emulator;
+mod error;
+mod firmware;
+mod gatt;
+mod l2cap;
+mod legacy_pairing;
+mod link_encryption;
+mod logger;
+mod phy;
+mod protocol;
+mod slip;
+mod smp;
+mod transport;
 
 static STOP_REQUEST: AtomicBool = AtomicBool::new(false);
 
+struct CliOptions {
+    port: Option<String>,
+    config: SnifferConfig,
+    print_att: bool,
+    gatt_json: Option<String>,
+}
+
+const USAGE: &str = "Usage: ble_sniffer [options]
+    --port <path>               Serial path of the sniffer (e.g. /dev/ttyUSB0), detected if missing,
+                                tcp://<host>:<port> for a remote sniffer, file://<path> to replay a UART dump,
+                                emulator://[<path>] for a simulated sniffer (reporting the packets of a dump)
+    --baud-rate <rate>          460800 (default), 1000000 or 2000000
+    --adv-hop <channels>        Advertising channels to hop on, e.g. 37,38,39 or 38
+    --passkey <000000-999999>   Passkey used by legacy Passkey Entry pairing
+    --oob-tk <hex>              16 bytes OOB temporary key
+    --ltk <hex>                 16 bytes legacy long term key
+    --sc-ltk <hex>              16 bytes LE Secure Connections long term key
+    --sc-private-key <hex>      32 bytes SC private key, or \"debug\" for the debug key
+    --irk [<name>=]<hex>        16 bytes identity resolving key, can be repeated
+    --irk-file <path>           File of <name>=<hex> IRKs, one per line
+    --coded                     Also scan for advertisements on the LE Coded PHY
+    --crack-legacy-pairing      Find the passkey of sniffed LE legacy pairings to decrypt their links
+    --att                       Print the ATT PDUs of the connections, responses with their request
+    --gatt-json <path>          Write the GATT databases discovered on the connections to a JSON file
+    --help                      Show this message
+Keys are written most significant byte first.
+While running, these lines on stdin change what the sniffer does:
+    idle                        Stop scanning
+    scan                        Scan the advertising channels
+    scan-options [scan-rsp] [aux] [coded]
+                                Also report SCAN_RSP, AUX_ADV_IND or LE Coded PHY advertisements
+    follow <address>[/random] [adv-only] [legacy] [coded]
+                                Follow a device, and the connections it enters without adv-only
+    keys <key options>          Replace the keys, e.g. keys --passkey 123456 --ltk <hex>
+    hop <channels>              Advertising channels to hop on
+    stop                        Stop the sniffer";
+
 fn main() {
-    let mut serial_path = String::new();
-    println!("Please input serial path (e.g. /dev/ttyUSB0): ");
-    loop {
+    let CliOptions {
+        port,
+        config,
+        print_att,
+        gatt_json,
+    } = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(error) => {
+            println!("Error: {}", error);
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    let mut serial_path = port.unwrap_or_default();
+    if serial_path.is_empty() {
+        let sniffers = firmware::detect_sniffers(ble_sniffer::SNIFFER_BAUDRATE);
+        for (path, firmware_id) in &sniffers {
+            println!("Sniffer found on {} (firmware ID {})", path, firmware_id);
+        }
+        match sniffers.first() {
+            Some((path, _)) => serial_path = path.clone(),
+            None => println!("Please input serial path (e.g. /dev/ttyUSB0): "),
+        }
+    }
+    while serial_path.is_empty() {
         match std::io::stdin().read_line(&mut serial_path) {
             Ok(_) => {
                 serial_path = serial_path.replace("\r", "").replace("\n", "");
-                match serialport::new(serial_path.as_str(), 460800).open() {
-                    Ok(_) => {
+                match firmware::probe_port(serial_path.as_str(), ble_sniffer::SNIFFER_BAUDRATE) {
+                    Some(_) => {
                         break;
                     }
-                    Err(error) => {
-                        println!("{}", serial_path.as_str());
-                        println!("Error: {}", error.to_string());
+                    None => {
+                        println!("No sniffer answers on {}", serial_path.as_str());
                         println!("Please input serial path (e.g. /dev/ttyUSB0): ");
+                        serial_path.clear();
                     }
                 }
             }
@@ -37,88 +131,317 @@ fn main() {
         }
     }
     install_signal_hook();
-    let (this_tx, thread_rx) = mpsc::channel::<String>();
+    let mut metadata = capture::CaptureMetadata::new(&config);
+    let (this_tx, thread_rx) = mpsc::channel::<SnifferControl>();
     let (thread_tx, this_rx) = mpsc::channel::<BlePacket>();
     let thread_handle = thread::spawn(move || {
-        ble_sniffer::analyze_serial_packets(serial_path.as_str(), thread_tx, &thread_rx)
+        ble_sniffer::analyze_serial_packets(serial_path.as_str(), &config, thread_tx, &thread_rx)
     });
-    let mut recorded_macs: Vec<[u8; 6]> = Vec::new();
+    spawn_stdin_controls(this_tx.clone());
+    let mut recorded_macs: Vec<BdAddr> = Vec::new();
+    let mut recorded_identities: Vec<String> = Vec::new();
+    let mut gatt_connections = GattConnections::new();
     loop {
         thread::sleep(Duration::from_secs(1));
         recorded_macs.clear();
-        if STOP_REQUEST.load(std::sync::atomic::Ordering::SeqCst) {
-            let _ = this_tx.send(String::from("thread-stop"));
+        recorded_identities.clear();
+        if STOP_REQUEST.load(Ordering::SeqCst) {
+            let _ = this_tx.send(SnifferControl::Stop);
             break;
         }
-        loop {
-            match this_rx.try_recv() {
-                Ok(result) => {
-                    if result.valid
-                        && result.ll_layer_data.pdu_type == ble_sniffer::ADV_TYPE_ADV_NONCONN_IND
-                    {
-                        match result.ll_layer_data.non_conn_ind {
-                            Some(non_conn_ind_msg) => {
-                                if mac_is_recorded(&recorded_macs, &non_conn_ind_msg.advertising_mac)
-                                {
-                                    continue;
-                                }
-                                recorded_macs.push(non_conn_ind_msg.advertising_mac.clone());
-                                let mut device_name = String::new();
-                                match non_conn_ind_msg.complete_local_name {
-                                    Some(name) => {
-                                        device_name = name.device_name;
-                                    }
-                                    None => {}
-                                }
-                                match non_conn_ind_msg.manufacturer_data {
-                                    Some(manufacturer_data) => {
-                                        if device_name.is_empty() {
-                                            println!(
-                                                "MAC: {}\tManufacturer: 0x{:04X}",
-                                                get_mac_bytes_str(non_conn_ind_msg.advertising_mac),
-                                                manufacturer_data.company_id
-                                            );
-                                        } else {
-                                            println!(
-                                                "MAC: {}\tManufacturer: 0x{:04X}\tDeviceName: {}",
-                                                get_mac_bytes_str(non_conn_ind_msg.advertising_mac),
-                                                manufacturer_data.company_id,
-                                                device_name.as_str()
-                                            );
-                                        }
-                                    }
-                                    None => {
-                                        if device_name.is_empty() {
-                                            println!(
-                                                "MAC: {}",
-                                                get_mac_bytes_str(non_conn_ind_msg.advertising_mac)
-                                            );
-                                        } else {
-                                            println!(
-                                                "MAC: {}\t\t\t\tDeviceName: {}",
-                                                get_mac_bytes_str(non_conn_ind_msg.advertising_mac),
-                                                device_name.as_str()
-                                            );
-                                        }
-                                    }
-                                }
+        // Replays and emulators end by themselves, the packets sent before still count
+        let finished = thread_handle.is_finished();
+        while let Ok(result) = this_rx.try_recv() {
+            metadata.record(&result);
+            // Flipped bits would show up as another device
+            if result.ll_layer_data.crc_check == Some(BleCrcCheck::Corrupted) {
+                continue;
+            }
+            if let Some(record) = &result.ll_layer_data.att {
+                if print_att {
+                    println!("ATT {}", record.summary());
+                }
+                gatt_connections.process(record);
+            }
+            if result.ll_layer_data.pdu_type == ble_sniffer::ADV_TYPE_ADV_NONCONN_IND {
+                let identity = result.ll_layer_data.tx_identity;
+                if let Some(non_conn_ind_msg) = result.ll_layer_data.non_conn_ind {
+                    if recorded_macs.contains(&non_conn_ind_msg.advertising_mac) {
+                        continue;
+                    }
+                    recorded_macs.push(non_conn_ind_msg.advertising_mac);
+                    // Rotating addresses of the same device are only shown once
+                    let mut mac_str = non_conn_ind_msg.advertising_mac.to_string();
+                    if let Some(identity) = identity {
+                        if recorded_identities.contains(&identity) {
+                            continue;
+                        }
+                        mac_str = format!("{} ({})", mac_str, identity);
+                        recorded_identities.push(identity);
+                    }
+                    let mut device_name = String::new();
+                    if let Some(name) = non_conn_ind_msg.complete_local_name {
+                        device_name = name.device_name;
+                    }
+                    match non_conn_ind_msg.manufacturer_data {
+                        Some(manufacturer_data) => {
+                            if device_name.is_empty() {
+                                println!(
+                                    "MAC: {}\tManufacturer: 0x{:04X}",
+                                    mac_str, manufacturer_data.company_id
+                                );
+                            } else {
+                                println!(
+                                    "MAC: {}\tManufacturer: 0x{:04X}\tDeviceName: {}",
+                                    mac_str,
+                                    manufacturer_data.company_id,
+                                    device_name.as_str()
+                                );
+                            }
+                        }
+                        None => {
+                            if device_name.is_empty() {
+                                println!("MAC: {}", mac_str);
+                            } else {
+                                println!(
+                                    "MAC: {}\t\t\t\tDeviceName: {}",
+                                    mac_str,
+                                    device_name.as_str()
+                                );
                             }
-                            None => {}
                         }
                     }
                 }
-                Err(_) => {
-                    break;
-                }
             }
         }
+        if finished {
+            break;
+        }
     }
     match thread_handle.join() {
-        Ok(_) => {
-            println!("ble_sniffer closed");
-        }
+        Ok(Ok(())) => println!("ble_sniffer closed"),
+        Ok(Err(error)) => println!("ble_sniffer failed, {}", error),
         Err(_) => {}
     }
+    println!("Capture metadata: {}", metadata.to_json());
+    if let Some(path) = gatt_json {
+        match std::fs::write(&path, gatt_connections.to_json()) {
+            Ok(()) => println!("GATT databases written to {}", path),
+            Err(error) => println!("Cannot write {}, {}", path, error),
+        }
+    }
+}
+
+// Returns None when the usage should be printed
+fn parse_args(args: Vec<String>) -> Result<Option<CliOptions>, String> {
+    let mut port: Option<String> = None;
+    let mut config = SnifferConfig::new();
+    let mut print_att = false;
+    let mut gatt_json: Option<String> = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        if arg == "--coded" {
+            config.scan_options.scan_coded = true;
+            continue;
+        }
+        if arg == "--crack-legacy-pairing" {
+            config.crack_legacy_pairing = true;
+            continue;
+        }
+        if arg == "--att" {
+            print_att = true;
+            continue;
+        }
+        let value = match args.next() {
+            Some(value) => value,
+            None => return Err(format!("Missing value of {}", arg)),
+        };
+        match arg.as_str() {
+            "--port" => port = Some(value),
+            "--gatt-json" => gatt_json = Some(value),
+            "--baud-rate" => match value.parse::<u32>() {
+                Ok(baud_rate) if firmware::SNIFFER_BAUD_RATES.contains(&baud_rate) => {
+                    config.baud_rate = baud_rate;
+                }
+                _ => return Err(format!("Unsupported baud rate {}", value)),
+            },
+            "--adv-hop" => match ble_sniffer::parse_adv_hop_seq(&value) {
+                Some(channels) => config.adv_channel_hop_sequence = channels,
+                None => return Err(format!("Invalid advertising hop sequence {}", value)),
+            },
+            "--passkey" => match value.parse::<u32>() {
+                Ok(passkey) if passkey <= 999999 => {
+                    config.keys.push(SnifferKey::Passkey(passkey));
+                }
+                _ => return Err(format!("Invalid passkey {}", value)),
+            },
+            "--oob-tk" => {
+                config
+                    .keys
+                    .push(SnifferKey::OobTemporaryKey(parse_key(&arg, &value)?));
+            }
+            "--ltk" => {
+                config
+                    .keys
+                    .push(SnifferKey::LegacyLongTermKey(parse_key(&arg, &value)?));
+            }
+            "--sc-ltk" => {
+                config
+                    .keys
+                    .push(SnifferKey::ScLongTermKey(parse_key(&arg, &value)?));
+            }
+            "--sc-private-key" => {
+                if value == "debug" {
+                    config
+                        .keys
+                        .push(SnifferKey::ScPrivateKey(ble_sniffer::SC_DEBUG_PRIVATE_KEY));
+                } else {
+                    config
+                        .keys
+                        .push(SnifferKey::ScPrivateKey(parse_key(&arg, &value)?));
+                }
+            }
+            "--irk" => match address_resolution::parse_named_irk(&value) {
+                Some(named_irk) => {
+                    config
+                        .keys
+                        .push(SnifferKey::IdentityResolvingKey(named_irk.irk));
+                    config.irk_store.add(&named_irk.name, named_irk.irk);
+                }
+                None => return Err(format!("{} expects [<name>=]<16 bytes hex>", arg)),
+            },
+            "--irk-file" => {
+                let mut irk_store = address_resolution::IrkStore::new();
+                irk_store.load(&value)?;
+                for named_irk in irk_store.irks() {
+                    config
+                        .keys
+                        .push(SnifferKey::IdentityResolvingKey(named_irk.irk));
+                    config.irk_store.add(&named_irk.name, named_irk.irk);
+                }
+            }
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    Ok(Some(CliOptions {
+        port,
+        config,
+        print_att,
+        gatt_json,
+    }))
+}
+
+// Reads the commands listed in USAGE from stdin until it is closed
+fn spawn_stdin_controls(tx: mpsc::Sender<SnifferControl>) {
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match parse_control(&line) {
+                // Goes through the main loop, which also prints the metadata
+                Ok(SnifferControl::Stop) => STOP_REQUEST.store(true, Ordering::SeqCst),
+                Ok(control) => {
+                    if tx.send(control).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => println!("Error: {}", error),
+            }
+        }
+    });
+}
+
+fn parse_control(line: &str) -> Result<SnifferControl, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let words: Vec<String> = words.map(|word| word.to_string()).collect();
+    match command {
+        "idle" => Ok(SnifferControl::GoIdle),
+        "scan" => Ok(SnifferControl::Scan),
+        "scan-options" => {
+            let mut scan_options = SnifferScanOptions::new();
+            for word in &words {
+                match word.as_str() {
+                    "scan-rsp" => scan_options.find_scan_rsp = true,
+                    "aux" => scan_options.find_aux = true,
+                    "coded" => scan_options.scan_coded = true,
+                    _ => return Err(format!("Unknown scan option {}", word)),
+                }
+            }
+            Ok(SnifferControl::SetScanOptions(scan_options))
+        }
+        "follow" => {
+            let address = match words.first() {
+                Some(address) => address.parse::<BdAddr>()?,
+                None => return Err("follow expects an address".to_string()),
+            };
+            let mut target = SnifferFollowTarget {
+                address,
+                only_advertisements: false,
+                only_legacy: false,
+                coded: false,
+            };
+            for word in &words[1..] {
+                match word.as_str() {
+                    "adv-only" => target.only_advertisements = true,
+                    "legacy" => target.only_legacy = true,
+                    "coded" => target.coded = true,
+                    _ => return Err(format!("Unknown follow option {}", word)),
+                }
+            }
+            Ok(SnifferControl::Follow(target))
+        }
+        "keys" => {
+            const KEY_OPTIONS: [&str; 7] = [
+                "--passkey",
+                "--oob-tk",
+                "--ltk",
+                "--sc-ltk",
+                "--sc-private-key",
+                "--irk",
+                "--irk-file",
+            ];
+            for option in words.iter().step_by(2) {
+                if !KEY_OPTIONS.contains(&option.as_str()) {
+                    return Err(format!("{} isn't a key option", option));
+                }
+            }
+            match parse_args(words)? {
+                Some(options) => Ok(SnifferControl::SetKeys(options.config.keys)),
+                None => Err("keys expects key options".to_string()),
+            }
+        }
+        "hop" => match words
+            .first()
+            .and_then(|text| ble_sniffer::parse_adv_hop_seq(text))
+        {
+            Some(channels) => Ok(SnifferControl::SetAdvHopSequence(channels)),
+            None => Err(format!(
+                "Invalid advertising hop sequence {}",
+                words.join(" ")
+            )),
+        },
+        "stop" => Ok(SnifferControl::Stop),
+        _ => Err(format!("Unknown command {}", command)),
+    }
+}
+
+fn parse_key<const N: usize>(arg: &str, value: &str) -> Result<[u8; N], String> {
+    match ble_sniffer::parse_hex_bytes(value) {
+        Some(bytes) => match bytes.try_into() {
+            Ok(key) => Ok(key),
+            Err(_) => Err(format!("{} expects {} bytes", arg, N)),
+        },
+        None => Err(format!("{} expects a hex string", arg)),
+    }
 }
 
 fn install_signal_hook() {
@@ -141,31 +464,7 @@ fn install_signal_hook() {
 
 extern "C" fn signal_handler(signal: i32) {
     if signal == SIGINT {
-        STOP_REQUEST.fetch_or(true, std::sync::atomic::Ordering::SeqCst);
-        println!("");
-    }
-}
-
-fn get_mac_bytes_str(mac_bytes: [u8; 6]) -> String {
-    format!(
-        "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-        mac_bytes[0], mac_bytes[1], mac_bytes[2], mac_bytes[3], mac_bytes[4], mac_bytes[5]
-    )
-}
-
-fn mac_is_recorded(recorded_macs: &Vec<[u8; 6]>, check_mac: &[u8; 6]) -> bool {
-    let mut byte_index: usize;
-    for recorded_mac in recorded_macs {
-        byte_index = 0;
-        for mac_byte in recorded_mac {
-            if *mac_byte != check_mac[byte_index] {
-                break;
-            }
-            byte_index += 1;
-        }
-        if byte_index == 6 {
-            return true;
-        }
+        STOP_REQUEST.fetch_or(true, Ordering::SeqCst);
+        println!();
     }
-    false
 }
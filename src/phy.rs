@@ -0,0 +1,61 @@
+use crate::ble_sniffer::{PHY_1M, PHY_2M, PHY_CODED, PHY_CODED_CI_S2, PHY_CODED_CI_S8};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.6 PartB 2.1 & 2.2
+// Access address, header and CRC around the PDU payload of the uncoded PHYs
+const UNCODED_OVERHEAD_BYTES: usize = 4 + 2 + 3;
+// Preamble (80us), access address (256us), CI (16us) and TERM1 (24us) are always S=8
+const CODED_FEC_BLOCK1_US: u32 = 80 + 256 + 16 + 24;
+// Header and CRC bits of FEC block 2, followed by 3 bits of TERM2
+const CODED_BLOCK2_OVERHEAD_BITS: u32 = 16 + 24 + 3;
+
+// Bits per second of the PDU, coding_indicator is needed for the coded PHY
+#[allow(unused)]
+pub fn phy_bit_rate(phy: u8, coding_indicator: Option<u8>) -> Option<u32> {
+    match (phy, coding_indicator) {
+        (PHY_1M, _) => Some(1_000_000),
+        (PHY_2M, _) => Some(2_000_000),
+        (PHY_CODED, Some(PHY_CODED_CI_S8)) => Some(125_000),
+        (PHY_CODED, Some(PHY_CODED_CI_S2)) => Some(500_000),
+        _ => None,
+    }
+}
+
+// Symbols per bit of the coded PHY
+fn coded_symbols(coding_indicator: Option<u8>) -> Option<u32> {
+    match coding_indicator {
+        Some(PHY_CODED_CI_S8) => Some(8),
+        Some(PHY_CODED_CI_S2) => Some(2),
+        _ => None,
+    }
+}
+
+// Airtime of a packet whose PDU payload (MIC included) is payload_len bytes long
+#[allow(unused)]
+pub fn pdu_airtime_us(phy: u8, coding_indicator: Option<u8>, payload_len: usize) -> Option<u32> {
+    match phy {
+        // 1 byte preamble, 8us per byte
+        PHY_1M => Some(((1 + UNCODED_OVERHEAD_BYTES + payload_len) * 8) as u32),
+        // 2 bytes preamble, 4us per byte
+        PHY_2M => Some(((2 + UNCODED_OVERHEAD_BYTES + payload_len) * 4) as u32),
+        PHY_CODED => {
+            let symbols = coded_symbols(coding_indicator)?;
+            Some(
+                CODED_FEC_BLOCK1_US
+                    + (CODED_BLOCK2_OVERHEAD_BITS + payload_len as u32 * 8) * symbols,
+            )
+        }
+        _ => None,
+    }
+}
+
+#[allow(unused)]
+pub fn phy_name(phy: u8, coding_indicator: Option<u8>) -> &'static str {
+    match (phy, coding_indicator) {
+        (PHY_1M, _) => "LE 1M",
+        (PHY_2M, _) => "LE 2M",
+        (PHY_CODED, Some(PHY_CODED_CI_S8)) => "LE Coded S=8",
+        (PHY_CODED, Some(PHY_CODED_CI_S2)) => "LE Coded S=2",
+        (PHY_CODED, _) => "LE Coded",
+        _ => "Unknown",
+    }
+}
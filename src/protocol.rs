@@ -0,0 +1,528 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    bd_addr::BdAddr,
+    ble_sniffer::{
+        make_send_bytes, BlePacket, SnifferConfig, SnifferFollowTarget, SnifferKey, SnifferMode,
+        SnifferScanOptions, ADV_CHANNELS, EVENT_CONNECT, EVENT_DISCONNECT, EVENT_FOLLOW,
+        EVENT_PACKET_ADV_PDU, EVENT_PACKET_DATA_PDU, GO_IDLE, HEADER_LENGTH, PING_REQ, PING_RESP,
+        REQ_FOLLOW, REQ_SCAN_CONT, REQ_TIMESTAMP, REQ_VERSION, RESP_TIMESTAMP, RESP_VERSION,
+        SET_ADV_CHANNEL_HOP_SEQ, SET_IDENTITY_RESOLVING_KEY, SET_LEGACY_LONG_TERM_KEY,
+        SET_PRIVATE_KEY, SET_SC_LONG_TERM_KEY, SET_TEMPORARY_KEY, SWITCH_BAUD_RATE_REQ,
+        SWITCH_BAUD_RATE_RESP,
+    },
+    crypto::passkey_to_temporary_key,
+    error::ParseError,
+    firmware::{
+        parse_ping_response, parse_switch_baud_rate_response, parse_timestamp_response,
+        SnifferFirmwareVersion, FIRMWARE_VERSION_TIMEOUT, PING_TIMEOUT,
+    },
+};
+
+// Commands sent by the host to the firmware
+// Reference: sniffer_uart_protocol.pdf
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnifferCommand {
+    // REQ_FOLLOW
+    Follow(SnifferFollowTarget),
+    // REQ_SCAN_CONT
+    Scan(SnifferScanOptions),
+    GoIdle,
+    // Keys are most significant byte first, the order the firmware expects
+    SetTemporaryKey([u8; 16]),
+    SetLegacyLongTermKey([u8; 16]),
+    SetScLongTermKey([u8; 16]),
+    SetPrivateKey([u8; 32]),
+    SetIdentityResolvingKey([u8; 16]),
+    // 1 to 3 distinct channels among 37, 38 and 39, see parse_adv_hop_seq
+    SetAdvChannelHopSequence(Vec<u8>),
+    Ping,
+    RequestVersion,
+    RequestTimestamp,
+    SwitchBaudRate(u32),
+}
+
+// What the firmware sends to the host
+#[allow(unused)]
+#[derive(Debug)]
+pub enum SnifferEvent {
+    // EVENT_PACKET_ADV_PDU and EVENT_PACKET_DATA_PDU, decode fails with the
+    // ParseError of packets BlePacket::from rejects
+    Packet(Box<BlePacket>),
+    // The firmware follows the requested device, then its connection
+    Follow,
+    Connect,
+    Disconnect,
+    // PING_RESP carries the firmware ID
+    PingResponse(u16),
+    Version(SnifferFirmwareVersion),
+    // Firmware clock in microseconds, see SnifferClock
+    Timestamp(u32),
+    // The rate the firmware switches to
+    SwitchBaudRate(u32),
+    Unknown { packet_id: u8, payload: Vec<u8> },
+}
+
+#[allow(unused)]
+impl SnifferCommand {
+    // The command putting the firmware in config.mode
+    pub fn from_mode(config: &SnifferConfig) -> SnifferCommand {
+        match &config.mode {
+            SnifferMode::Idle => SnifferCommand::GoIdle,
+            SnifferMode::Scan => SnifferCommand::Scan(config.scan_options),
+            SnifferMode::Follow(target) => SnifferCommand::Follow(target.clone()),
+        }
+    }
+
+    pub fn from_key(key: &SnifferKey) -> SnifferCommand {
+        match key {
+            SnifferKey::Passkey(passkey) => {
+                SnifferCommand::from_temporary_key(&passkey_to_temporary_key(*passkey))
+            }
+            SnifferKey::OobTemporaryKey(key) => SnifferCommand::SetTemporaryKey(*key),
+            SnifferKey::LegacyLongTermKey(key) => SnifferCommand::SetLegacyLongTermKey(*key),
+            SnifferKey::ScLongTermKey(key) => SnifferCommand::SetScLongTermKey(*key),
+            SnifferKey::ScPrivateKey(key) => SnifferCommand::SetPrivateKey(*key),
+            SnifferKey::IdentityResolvingKey(key) => SnifferCommand::SetIdentityResolvingKey(*key),
+        }
+    }
+
+    // Without a passkey or OOB TK, the Just Works TK is pushed first
+    pub fn from_keys(keys: &[SnifferKey]) -> Vec<SnifferCommand> {
+        let mut commands = Vec::new();
+        if !keys
+            .iter()
+            .any(|key| matches!(key, SnifferKey::Passkey(_) | SnifferKey::OobTemporaryKey(_)))
+        {
+            commands.push(SnifferCommand::from_key(&SnifferKey::Passkey(0)));
+        }
+        commands.extend(keys.iter().map(SnifferCommand::from_key));
+        commands
+    }
+
+    // temporary_key is little endian like the crypto module, e.g. the TK found by
+    // LegacyPairingCracker
+    pub fn from_temporary_key(temporary_key: &[u8; 16]) -> SnifferCommand {
+        let mut key = *temporary_key;
+        key.reverse();
+        SnifferCommand::SetTemporaryKey(key)
+    }
+
+    pub fn packet_id(&self) -> u8 {
+        match self {
+            SnifferCommand::Follow(_) => REQ_FOLLOW,
+            SnifferCommand::Scan(_) => REQ_SCAN_CONT,
+            SnifferCommand::GoIdle => GO_IDLE,
+            SnifferCommand::SetTemporaryKey(_) => SET_TEMPORARY_KEY,
+            SnifferCommand::SetLegacyLongTermKey(_) => SET_LEGACY_LONG_TERM_KEY,
+            SnifferCommand::SetScLongTermKey(_) => SET_SC_LONG_TERM_KEY,
+            SnifferCommand::SetPrivateKey(_) => SET_PRIVATE_KEY,
+            SnifferCommand::SetIdentityResolvingKey(_) => SET_IDENTITY_RESOLVING_KEY,
+            SnifferCommand::SetAdvChannelHopSequence(_) => SET_ADV_CHANNEL_HOP_SEQ,
+            SnifferCommand::Ping => PING_REQ,
+            SnifferCommand::RequestVersion => REQ_VERSION,
+            SnifferCommand::RequestTimestamp => REQ_TIMESTAMP,
+            SnifferCommand::SwitchBaudRate(_) => SWITCH_BAUD_RATE_REQ,
+        }
+    }
+
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            // The address goes most significant byte first, followed by its type
+            SnifferCommand::Follow(target) => {
+                let mut payload = target.address.bytes.to_vec();
+                payload.push(target.address.is_random() as u8);
+                let mut flags: u8 = 0;
+                if target.only_advertisements {
+                    flags = 1;
+                }
+                if target.only_legacy {
+                    flags |= 1 << 1;
+                }
+                if target.coded {
+                    flags |= 1 << 2;
+                }
+                payload.push(flags);
+                payload
+            }
+            SnifferCommand::Scan(scan_options) => {
+                let mut flags: u8 = 0;
+                if scan_options.find_scan_rsp {
+                    flags = 1;
+                }
+                if scan_options.find_aux {
+                    flags |= 1 << 1;
+                }
+                if scan_options.scan_coded {
+                    flags |= 1 << 2;
+                }
+                vec![flags]
+            }
+            SnifferCommand::SetTemporaryKey(key)
+            | SnifferCommand::SetLegacyLongTermKey(key)
+            | SnifferCommand::SetScLongTermKey(key)
+            | SnifferCommand::SetIdentityResolvingKey(key) => key.to_vec(),
+            SnifferCommand::SetPrivateKey(key) => key.to_vec(),
+            // The firmware always reads 3 channels after the length, the unused ones
+            // are padded with 37 like the nRF Sniffer host does
+            SnifferCommand::SetAdvChannelHopSequence(channels) => {
+                let mut payload = vec![channels.len() as u8];
+                payload.extend_from_slice(channels);
+                payload.resize(1 + ADV_CHANNELS.len(), ADV_CHANNELS[0]);
+                payload
+            }
+            SnifferCommand::SwitchBaudRate(baud_rate) => baud_rate.to_le_bytes().to_vec(),
+            SnifferCommand::GoIdle
+            | SnifferCommand::Ping
+            | SnifferCommand::RequestVersion
+            | SnifferCommand::RequestTimestamp => Vec::new(),
+        }
+    }
+
+    // The SLIP encoded UART packet, see SnifferCommander::encode for the counter
+    pub fn encode(&self, packet_counter: u16) -> Vec<u8> {
+        make_send_bytes(self.packet_id(), &self.payload(), packet_counter)
+    }
+
+    // bytes is a whole unescaped UART packet, as the firmware sees it
+    pub fn decode(bytes: &[u8]) -> Option<SnifferCommand> {
+        let header_length = HEADER_LENGTH as usize;
+        if bytes.len() < header_length {
+            return None;
+        }
+        let payload = &bytes[header_length..];
+        let command = match bytes[5] {
+            REQ_FOLLOW => {
+                if payload.len() < 7 {
+                    return None;
+                }
+                let flags = payload.get(7).copied().unwrap_or(0);
+                let mut address = [0; 6];
+                address.copy_from_slice(&payload[..6]);
+                SnifferCommand::Follow(SnifferFollowTarget {
+                    address: BdAddr::new(address, payload[6] == 1),
+                    only_advertisements: flags & 1 == 1,
+                    only_legacy: (flags >> 1) & 1 == 1,
+                    coded: (flags >> 2) & 1 == 1,
+                })
+            }
+            REQ_SCAN_CONT => {
+                let flags = payload.first().copied().unwrap_or(0);
+                SnifferCommand::Scan(SnifferScanOptions {
+                    find_scan_rsp: flags & 1 == 1,
+                    find_aux: (flags >> 1) & 1 == 1,
+                    scan_coded: (flags >> 2) & 1 == 1,
+                })
+            }
+            GO_IDLE => SnifferCommand::GoIdle,
+            SET_TEMPORARY_KEY => SnifferCommand::SetTemporaryKey(payload.try_into().ok()?),
+            SET_LEGACY_LONG_TERM_KEY => {
+                SnifferCommand::SetLegacyLongTermKey(payload.try_into().ok()?)
+            }
+            SET_SC_LONG_TERM_KEY => SnifferCommand::SetScLongTermKey(payload.try_into().ok()?),
+            SET_PRIVATE_KEY => SnifferCommand::SetPrivateKey(payload.try_into().ok()?),
+            SET_IDENTITY_RESOLVING_KEY => {
+                SnifferCommand::SetIdentityResolvingKey(payload.try_into().ok()?)
+            }
+            SET_ADV_CHANNEL_HOP_SEQ => {
+                let length = *payload.first()? as usize;
+                if payload.len() < 1 + length {
+                    return None;
+                }
+                SnifferCommand::SetAdvChannelHopSequence(payload[1..1 + length].to_vec())
+            }
+            PING_REQ => SnifferCommand::Ping,
+            REQ_VERSION => SnifferCommand::RequestVersion,
+            REQ_TIMESTAMP => SnifferCommand::RequestTimestamp,
+            SWITCH_BAUD_RATE_REQ => SnifferCommand::SwitchBaudRate(u32::from_le_bytes(
+                payload.get(..4)?.try_into().ok()?,
+            )),
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    // Packet id of the answer, None when the firmware doesn't answer the command
+    pub fn response_id(&self) -> Option<u8> {
+        match self {
+            SnifferCommand::Ping => Some(PING_RESP),
+            SnifferCommand::RequestVersion => Some(RESP_VERSION),
+            SnifferCommand::RequestTimestamp => Some(RESP_TIMESTAMP),
+            SnifferCommand::SwitchBaudRate(_) => Some(SWITCH_BAUD_RATE_RESP),
+            _ => None,
+        }
+    }
+
+    pub fn response_timeout(&self) -> Option<Duration> {
+        match self {
+            SnifferCommand::RequestVersion => Some(FIRMWARE_VERSION_TIMEOUT),
+            _ => self.response_id().map(|_| PING_TIMEOUT),
+        }
+    }
+}
+
+#[allow(unused)]
+impl SnifferEvent {
+    // bytes is a whole unescaped UART packet. Only packets need a protocol version
+    // BlePacket::from can parse, so RESP_VERSION of newer firmwares is still decoded.
+    pub fn decode(bytes: &[u8]) -> Result<SnifferEvent, ParseError> {
+        let header_length = HEADER_LENGTH as usize;
+        if bytes.len() < header_length {
+            return Err(ParseError::Truncated {
+                offset: 0,
+                needed: header_length,
+            });
+        }
+        let packet_id = bytes[5];
+        let malformed = ParseError::MalformedResponse(packet_id);
+        let event = match packet_id {
+            EVENT_PACKET_ADV_PDU | EVENT_PACKET_DATA_PDU => {
+                SnifferEvent::Packet(Box::new(BlePacket::from(bytes)?))
+            }
+            EVENT_FOLLOW => SnifferEvent::Follow,
+            EVENT_CONNECT => SnifferEvent::Connect,
+            EVENT_DISCONNECT => SnifferEvent::Disconnect,
+            PING_RESP => SnifferEvent::PingResponse(parse_ping_response(bytes).ok_or(malformed)?),
+            RESP_VERSION => {
+                SnifferEvent::Version(SnifferFirmwareVersion::from(bytes).ok_or(malformed)?)
+            }
+            RESP_TIMESTAMP => {
+                SnifferEvent::Timestamp(parse_timestamp_response(bytes).ok_or(malformed)?)
+            }
+            SWITCH_BAUD_RATE_RESP => SnifferEvent::SwitchBaudRate(
+                parse_switch_baud_rate_response(bytes).ok_or(malformed)?,
+            ),
+            packet_id => SnifferEvent::Unknown {
+                packet_id,
+                payload: bytes[header_length..].to_vec(),
+            },
+        };
+        Ok(event)
+    }
+
+    pub fn packet_id(&self) -> u8 {
+        match self {
+            SnifferEvent::Packet(packet) => packet.packet_id,
+            SnifferEvent::Follow => EVENT_FOLLOW,
+            SnifferEvent::Connect => EVENT_CONNECT,
+            SnifferEvent::Disconnect => EVENT_DISCONNECT,
+            SnifferEvent::PingResponse(_) => PING_RESP,
+            SnifferEvent::Version(_) => RESP_VERSION,
+            SnifferEvent::Timestamp(_) => RESP_TIMESTAMP,
+            SnifferEvent::SwitchBaudRate(_) => SWITCH_BAUD_RATE_RESP,
+            SnifferEvent::Unknown { packet_id, .. } => *packet_id,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingRequest {
+    command: SnifferCommand,
+    deadline: Instant,
+}
+
+// Numbers the commands sent to a sniffer and matches its responses with the
+// requests waiting for them. The firmware doesn't echo the packet counter, so
+// responses go to the oldest request expecting their packet id.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct SnifferCommander {
+    packet_counter: u16,
+    pending: Vec<PendingRequest>,
+}
+
+#[allow(unused)]
+impl SnifferCommander {
+    pub fn new() -> SnifferCommander {
+        SnifferCommander {
+            packet_counter: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    // Encodes the command with the next packet counter, commands expecting a
+    // response are remembered until it comes or times out
+    pub fn encode(&mut self, command: SnifferCommand) -> Vec<u8> {
+        let bytes = command.encode(self.packet_counter);
+        self.packet_counter = self.packet_counter.wrapping_add(1);
+        if let Some(timeout) = command.response_timeout() {
+            self.pending.push(PendingRequest {
+                command,
+                deadline: Instant::now() + timeout,
+            });
+        }
+        bytes
+    }
+
+    // The request answered by event, None for unsolicited events
+    pub fn correlate(&mut self, event: &SnifferEvent) -> Option<SnifferCommand> {
+        let packet_id = event.packet_id();
+        let index = self
+            .pending
+            .iter()
+            .position(|request| request.command.response_id() == Some(packet_id))?;
+        Some(self.pending.remove(index).command)
+    }
+
+    // Requests left unanswered past their timeout, they aren't waited for anymore
+    pub fn expired(&mut self) -> Vec<SnifferCommand> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut index = 0;
+        while index < self.pending.len() {
+            if self.pending[index].deadline <= now {
+                expired.push(self.pending.remove(index).command);
+            } else {
+                index += 1;
+            }
+        }
+        expired
+    }
+
+    // Forgets the pending requests, e.g. after reopening the port
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{ble_sniffer::UartPacketHeader, slip::SlipDecoder};
+
+    fn unescape(bytes: &[u8]) -> Vec<u8> {
+        let mut frames = SlipDecoder::new().push(bytes);
+        assert_eq!(frames.len(), 1);
+        frames.remove(0).unwrap()
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            SnifferCommand::Follow(SnifferFollowTarget {
+                address: "C0:11:22:33:44:55/random".parse().unwrap(),
+                only_advertisements: false,
+                only_legacy: true,
+                coded: true,
+            }),
+            SnifferCommand::Scan(SnifferScanOptions {
+                find_scan_rsp: true,
+                find_aux: false,
+                scan_coded: true,
+            }),
+            SnifferCommand::GoIdle,
+            SnifferCommand::SetTemporaryKey([1; 16]),
+            SnifferCommand::SetLegacyLongTermKey([2; 16]),
+            SnifferCommand::SetScLongTermKey([3; 16]),
+            SnifferCommand::SetPrivateKey([4; 32]),
+            SnifferCommand::SetIdentityResolvingKey([5; 16]),
+            SnifferCommand::SetAdvChannelHopSequence(vec![39, 37, 38]),
+            SnifferCommand::Ping,
+            SnifferCommand::RequestVersion,
+            SnifferCommand::RequestTimestamp,
+            SnifferCommand::SwitchBaudRate(2000000),
+        ];
+        for command in commands {
+            let bytes = unescape(&command.encode(0x1234));
+            assert_eq!(bytes[5], command.packet_id());
+            assert_eq!(SnifferCommand::decode(&bytes), Some(command));
+        }
+    }
+
+    #[test]
+    fn hop_sequence_is_padded_to_three_channels() {
+        let command = SnifferCommand::SetAdvChannelHopSequence(vec![39]);
+        assert_eq!(command.payload(), [1, 39, 37, 37]);
+        let bytes = unescape(&command.encode(7));
+        assert_eq!(
+            bytes,
+            [
+                HEADER_LENGTH,
+                4,
+                1,
+                7,
+                0,
+                SET_ADV_CHANNEL_HOP_SEQ,
+                1,
+                39,
+                37,
+                37
+            ]
+        );
+        assert_eq!(SnifferCommand::decode(&bytes), Some(command));
+        assert_eq!(
+            SnifferCommand::SetAdvChannelHopSequence(vec![38, 39]).payload(),
+            [2, 38, 39, 37]
+        );
+        assert_eq!(
+            SnifferCommand::SetAdvChannelHopSequence(vec![39, 38, 37]).payload(),
+            [3, 39, 38, 37]
+        );
+    }
+
+    #[test]
+    fn commander_numbers_commands() {
+        let mut commander = SnifferCommander::new();
+        let counters: Vec<u16> = [
+            SnifferCommand::Ping,
+            SnifferCommand::GoIdle,
+            SnifferCommand::Ping,
+        ]
+        .into_iter()
+        .map(|command| {
+            let bytes = unescape(&commander.encode(command));
+            UartPacketHeader::from(&bytes).unwrap().packet_counter
+        })
+        .collect();
+        assert_eq!(counters, [0, 1, 2]);
+    }
+
+    #[test]
+    fn commander_correlates_responses_by_packet_id() {
+        let mut commander = SnifferCommander::new();
+        commander.encode(SnifferCommand::Ping);
+        commander.encode(SnifferCommand::GoIdle);
+        commander.encode(SnifferCommand::RequestTimestamp);
+        commander.encode(SnifferCommand::SwitchBaudRate(1000000));
+        assert_eq!(
+            commander.correlate(&SnifferEvent::Timestamp(1)),
+            Some(SnifferCommand::RequestTimestamp)
+        );
+        assert_eq!(commander.correlate(&SnifferEvent::Timestamp(2)), None);
+        assert_eq!(commander.correlate(&SnifferEvent::Follow), None);
+        assert_eq!(
+            commander.correlate(&SnifferEvent::PingResponse(0x1234)),
+            Some(SnifferCommand::Ping)
+        );
+        commander.clear();
+        assert_eq!(
+            commander.correlate(&SnifferEvent::SwitchBaudRate(1000000)),
+            None
+        );
+    }
+
+    #[test]
+    fn unanswered_requests_expire() {
+        let mut commander = SnifferCommander::new();
+        commander.encode(SnifferCommand::Ping);
+        commander.encode(SnifferCommand::RequestVersion);
+        assert!(commander.expired().is_empty());
+        thread::sleep(PING_TIMEOUT);
+        // REQ_VERSION waits longer
+        assert_eq!(commander.expired(), vec![SnifferCommand::Ping]);
+        assert!(commander.expired().is_empty());
+        assert_eq!(
+            commander.correlate(&SnifferEvent::Version(SnifferFirmwareVersion {
+                text: "4.1.1".to_string(),
+                major: 4,
+                minor: 1,
+                patch: 1,
+                protocol_version: 3,
+            })),
+            Some(SnifferCommand::RequestVersion)
+        );
+    }
+}
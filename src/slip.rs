@@ -0,0 +1,199 @@
+use crate::ble_sniffer::{
+    HEADER_LENGTH, SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_ESC_START, SLIP_START,
+};
+
+// The longest UART packet: the header and a 16 bits payload length (protocol version 2+)
+#[allow(unused)]
+pub const SLIP_MAX_FRAME_LENGTH: usize = HEADER_LENGTH as usize + u16::MAX as usize;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlipError {
+    // SLIP_ESC followed by another byte than SLIP_ESC_START/END/ESC
+    BadEscape(u8),
+    // No SLIP_END within the maximum frame length
+    Oversize,
+    // SLIP_START before the SLIP_END of the current frame, its end was lost
+    UnexpectedStart,
+}
+
+// Unescapes the SLIP framing of the UART protocol (see sniffer_uart_protocol.pdf).
+// Bytes can be pushed in chunks of any size, a frame split across chunks is kept
+// until its end comes. Frames with an error are dropped, the decoder then waits
+// for the next SLIP_START.
+#[allow(unused)]
+#[derive(Debug)]
+pub struct SlipDecoder {
+    frame: Vec<u8>,
+    in_frame: bool,
+    escaped: bool,
+    max_frame_length: usize,
+}
+
+#[allow(unused)]
+impl SlipDecoder {
+    pub fn new() -> SlipDecoder {
+        SlipDecoder::with_max_frame_length(SLIP_MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_frame_length(max_frame_length: usize) -> SlipDecoder {
+        SlipDecoder {
+            frame: Vec::new(),
+            in_frame: false,
+            escaped: false,
+            max_frame_length,
+        }
+    }
+
+    // Complete frames (unescaped, without SLIP_START/END) and framing errors, in order
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Result<Vec<u8>, SlipError>> {
+        bytes.iter().filter_map(|b| self.push_byte(*b)).collect()
+    }
+
+    pub fn push_byte(&mut self, b: u8) -> Option<Result<Vec<u8>, SlipError>> {
+        if !self.in_frame {
+            // Anything between frames is noise, e.g. the tail of a dropped frame
+            if b == SLIP_START {
+                self.start_frame();
+            }
+            return None;
+        }
+        if self.escaped {
+            self.escaped = false;
+            let unescaped = match b {
+                SLIP_ESC_START => SLIP_START,
+                SLIP_ESC_END => SLIP_END,
+                SLIP_ESC_ESC => SLIP_ESC,
+                _ => return Some(Err(self.drop_frame(SlipError::BadEscape(b)))),
+            };
+            return self.push_frame_byte(unescaped);
+        }
+        match b {
+            SLIP_END => {
+                self.in_frame = false;
+                Some(Ok(std::mem::take(&mut self.frame)))
+            }
+            SLIP_ESC => {
+                self.escaped = true;
+                None
+            }
+            SLIP_START => {
+                self.start_frame();
+                Some(Err(SlipError::UnexpectedStart))
+            }
+            _ => self.push_frame_byte(b),
+        }
+    }
+
+    fn push_frame_byte(&mut self, b: u8) -> Option<Result<Vec<u8>, SlipError>> {
+        if self.frame.len() >= self.max_frame_length {
+            return Some(Err(self.drop_frame(SlipError::Oversize)));
+        }
+        self.frame.push(b);
+        None
+    }
+
+    fn start_frame(&mut self) {
+        self.frame.clear();
+        self.in_frame = true;
+        self.escaped = false;
+    }
+
+    fn drop_frame(&mut self, error: SlipError) -> SlipError {
+        self.reset();
+        error
+    }
+
+    // Bytes of the frame being received
+    pub fn pending_len(&self) -> usize {
+        self.frame.len()
+    }
+
+    pub fn reset(&mut self) {
+        self.frame.clear();
+        self.in_frame = false;
+        self.escaped = false;
+    }
+}
+
+// Wraps a UART packet in SLIP_START/SLIP_END, escaping the bytes in between
+#[allow(unused)]
+pub fn slip_encode(frame: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(frame.len() + 2);
+    encoded.push(SLIP_START);
+    for b in frame {
+        if *b == SLIP_START || *b == SLIP_END || *b == SLIP_ESC {
+            encoded.push(SLIP_ESC);
+            encoded.push(*b + 1);
+        } else {
+            encoded.push(*b);
+        }
+    }
+    encoded.push(SLIP_END);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_slip_encode() {
+        let frame = vec![0x01, SLIP_START, 0x02, SLIP_END, SLIP_ESC, 0x03];
+        let encoded = slip_encode(&frame);
+        assert_eq!(encoded.len(), frame.len() + 2 + 3);
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(decoder.push(&encoded), vec![Ok(frame)]);
+        assert_eq!(decoder.pending_len(), 0);
+    }
+
+    #[test]
+    fn keeps_a_frame_split_byte_by_byte() {
+        let frame = vec![0x10, SLIP_ESC, 0x20, SLIP_START];
+        let mut decoder = SlipDecoder::new();
+        let mut frames = Vec::new();
+        // Noise before the frame is skipped
+        for b in [0x42].iter().chain(slip_encode(&frame).iter()) {
+            if let Some(result) = decoder.push_byte(*b) {
+                frames.push(result);
+            }
+        }
+        assert_eq!(frames, vec![Ok(frame)]);
+    }
+
+    #[test]
+    fn reports_bad_escape_and_resyncs() {
+        let mut bytes = vec![SLIP_START, 0x01, SLIP_ESC, 0x42, 0x02, SLIP_END];
+        bytes.extend(slip_encode(&[0x03]));
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(
+            decoder.push(&bytes),
+            vec![Err(SlipError::BadEscape(0x42)), Ok(vec![0x03])]
+        );
+    }
+
+    #[test]
+    fn reports_oversize_frames() {
+        let mut decoder = SlipDecoder::with_max_frame_length(4);
+        assert_eq!(
+            decoder.push(&slip_encode(&[1, 2, 3, 4, 5])),
+            vec![Err(SlipError::Oversize)]
+        );
+        assert_eq!(decoder.pending_len(), 0);
+        assert_eq!(
+            decoder.push(&slip_encode(&[1, 2, 3, 4])),
+            vec![Ok(vec![1, 2, 3, 4])]
+        );
+    }
+
+    #[test]
+    fn reports_start_within_a_frame() {
+        let mut bytes = vec![SLIP_START, 0x01, 0x02];
+        bytes.extend(slip_encode(&[0x03]));
+        let mut decoder = SlipDecoder::new();
+        assert_eq!(
+            decoder.push(&bytes),
+            vec![Err(SlipError::UnexpectedStart), Ok(vec![0x03])]
+        );
+    }
+}
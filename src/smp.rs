@@ -0,0 +1,516 @@
+use crate::{bd_addr::BdAddr, ble_sniffer::read_u16_le};
+
+// Reference book: Bluetooth specification Core_v5.4 vol.3 PartH Chapter3
+#[allow(unused)]
+pub const SMP_PAIRING_REQUEST: u8 = 0x01;
+#[allow(unused)]
+pub const SMP_PAIRING_RESPONSE: u8 = 0x02;
+#[allow(unused)]
+pub const SMP_PAIRING_CONFIRM: u8 = 0x03;
+#[allow(unused)]
+pub const SMP_PAIRING_RANDOM: u8 = 0x04;
+#[allow(unused)]
+pub const SMP_PAIRING_FAILED: u8 = 0x05;
+#[allow(unused)]
+pub const SMP_ENCRYPTION_INFORMATION: u8 = 0x06;
+#[allow(unused)]
+pub const SMP_CENTRAL_IDENTIFICATION: u8 = 0x07;
+#[allow(unused)]
+pub const SMP_IDENTITY_INFORMATION: u8 = 0x08;
+#[allow(unused)]
+pub const SMP_IDENTITY_ADDRESS_INFORMATION: u8 = 0x09;
+#[allow(unused)]
+pub const SMP_SIGNING_INFORMATION: u8 = 0x0A;
+#[allow(unused)]
+pub const SMP_SECURITY_REQUEST: u8 = 0x0B;
+#[allow(unused)]
+pub const SMP_PAIRING_PUBLIC_KEY: u8 = 0x0C;
+#[allow(unused)]
+pub const SMP_PAIRING_DHKEY_CHECK: u8 = 0x0D;
+#[allow(unused)]
+pub const SMP_KEYPRESS_NOTIFICATION: u8 = 0x0E;
+
+#[allow(unused)]
+pub const SMP_IO_CAP_DISPLAY_ONLY: u8 = 0x00;
+#[allow(unused)]
+pub const SMP_IO_CAP_DISPLAY_YES_NO: u8 = 0x01;
+#[allow(unused)]
+pub const SMP_IO_CAP_KEYBOARD_ONLY: u8 = 0x02;
+#[allow(unused)]
+pub const SMP_IO_CAP_NO_INPUT_NO_OUTPUT: u8 = 0x03;
+#[allow(unused)]
+pub const SMP_IO_CAP_KEYBOARD_DISPLAY: u8 = 0x04;
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpAuthReq {
+    pub bonding: bool,
+    pub mitm: bool,
+    pub secure_connections: bool,
+    pub keypress: bool,
+    pub ct2: bool,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpKeyDistribution {
+    pub enc_key: bool,
+    pub id_key: bool,
+    pub sign_key: bool,
+    pub link_key: bool,
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SmpPairingFeatures {
+    pub io_capability: u8,
+    pub oob_data_present: bool,
+    pub auth_req: SmpAuthReq,
+    pub max_encryption_key_size: u8,
+    pub initiator_key_distribution: SmpKeyDistribution,
+    pub responder_key_distribution: SmpKeyDistribution,
+}
+
+// Keys and values are kept in the little endian order used on air
+#[allow(unused)]
+#[derive(Debug, Clone)]
+pub enum SmpPdu {
+    PairingRequest(SmpPairingFeatures),
+    PairingResponse(SmpPairingFeatures),
+    PairingConfirm { confirm: [u8; 16] },
+    PairingRandom { random: [u8; 16] },
+    PairingFailed { reason: u8 },
+    EncryptionInformation { long_term_key: [u8; 16] },
+    CentralIdentification { ediv: u16, rand: [u8; 8] },
+    IdentityInformation { identity_resolving_key: [u8; 16] },
+    IdentityAddressInformation { address: BdAddr },
+    SigningInformation { signature_key: [u8; 16] },
+    SecurityRequest { auth_req: SmpAuthReq },
+    PairingPublicKey { x: [u8; 32], y: [u8; 32] },
+    PairingDhKeyCheck { check: [u8; 16] },
+    KeypressNotification { notification_type: u8 },
+    Unknown { code: u8, data: Vec<u8> },
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpPairingMethod {
+    JustWorks,
+    PasskeyEntry,
+    NumericComparison,
+    OutOfBand,
+}
+
+impl SmpAuthReq {
+    pub fn from(b: u8) -> SmpAuthReq {
+        SmpAuthReq {
+            bonding: (b & 0b11) == 0b01,
+            mitm: ((b >> 2) & 1) == 1,
+            secure_connections: ((b >> 3) & 1) == 1,
+            keypress: ((b >> 4) & 1) == 1,
+            ct2: ((b >> 5) & 1) == 1,
+        }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let all_flags = [
+            (self.bonding, "Bonding"),
+            (self.mitm, "MITM"),
+            (self.secure_connections, "SC"),
+            (self.keypress, "Keypress"),
+            (self.ct2, "CT2"),
+        ];
+        all_flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl SmpKeyDistribution {
+    pub fn from(b: u8) -> SmpKeyDistribution {
+        SmpKeyDistribution {
+            enc_key: (b & 1) == 1,
+            id_key: ((b >> 1) & 1) == 1,
+            sign_key: ((b >> 2) & 1) == 1,
+            link_key: ((b >> 3) & 1) == 1,
+        }
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let all_keys = [
+            (self.enc_key, "EncKey"),
+            (self.id_key, "IdKey"),
+            (self.sign_key, "SignKey"),
+            (self.link_key, "LinkKey"),
+        ];
+        all_keys
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl SmpPairingFeatures {
+    pub fn from(bytes: &[u8]) -> Option<SmpPairingFeatures> {
+        if bytes.len() != 6 {
+            return None;
+        }
+        Some(SmpPairingFeatures {
+            io_capability: bytes[0],
+            oob_data_present: bytes[1] == 1,
+            auth_req: SmpAuthReq::from(bytes[2]),
+            max_encryption_key_size: bytes[3],
+            initiator_key_distribution: SmpKeyDistribution::from(bytes[4]),
+            responder_key_distribution: SmpKeyDistribution::from(bytes[5]),
+        })
+    }
+
+    // One line for the logs, e.g. "KeyboardDisplay, OOB no, AuthReq Bonding MITM,
+    // 16 bytes keys, initiator keys EncKey IdKey, responder keys EncKey"
+    pub fn summary(&self) -> String {
+        let names = |names: Vec<&'static str>| match names.is_empty() {
+            true => "none".to_string(),
+            false => names.join(" "),
+        };
+        format!(
+            "{}, OOB {}, AuthReq {}, {} bytes keys, initiator keys {}, responder keys {}",
+            smp_io_capability_name(self.io_capability),
+            if self.oob_data_present { "yes" } else { "no" },
+            names(self.auth_req.names()),
+            self.max_encryption_key_size,
+            names(self.initiator_key_distribution.names()),
+            names(self.responder_key_distribution.names())
+        )
+    }
+}
+
+impl SmpPairingMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SmpPairingMethod::JustWorks => "Just Works",
+            SmpPairingMethod::PasskeyEntry => "Passkey Entry",
+            SmpPairingMethod::NumericComparison => "Numeric Comparison",
+            SmpPairingMethod::OutOfBand => "Out of Band",
+        }
+    }
+}
+
+#[allow(unused)]
+impl SmpPdu {
+    pub fn from(bytes: &[u8]) -> Option<SmpPdu> {
+        let (code, data) = bytes.split_first()?;
+        let code = *code;
+        let pdu = match code {
+            SMP_PAIRING_REQUEST => SmpPdu::PairingRequest(SmpPairingFeatures::from(data)?),
+            SMP_PAIRING_RESPONSE => SmpPdu::PairingResponse(SmpPairingFeatures::from(data)?),
+            SMP_PAIRING_CONFIRM => SmpPdu::PairingConfirm {
+                confirm: data.try_into().ok()?,
+            },
+            SMP_PAIRING_RANDOM => SmpPdu::PairingRandom {
+                random: data.try_into().ok()?,
+            },
+            SMP_PAIRING_FAILED => {
+                if data.len() != 1 {
+                    return None;
+                }
+                SmpPdu::PairingFailed { reason: data[0] }
+            }
+            SMP_ENCRYPTION_INFORMATION => SmpPdu::EncryptionInformation {
+                long_term_key: data.try_into().ok()?,
+            },
+            SMP_CENTRAL_IDENTIFICATION => {
+                if data.len() != 10 {
+                    return None;
+                }
+                SmpPdu::CentralIdentification {
+                    ediv: read_u16_le(data, 0),
+                    rand: data[2..].try_into().ok()?,
+                }
+            }
+            SMP_IDENTITY_INFORMATION => SmpPdu::IdentityInformation {
+                identity_resolving_key: data.try_into().ok()?,
+            },
+            SMP_IDENTITY_ADDRESS_INFORMATION => {
+                if data.len() != 7 {
+                    return None;
+                }
+                SmpPdu::IdentityAddressInformation {
+                    address: BdAddr::from_le(&data[1..], data[0] != 0),
+                }
+            }
+            SMP_SIGNING_INFORMATION => SmpPdu::SigningInformation {
+                signature_key: data.try_into().ok()?,
+            },
+            SMP_SECURITY_REQUEST => {
+                if data.len() != 1 {
+                    return None;
+                }
+                SmpPdu::SecurityRequest {
+                    auth_req: SmpAuthReq::from(data[0]),
+                }
+            }
+            SMP_PAIRING_PUBLIC_KEY => {
+                if data.len() != 64 {
+                    return None;
+                }
+                SmpPdu::PairingPublicKey {
+                    x: data[..32].try_into().ok()?,
+                    y: data[32..].try_into().ok()?,
+                }
+            }
+            SMP_PAIRING_DHKEY_CHECK => SmpPdu::PairingDhKeyCheck {
+                check: data.try_into().ok()?,
+            },
+            SMP_KEYPRESS_NOTIFICATION => {
+                if data.len() != 1 {
+                    return None;
+                }
+                SmpPdu::KeypressNotification {
+                    notification_type: data[0],
+                }
+            }
+            _ => SmpPdu::Unknown {
+                code,
+                data: data.to_vec(),
+            },
+        };
+        Some(pdu)
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            SmpPdu::PairingRequest(_) => SMP_PAIRING_REQUEST,
+            SmpPdu::PairingResponse(_) => SMP_PAIRING_RESPONSE,
+            SmpPdu::PairingConfirm { .. } => SMP_PAIRING_CONFIRM,
+            SmpPdu::PairingRandom { .. } => SMP_PAIRING_RANDOM,
+            SmpPdu::PairingFailed { .. } => SMP_PAIRING_FAILED,
+            SmpPdu::EncryptionInformation { .. } => SMP_ENCRYPTION_INFORMATION,
+            SmpPdu::CentralIdentification { .. } => SMP_CENTRAL_IDENTIFICATION,
+            SmpPdu::IdentityInformation { .. } => SMP_IDENTITY_INFORMATION,
+            SmpPdu::IdentityAddressInformation { .. } => SMP_IDENTITY_ADDRESS_INFORMATION,
+            SmpPdu::SigningInformation { .. } => SMP_SIGNING_INFORMATION,
+            SmpPdu::SecurityRequest { .. } => SMP_SECURITY_REQUEST,
+            SmpPdu::PairingPublicKey { .. } => SMP_PAIRING_PUBLIC_KEY,
+            SmpPdu::PairingDhKeyCheck { .. } => SMP_PAIRING_DHKEY_CHECK,
+            SmpPdu::KeypressNotification { .. } => SMP_KEYPRESS_NOTIFICATION,
+            SmpPdu::Unknown { code, .. } => *code,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.code() {
+            SMP_PAIRING_REQUEST => "Pairing Request",
+            SMP_PAIRING_RESPONSE => "Pairing Response",
+            SMP_PAIRING_CONFIRM => "Pairing Confirm",
+            SMP_PAIRING_RANDOM => "Pairing Random",
+            SMP_PAIRING_FAILED => "Pairing Failed",
+            SMP_ENCRYPTION_INFORMATION => "Encryption Information",
+            SMP_CENTRAL_IDENTIFICATION => "Central Identification",
+            SMP_IDENTITY_INFORMATION => "Identity Information",
+            SMP_IDENTITY_ADDRESS_INFORMATION => "Identity Address Information",
+            SMP_SIGNING_INFORMATION => "Signing Information",
+            SMP_SECURITY_REQUEST => "Security Request",
+            SMP_PAIRING_PUBLIC_KEY => "Pairing Public Key",
+            SMP_PAIRING_DHKEY_CHECK => "Pairing DHKey Check",
+            SMP_KEYPRESS_NOTIFICATION => "Keypress Notification",
+            _ => "Unknown",
+        }
+    }
+}
+
+// Reference: Core v5.4 vol.3 PartH 2.3.5.1
+#[allow(unused)]
+pub fn smp_pairing_method(
+    request: &SmpPairingFeatures,
+    response: &SmpPairingFeatures,
+) -> SmpPairingMethod {
+    let secure_connections =
+        request.auth_req.secure_connections && response.auth_req.secure_connections;
+    let oob = if secure_connections {
+        request.oob_data_present || response.oob_data_present
+    } else {
+        request.oob_data_present && response.oob_data_present
+    };
+    if oob {
+        return SmpPairingMethod::OutOfBand;
+    }
+    if !request.auth_req.mitm && !response.auth_req.mitm {
+        return SmpPairingMethod::JustWorks;
+    }
+    let numeric_comparison_or_passkey = if secure_connections {
+        SmpPairingMethod::NumericComparison
+    } else {
+        SmpPairingMethod::PasskeyEntry
+    };
+    let numeric_comparison_or_just_works = if secure_connections {
+        SmpPairingMethod::NumericComparison
+    } else {
+        SmpPairingMethod::JustWorks
+    };
+    match (request.io_capability, response.io_capability) {
+        (SMP_IO_CAP_DISPLAY_YES_NO, SMP_IO_CAP_DISPLAY_YES_NO) => numeric_comparison_or_just_works,
+        (SMP_IO_CAP_DISPLAY_YES_NO, SMP_IO_CAP_KEYBOARD_DISPLAY)
+        | (SMP_IO_CAP_KEYBOARD_DISPLAY, SMP_IO_CAP_DISPLAY_YES_NO)
+        | (SMP_IO_CAP_KEYBOARD_DISPLAY, SMP_IO_CAP_KEYBOARD_DISPLAY) => {
+            numeric_comparison_or_passkey
+        }
+        (SMP_IO_CAP_KEYBOARD_ONLY, SMP_IO_CAP_DISPLAY_ONLY)
+        | (SMP_IO_CAP_KEYBOARD_ONLY, SMP_IO_CAP_DISPLAY_YES_NO)
+        | (SMP_IO_CAP_KEYBOARD_ONLY, SMP_IO_CAP_KEYBOARD_ONLY)
+        | (SMP_IO_CAP_KEYBOARD_ONLY, SMP_IO_CAP_KEYBOARD_DISPLAY)
+        | (SMP_IO_CAP_DISPLAY_ONLY, SMP_IO_CAP_KEYBOARD_ONLY)
+        | (SMP_IO_CAP_DISPLAY_YES_NO, SMP_IO_CAP_KEYBOARD_ONLY)
+        | (SMP_IO_CAP_KEYBOARD_DISPLAY, SMP_IO_CAP_KEYBOARD_ONLY)
+        | (SMP_IO_CAP_DISPLAY_ONLY, SMP_IO_CAP_KEYBOARD_DISPLAY)
+        | (SMP_IO_CAP_KEYBOARD_DISPLAY, SMP_IO_CAP_DISPLAY_ONLY) => SmpPairingMethod::PasskeyEntry,
+        _ => SmpPairingMethod::JustWorks,
+    }
+}
+
+#[allow(unused)]
+pub fn smp_io_capability_name(io_capability: u8) -> &'static str {
+    match io_capability {
+        SMP_IO_CAP_DISPLAY_ONLY => "DisplayOnly",
+        SMP_IO_CAP_DISPLAY_YES_NO => "DisplayYesNo",
+        SMP_IO_CAP_KEYBOARD_ONLY => "KeyboardOnly",
+        SMP_IO_CAP_NO_INPUT_NO_OUTPUT => "NoInputNoOutput",
+        SMP_IO_CAP_KEYBOARD_DISPLAY => "KeyboardDisplay",
+        _ => "Reserved",
+    }
+}
+
+// Reference: Core v5.4 vol.3 PartH Table 3.7
+#[allow(unused)]
+pub fn smp_failed_reason_name(reason: u8) -> &'static str {
+    match reason {
+        0x01 => "Passkey Entry Failed",
+        0x02 => "OOB Not Available",
+        0x03 => "Authentication Requirements",
+        0x04 => "Confirm Value Failed",
+        0x05 => "Pairing Not Supported",
+        0x06 => "Encryption Key Size",
+        0x07 => "Command Not Supported",
+        0x08 => "Unspecified Reason",
+        0x09 => "Repeated Attempts",
+        0x0A => "Invalid Parameters",
+        0x0B => "DHKey Check Failed",
+        0x0C => "Numeric Comparison Failed",
+        0x0D => "BR/EDR pairing in progress",
+        0x0E => "Cross-transport Key Derivation/Generation not allowed",
+        0x0F => "Key Rejected",
+        0x10 => "Busy",
+        _ => "Reserved",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bd_addr::BdAddrType,
+        l2cap::{BleL2capFrame, L2capChannelEvent, L2capChannelTracker, L2CAP_CID_SMP},
+    };
+
+    fn features(pdu: &SmpPdu) -> SmpPairingFeatures {
+        match pdu {
+            SmpPdu::PairingRequest(features) | SmpPdu::PairingResponse(features) => *features,
+            pdu => panic!("expected pairing features, got {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn decodes_pairing_request_and_response() {
+        // KeyboardDisplay, bonding, MITM, SC and CT2, all keys distributed
+        let request = SmpPdu::from(&[0x01, 0x04, 0x00, 0x2d, 0x10, 0x0f, 0x0f]).unwrap();
+        assert_eq!(request.code(), SMP_PAIRING_REQUEST);
+        let request = features(&request);
+        assert_eq!(request.io_capability, SMP_IO_CAP_KEYBOARD_DISPLAY);
+        assert!(!request.oob_data_present);
+        assert_eq!(
+            request.auth_req,
+            SmpAuthReq {
+                bonding: true,
+                mitm: true,
+                secure_connections: true,
+                keypress: false,
+                ct2: true,
+            }
+        );
+        assert_eq!(request.max_encryption_key_size, 16);
+        let all_keys = SmpKeyDistribution {
+            enc_key: true,
+            id_key: true,
+            sign_key: true,
+            link_key: true,
+        };
+        assert_eq!(request.initiator_key_distribution, all_keys);
+        assert_eq!(request.responder_key_distribution, all_keys);
+
+        // NoInputNoOutput with OOB data and keypress, 7 bytes keys, EncKey and IdKey
+        let response = SmpPdu::from(&[0x02, 0x03, 0x01, 0x19, 0x07, 0x01, 0x03]).unwrap();
+        assert_eq!(response.name(), "Pairing Response");
+        let response = features(&response);
+        assert_eq!(response.io_capability, SMP_IO_CAP_NO_INPUT_NO_OUTPUT);
+        assert!(response.oob_data_present);
+        assert!(response.auth_req.bonding && !response.auth_req.mitm);
+        assert!(response.auth_req.secure_connections && response.auth_req.keypress);
+        assert_eq!(response.max_encryption_key_size, 7);
+        assert!(response.initiator_key_distribution.enc_key);
+        assert!(!response.initiator_key_distribution.id_key);
+        assert!(response.responder_key_distribution.id_key);
+        assert!(!response.responder_key_distribution.sign_key);
+        assert_eq!(
+            smp_pairing_method(&request, &response),
+            SmpPairingMethod::OutOfBand
+        );
+
+        assert_eq!(
+            response.summary(),
+            "NoInputNoOutput, OOB yes, AuthReq Bonding SC Keypress, 7 bytes keys, \
+             initiator keys EncKey, responder keys EncKey IdKey"
+        );
+
+        // Truncated features are rejected
+        assert!(SmpPdu::from(&[0x01, 0x04, 0x00, 0x2d, 0x10, 0x0f]).is_none());
+    }
+
+    #[test]
+    fn decodes_identity_address_information() {
+        let pdu = [0x09, 0x00, 0xa6, 0xa5, 0xa4, 0xa3, 0xa2, 0xa1];
+        let address = match SmpPdu::from(&pdu).unwrap() {
+            SmpPdu::IdentityAddressInformation { address } => address,
+            pdu => panic!("expected an identity address, got {:?}", pdu),
+        };
+        assert_eq!(address.to_string(), "A1:A2:A3:A4:A5:A6");
+        assert_eq!(address.address_type, BdAddrType::Public);
+
+        let pdu = [0x09, 0x01, 0x66, 0x55, 0x44, 0x33, 0x22, 0xc1];
+        let address = match SmpPdu::from(&pdu).unwrap() {
+            SmpPdu::IdentityAddressInformation { address } => address,
+            pdu => panic!("expected an identity address, got {:?}", pdu),
+        };
+        assert_eq!(address.to_string(), "C1:22:33:44:55:66");
+        assert_eq!(address.address_type, BdAddrType::RandomStatic);
+        assert!(SmpPdu::from(&pdu[..7]).is_none());
+    }
+
+    #[test]
+    fn smp_frames_come_out_of_l2cap_tracker() {
+        let mut tracker = L2capChannelTracker::new();
+        let frame = BleL2capFrame {
+            access_address: 0x50654A9B,
+            direction_to_slave: false,
+            channel_id: L2CAP_CID_SMP,
+            payload: vec![SMP_PAIRING_FAILED, 0x04],
+        };
+        match tracker.process(&frame) {
+            Some(L2capChannelEvent::Smp(SmpPdu::PairingFailed { reason })) => {
+                assert_eq!(smp_failed_reason_name(reason), "Confirm Value Failed");
+            }
+            event => panic!("expected Pairing Failed, got {:?}", event),
+        }
+    }
+}
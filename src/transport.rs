@@ -0,0 +1,388 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use serialport::SerialPort;
+
+use crate::{
+    ble_sniffer::{PROTOVER_V3, SNIFFER_BAUDRATE},
+    emulator::FirmwareEmulator,
+    firmware::SERIAL_READ_TIMEOUT,
+};
+
+// Where the UART bytes of a sniffer come from and where its commands go.
+// read() blocks at most SERIAL_READ_TIMEOUT and returns Ok(0) when nothing came,
+// Err(ErrorKind::UnexpectedEof) means the source is exhausted and won't come back.
+#[allow(unused)]
+pub trait SnifferTransport: Send {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    // None when the transport doesn't control the baud rate, e.g. behind a TCP bridge
+    fn baud_rate(&self) -> Option<u32> {
+        None
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Whether a firmware answers the commands written, false for recorded captures
+    fn is_live(&self) -> bool {
+        true
+    }
+}
+
+// "tcp://host:port" for a sniffer shared over the network (e.g. ser2net on a
+// Raspberry Pi), "file://path" for a raw dump of the UART bytes, "emulator://" for
+// FirmwareEmulator::demo or "emulator://path" to emulate a sniffer reporting the
+// packets of a raw dump, anything else is the path of a serial port
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnifferSource {
+    Serial(String),
+    Tcp(String),
+    File(String),
+    // Empty for the demo script
+    Emulator(String),
+}
+
+#[allow(unused)]
+impl SnifferSource {
+    pub fn open(&self) -> io::Result<Box<dyn SnifferTransport>> {
+        Ok(match self {
+            SnifferSource::Serial(path) => Box::new(SerialTransport::open(path, SNIFFER_BAUDRATE)?),
+            SnifferSource::Tcp(address) => Box::new(TcpTransport::connect(address)?),
+            SnifferSource::File(path) => Box::new(FileTransport::open(path)?),
+            SnifferSource::Emulator(path) => {
+                let mut emulator = if path.is_empty() {
+                    FirmwareEmulator::demo(PROTOVER_V3)
+                } else {
+                    FirmwareEmulator::from_dump(PROTOVER_V3, &fs::read(path)?)
+                };
+                emulator.set_close_when_done(true);
+                Box::new(emulator)
+            }
+        })
+    }
+}
+
+impl FromStr for SnifferSource {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<SnifferSource, String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(String::from("Empty sniffer source"));
+        }
+        Ok(if let Some(address) = text.strip_prefix("tcp://") {
+            SnifferSource::Tcp(address.to_string())
+        } else if let Some(path) = text.strip_prefix("file://") {
+            SnifferSource::File(path.to_string())
+        } else if let Some(path) = text.strip_prefix("emulator://") {
+            SnifferSource::Emulator(path.to_string())
+        } else {
+            SnifferSource::Serial(text.to_string())
+        })
+    }
+}
+
+impl fmt::Display for SnifferSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnifferSource::Serial(path) => write!(f, "{}", path),
+            SnifferSource::Tcp(address) => write!(f, "tcp://{}", address),
+            SnifferSource::File(path) => write!(f, "file://{}", path),
+            SnifferSource::Emulator(path) => write!(f, "emulator://{}", path),
+        }
+    }
+}
+
+#[allow(unused)]
+pub struct SerialTransport {
+    serial: Box<dyn SerialPort>,
+}
+
+#[allow(unused)]
+impl SerialTransport {
+    pub fn open(path: &str, baud_rate: u32) -> io::Result<SerialTransport> {
+        let serial = serialport::new(path, baud_rate)
+            .timeout(SERIAL_READ_TIMEOUT)
+            .open()?;
+        Ok(SerialTransport { serial })
+    }
+}
+
+impl SnifferTransport for SerialTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.serial.read(buffer) {
+            Err(error) if error.kind() == ErrorKind::TimedOut => Ok(0),
+            result => result,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.serial.write_all(bytes)
+    }
+
+    fn baud_rate(&self) -> Option<u32> {
+        self.serial.baud_rate().ok()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        Ok(self.serial.set_baud_rate(baud_rate)?)
+    }
+}
+
+// The remote end forwards the raw UART bytes both ways, the baud rate is its business
+#[allow(unused)]
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+#[allow(unused)]
+impl TcpTransport {
+    pub fn connect(address: &str) -> io::Result<TcpTransport> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(SERIAL_READ_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream })
+    }
+}
+
+impl SnifferTransport for TcpTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buffer) {
+            Ok(0) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed",
+            )),
+            Err(error)
+                if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut =>
+            {
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.stream.write_all(bytes)
+    }
+}
+
+// Replays a raw dump of the UART bytes, the commands written are dropped
+#[allow(unused)]
+pub struct FileTransport {
+    file: File,
+}
+
+#[allow(unused)]
+impl FileTransport {
+    pub fn open(path: &str) -> io::Result<FileTransport> {
+        Ok(FileTransport {
+            file: File::open(path)?,
+        })
+    }
+}
+
+impl SnifferTransport for FileTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self.file.read(buffer)? {
+            0 => Err(io::Error::new(ErrorKind::UnexpectedEof, "end of file")),
+            read_len => Ok(read_len),
+        }
+    }
+
+    fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+// In-memory transport for tests. Clones share the same buffers: keep one to feed
+// the input and check what was written while the other is read by the sniffer.
+#[allow(unused)]
+#[derive(Clone)]
+pub struct MockTransport {
+    input: Arc<Mutex<VecDeque<u8>>>,
+    output: Arc<Mutex<Vec<u8>>>,
+    closed: Arc<Mutex<bool>>,
+    live: bool,
+}
+
+#[allow(unused)]
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport {
+            input: Arc::new(Mutex::new(VecDeque::new())),
+            output: Arc::new(Mutex::new(Vec::new())),
+            closed: Arc::new(Mutex::new(false)),
+            live: true,
+        }
+    }
+
+    // A transport replaying input like a capture file
+    pub fn replay(input: &[u8]) -> MockTransport {
+        let mut transport = MockTransport::new();
+        transport.push_input(input);
+        transport.close();
+        transport.live = false;
+        transport
+    }
+
+    pub fn push_input(&self, bytes: &[u8]) {
+        if let Ok(mut input) = self.input.lock() {
+            input.extend(bytes);
+        }
+    }
+
+    // Reads fail with UnexpectedEof once the input left is consumed
+    pub fn close(&self) {
+        if let Ok(mut closed) = self.closed.lock() {
+            *closed = true;
+        }
+    }
+
+    // Everything written since the last call
+    pub fn take_output(&self) -> Vec<u8> {
+        match self.output.lock() {
+            Ok(mut output) => std::mem::take(&mut *output),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+impl SnifferTransport for MockTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read_len = match self.input.lock() {
+            Ok(mut input) => {
+                let read_len = input.len().min(buffer.len());
+                for (index, b) in input.drain(..read_len).enumerate() {
+                    buffer[index] = b;
+                }
+                read_len
+            }
+            Err(_) => return Err(io::Error::other("poisoned mock input")),
+        };
+        if read_len > 0 {
+            return Ok(read_len);
+        }
+        if self.closed.lock().map(|closed| *closed).unwrap_or(true) {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "mock closed"));
+        }
+        // Stand in for the read timeout without spinning
+        thread::sleep(Duration::from_millis(1));
+        Ok(0)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.output.lock() {
+            Ok(mut output) => {
+                output.extend_from_slice(bytes);
+                Ok(())
+            }
+            Err(_) => Err(io::Error::other("poisoned mock output")),
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        self.live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        ble_sniffer::{
+            analyze_transport_packets, make_event_bytes, BlePacket, SnifferConfig,
+            ADV_TYPE_ADV_NONCONN_IND, PING_RESP, PROTOVER_V2,
+        },
+        emulator::EmulatedPacket,
+    };
+
+    // A capture of a sniffer: two advertisements with a ping response in between,
+    // the UART packet counted 2 got lost
+    fn recorded_stream() -> Vec<u8> {
+        let mut stream = Vec::new();
+        for (packet_counter, address_byte) in [(0, 0x01), (3, 0x02)] {
+            let mut pdu = vec![ADV_TYPE_ADV_NONCONN_IND, 9];
+            pdu.extend_from_slice(&[address_byte, 0x22, 0x33, 0x44, 0x55, 0x66]);
+            pdu.extend_from_slice(&[0x02, 0x01, 0x06]);
+            let packet = EmulatedPacket::adv(38, &pdu);
+            stream.extend(make_event_bytes(
+                PROTOVER_V2,
+                packet.packet_id,
+                &packet.payload,
+                packet_counter,
+            ));
+            if packet_counter == 0 {
+                stream.extend(make_event_bytes(PROTOVER_V2, PING_RESP, &[0xE0, 0xE0], 1));
+            }
+        }
+        stream
+    }
+
+    #[test]
+    fn mock_transport_replays_into_the_packet_reader() {
+        let (tx, rx_packets) = mpsc::channel();
+        let (_control_tx, rx) = mpsc::channel();
+        let transport = MockTransport::replay(&recorded_stream());
+        let result = analyze_transport_packets(
+            "mock",
+            || Ok(Box::new(transport.clone()) as Box<dyn SnifferTransport>),
+            &SnifferConfig::new(),
+            tx,
+            &rx,
+        );
+        assert!(result.is_ok());
+
+        let packets: Vec<BlePacket> = rx_packets.try_iter().collect();
+        assert_eq!(packets.len(), 2);
+        let addresses: Vec<u8> = packets
+            .iter()
+            .map(|packet| {
+                let non_conn_ind = packet.ll_layer_data.non_conn_ind.as_ref().unwrap();
+                assert_eq!(packet.packet_header.channel_index, 38);
+                assert!(packet.packet_header.crc_ok);
+                assert!(non_conn_ind.flags.is_some());
+                non_conn_ind.advertising_mac.bytes[5]
+            })
+            .collect();
+        assert_eq!(addresses, [0x01, 0x02]);
+        assert_eq!(packets[0].dropped_before, 0);
+        assert_eq!(packets[1].dropped_before, 1);
+    }
+
+    #[test]
+    fn mock_transport_reads_until_closed() {
+        let mut transport = MockTransport::new();
+        let sniffer_side = transport.clone();
+        sniffer_side.push_input(&[1, 2, 3]);
+        let mut buffer = [0; 2];
+        assert_eq!(transport.read(&mut buffer).unwrap(), 2);
+        assert_eq!(transport.read(&mut buffer).unwrap(), 1);
+        assert_eq!(buffer[0], 3);
+        assert_eq!(transport.read(&mut buffer).unwrap(), 0);
+        transport.write(&[4, 5]).unwrap();
+        assert_eq!(sniffer_side.take_output(), [4, 5]);
+        sniffer_side.close();
+        let error = transport.read(&mut buffer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}